@@ -1,6 +1,9 @@
 use std::fs::File;
 
-use aquarana::{OggOpusHead, OggOpusTags, opus::OpusPacket};
+use aquarana::{
+    OggOpusHead, OggOpusTags,
+    opus::{OpusDecoder, toc::Channels},
+};
 use ogg::reading::PacketReader;
 
 fn main() {
@@ -9,10 +12,8 @@ fn main() {
     let mut reader = PacketReader::new(file);
 
     let packet = reader.read_packet().unwrap().unwrap();
-    println!(
-        "opus head = {:#?}",
-        OggOpusHead::try_from(packet.data.as_slice()).unwrap()
-    );
+    let head = OggOpusHead::try_from(packet.data.as_slice()).unwrap();
+    println!("opus head = {:#?}", head);
 
     let packet = reader.read_packet().unwrap().unwrap();
     println!(
@@ -20,9 +21,26 @@ fn main() {
         OggOpusTags::try_from(packet.data.as_slice()).unwrap()
     );
 
-    let packet = reader.read_packet().unwrap().unwrap();
+    let channels = if head.channel_count > 1 {
+        Channels::Stereo
+    } else {
+        Channels::Mono
+    };
+
+    let mut decoder = OpusDecoder::new(48000, channels).unwrap();
+
+    // 120ms is the longest duration a single packet can hold.
+    let mut pcm = vec![0.0f32; 5760 * channels as usize];
+
+    let mut packets = 0;
+    let mut samples = 0;
+    while let Some(packet) = reader.read_packet().unwrap() {
+        samples += decoder.decode(Some(&packet.data), &mut pcm).unwrap();
+        packets += 1;
+    }
+
     println!(
-        "opus packet = {:#?}",
-        OpusPacket::decode(packet.data.as_slice()).unwrap()
+        "decoded {} packets, {} samples per channel",
+        packets, samples
     );
 }
@@ -0,0 +1,145 @@
+//! CELT anti-collapse implementation
+//!
+//! When a transient frame is coded with short blocks, a band may receive so
+//! few pulses that some of its short blocks end up with no energy at all,
+//! which sounds like the band "collapsed" in time. If the anti-collapse flag
+//! is set, the collapsed blocks are filled with noise at a level derived from
+//! the energy of the two previous frames.
+
+use crate::opus::{
+    entropy::{CeltRangeCoding, RangeCodingDecoder},
+    toc::Channels,
+};
+
+use super::{
+    CeltFrameDecoder,
+    bands::{lcg_rand, renormalise_vector},
+    bit_alloc::FREQ_BANDS,
+};
+
+/// Anti-collapse decoder
+pub struct AntiCollapse;
+
+impl AntiCollapse {
+    /// Decodes the anti-collapse flag, only coded when the bit allocation
+    /// reserved a bit for it.
+    pub fn decode(dec: &mut CeltFrameDecoder, range_dec: &mut RangeCodingDecoder) {
+        dec.anticollapse = dec.anticollapse_needed > 0 && range_dec.rawbits(1) != 0;
+    }
+
+    /// Fills the collapsed short blocks of every band with noise, then
+    /// renormalizes the band.
+    pub fn process(dec: &mut CeltFrameDecoder) {
+        let lm = dec.size;
+        let mut seed = dec.seed;
+
+        for band in dec.band_range.clone() {
+            let n0 = (FREQ_BANDS[band + 1] - FREQ_BANDS[band]) as usize;
+            let offset = (FREQ_BANDS[band] as usize) << lm;
+
+            // Depth in 1/8 bits
+            let depth = ((1 + dec.pulses[band]) as u32 / n0 as u32) >> lm;
+            let thresh = 0.5 * (-0.125 * depth as f32).exp2();
+            let sqrt_1 = 1.0 / ((n0 << lm) as f32).sqrt();
+
+            for channel in 0..dec.channels as usize {
+                let mut prev1 = dec.blocks[channel].prev_energy[0][band];
+                let mut prev2 = dec.blocks[channel].prev_energy[1][band];
+                if dec.channels == Channels::Mono {
+                    prev1 = prev1.max(dec.blocks[1].prev_energy[0][band]);
+                    prev2 = prev2.max(dec.blocks[1].prev_energy[1][band]);
+                }
+
+                let block = &mut dec.blocks[channel];
+                let ediff = 0f32.max(block.energy[band] - prev1.min(prev2));
+
+                // r needs to be multiplied by 2 or 2 * sqrt(2) depending on
+                // lm because short blocks don't have the same energy as long.
+                let mut r = 2.0 * (-ediff).exp2();
+                if lm == 3 {
+                    r *= std::f32::consts::SQRT_2;
+                }
+
+                let r = r.min(thresh) * sqrt_1;
+
+                let x = &mut block.coeffs[offset..offset + (n0 << lm)];
+                let mut renormalize = false;
+                for k in 0..1 << lm {
+                    // Detect collapse
+                    if block.collapse_masks[band] & 1 << k != 0 {
+                        continue;
+                    }
+
+                    // Fill with noise
+                    for j in 0..n0 {
+                        seed = lcg_rand(seed);
+                        x[(j << lm) + k] = if seed & 0x8000 != 0 { r } else { -r };
+                    }
+
+                    renormalize = true;
+                }
+
+                // We just added some energy, so we need to renormalise
+                if renormalize {
+                    renormalise_vector(x, 1.0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opus::toc::Channels;
+
+    use super::{AntiCollapse, CeltFrameDecoder};
+
+    #[test]
+    fn collapsed_blocks_are_filled_with_noise() {
+        let mut dec = CeltFrameDecoder {
+            band_range: 11..13,
+            size: 2,
+            channels: Channels::Mono,
+            seed: 42,
+            ..Default::default()
+        };
+
+        // Band 11 has pulses in all its 4 short blocks, band 12 only in the
+        // first and third ones.
+        let block = &mut dec.blocks[0];
+        block.coeffs[56..64].fill(0.5);
+        block.collapse_masks[11] = 0b1111;
+        for j in 0..4 {
+            block.coeffs[64 + (j << 2)] = 0.125f32.sqrt();
+            block.coeffs[64 + (j << 2) + 2] = 0.125f32.sqrt();
+        }
+        block.collapse_masks[12] = 0b0101;
+
+        // The energy rose by 5 since two frames ago, the noise is at
+        // 2 * 2^-5 / sqrt(16) before the band is renormalised.
+        block.energy[12] = 0.0;
+        block.prev_energy[0][12] = -3.0;
+        block.prev_energy[1][12] = -5.0;
+
+        AntiCollapse::process(&mut dec);
+
+        let x = &dec.blocks[0].coeffs;
+        assert_eq!(x[56..64], [0.5; 8]);
+
+        let norm = x[64..80].iter().map(|x| x * x).sum::<f32>();
+        assert!((norm - 1.0).abs() < 1e-5);
+
+        let kept = x[64];
+        for j in 0..4 {
+            assert_eq!(x[64 + (j << 2)], kept);
+            assert_eq!(x[64 + (j << 2) + 2], kept);
+            for k in [1, 3] {
+                let noise = x[64 + (j << 2) + k];
+                assert!((noise.abs() / kept - 8f32.sqrt() / 64.0).abs() < 1e-5);
+            }
+        }
+
+        // Both signs are drawn.
+        assert!(x[64..80].iter().any(|&x| x < 0.0));
+    }
+}
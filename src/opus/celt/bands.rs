@@ -0,0 +1,1121 @@
+//! CELT band shape decoding implementation
+//!
+//! The normalized shape of every band is coded with PVQ. When a band gets
+//! more bits than a single PVQ codeword can use, it is recursively split in
+//! two halves, and the way the energy is distributed between the halves is
+//! coded as an angle ("theta"), the bits left being shared between them.
+
+use crate::opus::{
+    entropy::{CeltRangeCoding, RangeCodingDecoder},
+    toc::Channels,
+};
+
+use super::{
+    CeltFrameDecoder, MAX_BANDS, SHORT_BLOCKSIZE,
+    bit_alloc::{BitAlloc, FREQ_BANDS, LOG_GREQ_RANGE, Spread},
+    pvq::Pvq,
+};
+
+pub const QTHETA_OFFSET: i32 = 4;
+pub const QTHETA_OFFSET_TWOPHASE: i32 = 16;
+
+const EXP2_TABLE8: [i32; 8] = [16384, 17866, 19483, 21247, 23170, 25267, 27554, 30048];
+
+const BIT_INTERLEAVE: [u32; 16] = [0, 1, 1, 1, 2, 3, 3, 3, 2, 3, 3, 3, 2, 3, 3, 3];
+
+const BIT_DEINTERLEAVE: [u32; 16] = [
+    0x00, 0x03, 0x0C, 0x0F, 0x30, 0x33, 0x3C, 0x3F, 0xC0, 0xC3, 0xCC, 0xCF, 0xF0, 0xF3, 0xFC, 0xFF,
+];
+
+/// Mean energy of every band, removed from the coded energies.
+const E_MEANS: [f32; MAX_BANDS] = [
+    6.4375, 6.25, 5.75, 5.3125, 5.0625, 4.8125, 4.5, 4.375, 4.875, 4.6875, 4.5625, 4.4375, 4.875,
+    4.625, 4.3125, 4.5, 4.375, 4.625, 4.75, 4.4375, 3.75,
+];
+
+/// Order of the blocks in a hadamard band, for every stride.
+const ORDERY: [&[usize]; 4] = [
+    &[1, 0],
+    &[3, 0, 2, 1],
+    &[7, 0, 4, 3, 6, 1, 5, 2],
+    &[15, 0, 8, 7, 12, 3, 11, 4, 14, 1, 9, 6, 13, 2, 10, 5],
+];
+
+/// Linear congruential generator used for the noise of the decoder.
+pub fn lcg_rand(seed: u32) -> u32 {
+    seed.wrapping_mul(1664525).wrapping_add(1013904223)
+}
+
+/// Scales `x` to a norm of `gain`.
+pub fn renormalise_vector(x: &mut [f32], gain: f32) {
+    let energy = 1e-15 + x.iter().map(|x| x * x).sum::<f32>();
+    let gain = 1.0 / energy.sqrt() * gain;
+    for x in x.iter_mut() {
+        *x *= gain;
+    }
+}
+
+/// Haar wavelet transform of the `stride` interleaved vectors of `x`, merging
+/// or splitting pairs of adjacent coefficients. The transform is its own
+/// inverse.
+pub fn haar1(x: &mut [f32], n0: usize, stride: usize) {
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    for i in 0..stride {
+        for j in 0..n0 >> 1 {
+            let tmp1 = FRAC_1_SQRT_2 * x[stride * 2 * j + i];
+            let tmp2 = FRAC_1_SQRT_2 * x[stride * (2 * j + 1) + i];
+            x[stride * 2 * j + i] = tmp1 + tmp2;
+            x[stride * (2 * j + 1) + i] = tmp1 - tmp2;
+        }
+    }
+}
+
+/// Reorganizes the `stride` interleaved vectors of `n0` samples of `x` in
+/// time order instead of frequency order, in hadamard order if `hadamard`.
+fn deinterleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let mut tmp = vec![0.0; n0 * stride];
+    for i in 0..stride {
+        let order = if hadamard {
+            ORDERY[stride.ilog2() as usize - 1][i]
+        } else {
+            i
+        };
+        for j in 0..n0 {
+            tmp[order * n0 + j] = x[j * stride + i];
+        }
+    }
+
+    x[..n0 * stride].copy_from_slice(&tmp);
+}
+
+/// Inverse of [`deinterleave_hadamard`].
+fn interleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let mut tmp = vec![0.0; n0 * stride];
+    for i in 0..stride {
+        let order = if hadamard {
+            ORDERY[stride.ilog2() as usize - 1][i]
+        } else {
+            i
+        };
+        for j in 0..n0 {
+            tmp[j * stride + i] = x[order * n0 + j];
+        }
+    }
+
+    x[..n0 * stride].copy_from_slice(&tmp);
+}
+
+/// Turns the decoded mid `x` and side `y` of a stereo band back into the
+/// left and right channels, both normalized.
+fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32) {
+    // Compute the norm of X+Y and X-Y as |X|^2 + |Y|^2 +/- sum(xy)
+    let xp = mid * y.iter().zip(x.iter()).map(|(y, x)| y * x).sum::<f32>();
+    let side = y.iter().map(|y| y * y).sum::<f32>();
+
+    // Compensating for the mid normalization
+    let el = mid * mid + side - 2.0 * xp;
+    let er = mid * mid + side + 2.0 * xp;
+    if er < 6e-4 || el < 6e-4 {
+        y.copy_from_slice(x);
+        return;
+    }
+
+    let lgain = 1.0 / el.sqrt();
+    let rgain = 1.0 / er.sqrt();
+    for (x, y) in x.iter_mut().zip(y.iter_mut()) {
+        // Apply mid scaling (side is already scaled)
+        let l = mid * *x;
+        let r = *y;
+        *x = lgain * (l - r);
+        *y = rgain * (l + r);
+    }
+}
+
+/// Q15 fractional multiplication of two 16-bit values.
+fn frac_mul16(a: i32, b: i32) -> i32 {
+    (16384 + a as i16 as i32 * b as i16 as i32) >> 15
+}
+
+/// Cosine approximation, bit-exact on any platform since it has an impact
+/// on the bit allocation.
+fn bitexact_cos(x: i32) -> i32 {
+    let x2 = (4096 + x * x) >> 13;
+
+    1 + (32767 - x2) + frac_mul16(x2, -7651 + frac_mul16(x2, 8277 + frac_mul16(-626, x2)))
+}
+
+fn bitexact_log2tan(mut isin: i32, mut icos: i32) -> i32 {
+    let lc = icos.ilog2() as i32 + 1;
+    let ls = isin.ilog2() as i32 + 1;
+    icos <<= 15 - lc;
+    isin <<= 15 - ls;
+
+    (ls - lc) * (1 << 11) + frac_mul16(isin, frac_mul16(isin, -2597) + 7932)
+        - frac_mul16(icos, frac_mul16(icos, -2597) + 7932)
+}
+
+/// Split parameters of a band decoded from theta
+struct Split {
+    imid: i32,
+    iside: i32,
+    delta: i32,
+    itheta: i32,
+    qalloc: i32,
+    /// Whether the side of a stereo band is inverted.
+    inv: bool,
+}
+
+/// State shared by the band quantization recursion, the band and remaining
+/// bits being updated for every band.
+struct BandDecoder<'a, 'b> {
+    range_dec: &'a mut RangeCodingDecoder<'b>,
+    band: usize,
+    remaining_bits: i32,
+    tf_change: i32,
+    spread: Option<Spread>,
+    seed: u32,
+    intensity: usize,
+    disable_inv: bool,
+}
+
+impl BandDecoder<'_, '_> {
+    /// Resolution of theta for a band split, given the bits of the band.
+    fn compute_qn(n: usize, b: i32, offset: i32, pulse_cap: i32, stereo: bool) -> i32 {
+        let mut n2 = 2 * n as i32 - 1;
+        if stereo && n == 2 {
+            n2 -= 1;
+        }
+
+        // The upper limit ensures that a split always has enough bits left
+        // over to code at least one pulse in the side.
+        let qb = ((b + n2 * offset) / n2)
+            .min(b - pulse_cap - (4 << 3))
+            .min(8 << 3);
+
+        if qb < (1 << 3 >> 1) {
+            1
+        } else {
+            let qn = EXP2_TABLE8[(qb & 0x7) as usize] >> (14 - (qb >> 3));
+            ((qn + 1) >> 1) << 1
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn compute_theta(
+        &mut self,
+        n: usize,
+        b: &mut i32,
+        blocks: usize,
+        blocks0: usize,
+        lm: i32,
+        stereo: bool,
+        fill: &mut u32,
+    ) -> Split {
+        // Decide on the resolution to give to theta
+        let pulse_cap = LOG_GREQ_RANGE[self.band] as i32 + lm * (1 << 3);
+        let offset = (pulse_cap >> 1)
+            - if stereo && n == 2 {
+                QTHETA_OFFSET_TWOPHASE
+            } else {
+                QTHETA_OFFSET
+            };
+
+        let mut qn = Self::compute_qn(n, *b, offset, pulse_cap, stereo);
+        if stereo && self.band >= self.intensity {
+            qn = 1;
+        }
+
+        let tell = self.range_dec.tell_frac() as i32;
+        let mut itheta = 0;
+        let mut inv = false;
+        if qn != 1 {
+            // A step pdf is used for the stereo angle, a uniform one for the
+            // time split and a triangular one for the rest.
+            itheta = if stereo && n > 2 {
+                self.range_dec.step(qn as usize / 2)
+            } else if blocks0 > 1 || stereo {
+                self.range_dec.uniform(qn as usize + 1)
+            } else {
+                self.range_dec.triangular(qn as usize)
+            } as i32;
+
+            itheta = itheta * 16384 / qn;
+        } else if stereo {
+            // Intensity stereo only codes whether the side is inverted.
+            if *b > 2 << 3 && self.remaining_bits > 2 << 3 {
+                inv = self.range_dec.logp(2);
+            }
+
+            if self.disable_inv {
+                inv = false;
+            }
+        }
+
+        let qalloc = self.range_dec.tell_frac() as i32 - tell;
+        *b -= qalloc;
+
+        let (imid, iside, delta) = match itheta {
+            0 => {
+                *fill &= (1 << blocks) - 1;
+                (32767, 0, -16384)
+            }
+            16384 => {
+                *fill &= ((1 << blocks) - 1) << blocks;
+                (0, 32767, 16384)
+            }
+            _ => {
+                let imid = bitexact_cos(itheta);
+                let iside = bitexact_cos(16384 - itheta);
+
+                // The mid vs side allocation that minimizes squared error in
+                // the band
+                let delta = frac_mul16((n as i32 - 1) << 7, bitexact_log2tan(iside, imid));
+                (imid, iside, delta)
+            }
+        };
+
+        Split {
+            imid,
+            iside,
+            delta,
+            itheta,
+            qalloc,
+            inv,
+        }
+    }
+
+    /// Decodes a band of a single coefficient per channel, only the signs
+    /// are coded.
+    fn quant_band_n1(
+        &mut self,
+        x: &mut [f32],
+        y: Option<&mut [f32]>,
+        lowband_out: Option<&mut [f32]>,
+    ) -> u32 {
+        for x in std::iter::once(&mut *x).chain(y) {
+            let mut sign = 0;
+            if self.remaining_bits >= 1 << 3 {
+                sign = self.range_dec.rawbits(1);
+                self.remaining_bits -= 1 << 3;
+            }
+
+            x[0] = if sign != 0 { -1.0 } else { 1.0 };
+        }
+
+        if let Some(lowband_out) = lowband_out {
+            lowband_out[0] = x[0];
+        }
+
+        1
+    }
+
+    /// Decodes a mono partition, recursively splitting it in two halves as
+    /// long as it has more bits than a single PVQ codeword can use, a band
+    /// can end up being split in 8 parts.
+    #[allow(clippy::too_many_arguments)]
+    fn quant_partition(
+        &mut self,
+        x: &mut [f32],
+        lowband: Option<&[f32]>,
+        mut b: i32,
+        mut blocks: usize,
+        mut lm: i32,
+        gain: f32,
+        mut fill: u32,
+    ) -> u32 {
+        let blocks0 = blocks;
+
+        // If we need 1.5 more bit than we can produce, split the band in two.
+        if lm != -1 && b > BitAlloc::max_bits(self.band, lm) + 12 && x.len() > 2 {
+            let n = x.len() >> 1;
+            let (x, y) = x.split_at_mut(n);
+
+            lm -= 1;
+            if blocks == 1 {
+                fill = (fill & 1) | (fill << 1);
+            }
+
+            blocks = (blocks + 1) >> 1;
+
+            let split = self.compute_theta(n, &mut b, blocks, blocks0, lm, false, &mut fill);
+            let mid = split.imid as f32 / 32768.0;
+            let side = split.iside as f32 / 32768.0;
+            let mut delta = split.delta;
+
+            // Give more bits to low-energy MDCTs than they would otherwise
+            // deserve
+            if blocks0 > 1 && split.itheta & 0x3fff != 0 {
+                if split.itheta > 8192 {
+                    // Rough approximation for pre-echo masking
+                    delta -= delta >> (4 - lm);
+                } else {
+                    // Corresponds to a forward-masking slope of 1.5 dB per 10 ms
+                    delta = 0.min(delta + (((n as i32) << 3) >> (5 - lm)));
+                }
+            }
+
+            let mut mbits = 0.max(b.min((b - delta) / 2));
+            let mut sbits = b - mbits;
+            self.remaining_bits -= split.qalloc;
+
+            let next_lowband2 = lowband.map(|lowband| &lowband[n..]);
+
+            // The bits one half did not use are given to the other one
+            let mut rebalance = self.remaining_bits;
+            if mbits >= sbits {
+                let mut cm = self.quant_partition(x, lowband, mbits, blocks, lm, gain * mid, fill);
+
+                rebalance = mbits - (rebalance - self.remaining_bits);
+                if rebalance > 3 << 3 && split.itheta != 0 {
+                    sbits += rebalance - (3 << 3);
+                }
+
+                cm |= self.quant_partition(
+                    y,
+                    next_lowband2,
+                    sbits,
+                    blocks,
+                    lm,
+                    gain * side,
+                    fill >> blocks,
+                ) << (blocks0 >> 1);
+
+                cm
+            } else {
+                let mut cm = self.quant_partition(
+                    y,
+                    next_lowband2,
+                    sbits,
+                    blocks,
+                    lm,
+                    gain * side,
+                    fill >> blocks,
+                ) << (blocks0 >> 1);
+
+                rebalance = sbits - (rebalance - self.remaining_bits);
+                if rebalance > 3 << 3 && split.itheta != 16384 {
+                    mbits += rebalance - (3 << 3);
+                }
+
+                cm |= self.quant_partition(x, lowband, mbits, blocks, lm, gain * mid, fill);
+
+                cm
+            }
+        } else {
+            // This is the basic no-split case
+            let mut q = BitAlloc::bits2pulses(self.band, lm, b);
+            let mut curr_bits = BitAlloc::pulses2bits(self.band, lm, q);
+            self.remaining_bits -= curr_bits;
+
+            // Ensures we can never bust the budget
+            while self.remaining_bits < 0 && q > 0 {
+                self.remaining_bits += curr_bits;
+                q -= 1;
+                curr_bits = BitAlloc::pulses2bits(self.band, lm, q);
+                self.remaining_bits -= curr_bits;
+            }
+
+            if q != 0 {
+                let k = BitAlloc::get_pulses(q);
+                return Pvq::decode(self.range_dec, x, k, self.spread, blocks, gain);
+            }
+
+            // If there's no pulse, fill the band anyway
+            let cm_mask = (1 << blocks) - 1;
+            fill &= cm_mask;
+            if fill == 0 {
+                x.fill(0.0);
+                return 0;
+            }
+
+            let cm = if let Some(lowband) = lowband {
+                // Folded spectrum, with noise about 48 dB below the normal
+                // folding level
+                for (x, lowband) in x.iter_mut().zip(lowband) {
+                    self.seed = lcg_rand(self.seed);
+                    let noise = if self.seed & 0x8000 != 0 { 1.0 } else { -1.0 } / 256.0;
+                    *x = lowband + noise;
+                }
+
+                fill
+            } else {
+                // Noise
+                for x in x.iter_mut() {
+                    self.seed = lcg_rand(self.seed);
+                    *x = (self.seed as i32 >> 20) as f32;
+                }
+
+                cm_mask
+            };
+
+            renormalise_vector(x, gain);
+
+            cm
+        }
+    }
+
+    /// Decodes a band of a single channel, folding from `lowband` when the
+    /// band gets no pulses. The shape is scaled to a norm of `gain` and
+    /// written to `lowband_out`, scaled to be folded into the next bands.
+    ///
+    /// The time-frequency resolution of the band is changed with haar
+    /// transforms according to `tf_change` before splitting it, and restored
+    /// once decoded.
+    #[allow(clippy::too_many_arguments)]
+    fn quant_band(
+        &mut self,
+        x: &mut [f32],
+        mut lowband: Option<&mut [f32]>,
+        lowband_out: Option<&mut [f32]>,
+        b: i32,
+        mut blocks: usize,
+        lm: i32,
+        gain: f32,
+        mut fill: u32,
+    ) -> u32 {
+        let n0 = x.len();
+        let long_blocks = blocks == 1;
+        let mut n_b = n0 / blocks;
+        let mut tf_change = self.tf_change;
+
+        // Special case for one sample
+        if n0 == 1 {
+            return self.quant_band_n1(x, None, lowband_out);
+        }
+
+        // Band recombining to increase frequency resolution
+        let recombine = tf_change.max(0) as usize;
+        for k in 0..recombine {
+            if let Some(lowband) = lowband.as_deref_mut() {
+                haar1(lowband, n0 >> k, 1 << k);
+            }
+
+            fill = BIT_INTERLEAVE[fill as usize & 0xF] | BIT_INTERLEAVE[fill as usize >> 4] << 2;
+        }
+
+        blocks >>= recombine;
+        n_b <<= recombine;
+
+        // Increasing the time resolution
+        let mut time_divide = 0;
+        while n_b & 1 == 0 && tf_change < 0 {
+            if let Some(lowband) = lowband.as_deref_mut() {
+                haar1(lowband, n_b, blocks);
+            }
+
+            fill |= fill << blocks;
+            blocks <<= 1;
+            n_b >>= 1;
+            time_divide += 1;
+            tf_change += 1;
+        }
+
+        let blocks0 = blocks;
+        let n_b0 = n_b;
+
+        // Reorganize the samples in time order instead of frequency order
+        if blocks0 > 1
+            && let Some(lowband) = lowband.as_deref_mut()
+        {
+            deinterleave_hadamard(lowband, n_b >> recombine, blocks0 << recombine, long_blocks);
+        }
+
+        let mut cm = self.quant_partition(x, lowband.as_deref(), b, blocks, lm, gain, fill);
+
+        // Undo the sample reorganization going from time order to frequency
+        // order
+        if blocks0 > 1 {
+            interleave_hadamard(x, n_b >> recombine, blocks0 << recombine, long_blocks);
+        }
+
+        // Undo time-freq changes that we did earlier
+        n_b = n_b0;
+        blocks = blocks0;
+        for _ in 0..time_divide {
+            blocks >>= 1;
+            n_b <<= 1;
+            cm |= cm >> blocks;
+            haar1(x, n_b, blocks);
+        }
+
+        for k in 0..recombine {
+            cm = BIT_DEINTERLEAVE[cm as usize];
+            haar1(x, n0 >> k, 1 << k);
+        }
+
+        blocks <<= recombine;
+
+        // Scale output for later folding
+        if let Some(lowband_out) = lowband_out {
+            let n = (n0 as f32).sqrt();
+            for (out, x) in lowband_out.iter_mut().zip(x.iter()) {
+                *out = n * x;
+            }
+        }
+
+        cm & ((1 << blocks) - 1)
+    }
+
+    /// Decodes a stereo band, coded as a mid and a side whose energies are
+    /// split according to theta. Above the intensity band, only the mid is
+    /// coded and the side is at most inverted.
+    #[allow(clippy::too_many_arguments)]
+    fn quant_band_stereo(
+        &mut self,
+        x: &mut [f32],
+        y: &mut [f32],
+        lowband: Option<&mut [f32]>,
+        lowband_out: Option<&mut [f32]>,
+        mut b: i32,
+        blocks: usize,
+        lm: i32,
+        mut fill: u32,
+    ) -> u32 {
+        let n = x.len();
+
+        // Special case for one sample
+        if n == 1 {
+            return self.quant_band_n1(x, Some(y), lowband_out);
+        }
+
+        let orig_fill = fill;
+        let split = self.compute_theta(n, &mut b, blocks, blocks, lm, true, &mut fill);
+        let mid = split.imid as f32 / 32768.0;
+        let side = split.iside as f32 / 32768.0;
+
+        let cm = if n == 2 {
+            // Special case for N=2 that only works for stereo and takes
+            // advantage of the fact that mid and side are orthogonal to encode
+            // the side with just one bit.
+            let sbits = if split.itheta != 0 && split.itheta != 16384 {
+                1 << 3
+            } else {
+                0
+            };
+
+            let mbits = b - sbits;
+            self.remaining_bits -= split.qalloc + sbits;
+
+            // The channel with the most energy is coded as the mid
+            let (x2, y2) = if split.itheta > 8192 {
+                (&mut *y, &mut *x)
+            } else {
+                (&mut *x, &mut *y)
+            };
+
+            let mut sign = 0;
+            if sbits != 0 {
+                sign = self.range_dec.rawbits(1);
+            }
+
+            let sign = 1.0 - 2.0 * sign as f32;
+
+            // We use orig_fill here because we want to fold the side, but if
+            // itheta==16384, we'll have cleared the low bits of fill.
+            let cm = self.quant_band(x2, lowband, lowband_out, mbits, blocks, lm, 1.0, orig_fill);
+
+            // We don't split N=2 bands, so cm is either 1 or 0 (for a
+            // fold-collapse), and there's no need to worry about mixing with
+            // the other channel.
+            y2[0] = -sign * x2[1];
+            y2[1] = sign * x2[0];
+
+            for (x, y) in x.iter_mut().zip(y.iter_mut()) {
+                let (l, r) = (mid * *x, side * *y);
+                *x = l - r;
+                *y = l + r;
+            }
+
+            cm
+        } else {
+            // "Normal" split code
+            let mut mbits = 0.max(b.min((b - split.delta) / 2));
+            let mut sbits = b - mbits;
+            self.remaining_bits -= split.qalloc;
+
+            // The bits the mid did not use are given to the side, and the
+            // other way around.
+            let mut rebalance = self.remaining_bits;
+            let cm = if mbits >= sbits {
+                // In stereo mode, we do not apply a scaling to the mid because
+                // we need the normalized mid for folding later.
+                let cm = self.quant_band(x, lowband, lowband_out, mbits, blocks, lm, 1.0, fill);
+
+                rebalance = mbits - (rebalance - self.remaining_bits);
+                if rebalance > 3 << 3 && split.itheta != 0 {
+                    sbits += rebalance - (3 << 3);
+                }
+
+                // For a stereo split, the high bits of fill are always zero,
+                // so no folding will be done to the side.
+                cm | self.quant_band(y, None, None, sbits, blocks, lm, side, fill >> blocks)
+            } else {
+                let cm = self.quant_band(y, None, None, sbits, blocks, lm, side, fill >> blocks);
+
+                rebalance = sbits - (rebalance - self.remaining_bits);
+                if rebalance > 3 << 3 && split.itheta != 16384 {
+                    mbits += rebalance - (3 << 3);
+                }
+
+                cm | self.quant_band(x, lowband, lowband_out, mbits, blocks, lm, 1.0, fill)
+            };
+
+            stereo_merge(x, y, mid);
+
+            cm
+        };
+
+        if split.inv {
+            for y in y.iter_mut() {
+                *y = -*y;
+            }
+        }
+
+        cm
+    }
+}
+
+/// Band shape decoder
+pub struct Bands;
+
+impl Bands {
+    pub fn decode(dec: &mut CeltFrameDecoder, range_dec: &mut RangeCodingDecoder) {
+        let lm = dec.size;
+        let blocks = if dec.transient { 1 << lm } else { 1 };
+        let total_bits = ((range_dec.len() as i32) << 3) - dec.anticollapse_needed;
+        let start = dec.band_range.start;
+        let last = dec.band_range.end - 1;
+        let channels = dec.channels as usize;
+        let mut balance = dec.balance;
+        let mut dual_stereo = dec.dual_stereo;
+
+        for block in dec.blocks.iter_mut() {
+            block.coeffs.fill(0.0);
+        }
+
+        // The normalized shapes of the decoded bands of each channel, to fold
+        // from in the bands that get no pulses. The last band is never folded
+        // from.
+        let norm_offset = (FREQ_BANDS[start] as usize) << lm;
+        let norm_len = ((FREQ_BANDS[MAX_BANDS - 1] as usize) << lm) - norm_offset;
+        let mut norm = [vec![0.0; norm_len], vec![0.0; norm_len]];
+
+        let mut band_dec = BandDecoder {
+            range_dec,
+            band: start,
+            remaining_bits: 0,
+            tf_change: 0,
+            spread: dec.spread,
+            seed: dec.seed,
+            intensity: dec.intensity_stereo,
+            disable_inv: dec.disable_inv,
+        };
+
+        let mut lowband_offset = 0;
+        let mut update_lowband = true;
+        for i in dec.band_range.clone() {
+            let tell = band_dec.range_dec.tell_frac() as i32;
+            let range = (FREQ_BANDS[i] as usize) << lm..(FREQ_BANDS[i + 1] as usize) << lm;
+            let n = range.len();
+
+            // Compute how many bits we want to allocate to this band
+            if i != start {
+                balance -= tell;
+            }
+
+            let remaining_bits = total_bits - tell - 1;
+            let b = if i < dec.coded_bands {
+                let curr_balance = balance / 3.min(dec.coded_bands - i) as i32;
+                0.max(16383.min((remaining_bits + 1).min(dec.pulses[i] + curr_balance)))
+            } else {
+                0
+            };
+
+            band_dec.band = i;
+            band_dec.remaining_bits = remaining_bits;
+            band_dec.tf_change = dec.time_frequency_change[i];
+
+            if (range.start >= n + norm_offset || i == start + 1)
+                && (update_lowband || lowband_offset == 0)
+            {
+                lowband_offset = i;
+            }
+
+            if i == start + 1 {
+                Self::special_hybrid_folding(&mut norm, start, lm);
+            }
+
+            // Get a conservative estimate of the collapse masks of the bands
+            // we're going to be folding from.
+            let mut effective_lowband = None;
+            let (x_cm, y_cm) = if lowband_offset != 0
+                && (dec.spread != Some(Spread::Aggressive) || blocks > 1 || band_dec.tf_change < 0)
+            {
+                // This ensures we never repeat spectral content within one band
+                let lowband =
+                    ((FREQ_BANDS[lowband_offset] as usize) << lm).saturating_sub(norm_offset + n);
+
+                let mut fold_start = lowband_offset - 1;
+                while (FREQ_BANDS[fold_start] as usize) << lm > lowband + norm_offset {
+                    fold_start -= 1;
+                }
+
+                let mut fold_end = lowband_offset;
+                while fold_end < i
+                    && ((FREQ_BANDS[fold_end] as usize) << lm) < lowband + norm_offset + n
+                {
+                    fold_end += 1;
+                }
+
+                effective_lowband = Some(lowband);
+                (fold_start..fold_end).fold((0, 0), |(x_cm, y_cm), band| {
+                    (
+                        x_cm | dec.blocks[0].collapse_masks[band] as u32,
+                        y_cm | dec.blocks[channels - 1].collapse_masks[band] as u32,
+                    )
+                })
+            } else {
+                // Otherwise, we'll be using the LCG to fold, so all blocks will
+                // (almost always) be non-zero.
+                ((1 << blocks) - 1, (1 << blocks) - 1)
+            };
+
+            let lm = lm as i32;
+            let out_range = range.start - norm_offset..range.end - norm_offset;
+            // Dual stereo stops at the intensity band, the folding data of
+            // both channels is merged for the bands coded jointly.
+            if dual_stereo && i == dec.intensity_stereo {
+                dual_stereo = false;
+
+                let [norm, norm2] = &mut norm;
+                for (norm, norm2) in norm[..range.start - norm_offset].iter_mut().zip(norm2) {
+                    *norm = 0.5 * (*norm + *norm2);
+                }
+            }
+
+            if dual_stereo {
+                for (channel, (block, norm)) in dec.blocks.iter_mut().zip(&mut norm).enumerate() {
+                    let mut lowband = effective_lowband.map(|it| norm[it..it + n].to_vec());
+                    let lowband_out = (i != last).then(|| &mut norm[out_range.clone()]);
+                    let fill = if channel == 0 { x_cm } else { y_cm };
+
+                    block.collapse_masks[i] = band_dec.quant_band(
+                        &mut block.coeffs[range.clone()],
+                        lowband.as_deref_mut(),
+                        lowband_out,
+                        b / 2,
+                        blocks,
+                        lm,
+                        1.0,
+                        fill,
+                    ) as u8;
+                }
+            } else if dec.channels == Channels::Stereo {
+                let norm = &mut norm[0];
+                let mut lowband = effective_lowband.map(|it| norm[it..it + n].to_vec());
+                let lowband_out = (i != last).then(|| &mut norm[out_range]);
+
+                let [left, right] = &mut dec.blocks;
+                let cm = band_dec.quant_band_stereo(
+                    &mut left.coeffs[range.clone()],
+                    &mut right.coeffs[range],
+                    lowband.as_deref_mut(),
+                    lowband_out,
+                    b,
+                    blocks,
+                    lm,
+                    x_cm | y_cm,
+                ) as u8;
+
+                left.collapse_masks[i] = cm;
+                right.collapse_masks[i] = cm;
+            } else {
+                let norm = &mut norm[0];
+                let mut lowband = effective_lowband.map(|it| norm[it..it + n].to_vec());
+                let lowband_out = (i != last).then(|| &mut norm[out_range]);
+
+                let block = &mut dec.blocks[0];
+                block.collapse_masks[i] = band_dec.quant_band(
+                    &mut block.coeffs[range],
+                    lowband.as_deref_mut(),
+                    lowband_out,
+                    b,
+                    blocks,
+                    lm,
+                    1.0,
+                    x_cm | y_cm,
+                ) as u8;
+            }
+
+            balance += dec.pulses[i] + tell;
+
+            // Update the folding position only as long as we have 1 bit/sample
+            // depth.
+            update_lowband = b > (n << 3) as i32;
+        }
+
+        dec.seed = band_dec.seed;
+    }
+
+    /// Scales the normalized band shapes of every coded channel by the
+    /// decoded band energies, turning them into the MDCT coefficients of the
+    /// frame. The coefficients outside of the coded bands are cleared, all
+    /// of them for a silent frame, as well as those above the bandwidth of
+    /// an output below 48 kHz.
+    pub fn denormalise(dec: &mut CeltFrameDecoder) {
+        let lm = dec.size;
+        let start = (FREQ_BANDS[dec.band_range.start] as usize) << lm;
+        let end = (FREQ_BANDS[dec.band_range.end] as usize) << lm;
+        let bound = end.min((SHORT_BLOCKSIZE << lm) / dec.downsample);
+
+        for block in dec.blocks.iter_mut().take(dec.channels as usize) {
+            if dec.silence {
+                block.coeffs.fill(0.0);
+                continue;
+            }
+
+            block.coeffs[..start].fill(0.0);
+            block.coeffs[end..].fill(0.0);
+
+            for band in dec.band_range.clone() {
+                let range =
+                    (FREQ_BANDS[band] as usize) << lm..(FREQ_BANDS[band + 1] as usize) << lm;
+                let gain = (block.energy[band] + E_MEANS[band]).min(32.0).exp2();
+                for x in block.coeffs[range].iter_mut() {
+                    *x *= gain;
+                }
+            }
+
+            block.coeffs[bound..].fill(0.0);
+        }
+    }
+
+    /// Duplicates enough of the first band folding data to be able to fold
+    /// the second band, copies no data for CELT-only mode.
+    fn special_hybrid_folding(norm: &mut [Vec<f32>; 2], start: usize, lm: usize) {
+        let n1 = ((FREQ_BANDS[start + 1] - FREQ_BANDS[start]) as usize) << lm;
+        let n2 = ((FREQ_BANDS[start + 2] - FREQ_BANDS[start + 1]) as usize) << lm;
+        if n2 > n1 {
+            for norm in norm.iter_mut() {
+                norm.copy_within(2 * n1 - n2..n1, n1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opus::{entropy::RangeCodingDecoder, toc::Channels};
+
+    use super::{
+        BandDecoder, Bands, CeltFrameDecoder, E_MEANS, FREQ_BANDS, deinterleave_hadamard, haar1,
+        interleave_hadamard, stereo_merge,
+    };
+
+    fn band_decoder<'a, 'b>(range_dec: &'a mut RangeCodingDecoder<'b>) -> BandDecoder<'a, 'b> {
+        BandDecoder {
+            range_dec,
+            band: 12,
+            remaining_bits: 0,
+            tf_change: 0,
+            spread: None,
+            seed: 42,
+            intensity: 21,
+            disable_inv: false,
+        }
+    }
+
+    #[test]
+    fn bands_without_pulses_are_folded() {
+        let buf = [0; 8];
+        let mut rd = RangeCodingDecoder::new(&buf);
+        let mut band_dec = band_decoder(&mut rd);
+        let lowband = [1.0, -2.0, 3.0, -4.0, 4.0, -3.0, 2.0, -1.0];
+        let mut x = [0.0; 8];
+
+        // The lower band is copied with a little noise and scaled to the gain
+        // of the band.
+        let cm = band_dec.quant_partition(&mut x, Some(&lowband), 0, 2, 1, 0.5, 0b10);
+        assert_eq!(cm, 0b10);
+        for (x, lowband) in x.iter().zip(lowband) {
+            assert!((x - 0.5 * lowband / 60f32.sqrt()).abs() < 5e-3);
+        }
+
+        // Without any block to fold from, the band is left empty.
+        let cm = band_dec.quant_partition(&mut x, Some(&lowband), 0, 2, 1, 0.5, 0);
+        assert_eq!(cm, 0);
+        assert_eq!(x, [0.0; 8]);
+
+        // Without a lower band, noise fills all the blocks.
+        let seed = band_dec.seed;
+        let cm = band_dec.quant_partition(&mut x, None, 0, 2, 1, 0.5, 0b01);
+        assert_eq!(cm, 0b11);
+        let norm = x.iter().map(|x| x * x).sum::<f32>();
+        assert!((norm - 0.25).abs() < 1e-5);
+        assert!(x.iter().all(|&x| x != 0.0));
+        assert_ne!(band_dec.seed, seed);
+    }
+
+    #[test]
+    fn tf_change_round_trip() {
+        use std::f32::consts::SQRT_2;
+
+        // The haar transform merges pairs into their sum and difference, and
+        // undoes itself.
+        let mut x = [1.0, 3.0, 2.0, -2.0];
+        haar1(&mut x, 4, 1);
+        for (x, expected) in x.iter().zip([4.0, -2.0, 0.0, 4.0]) {
+            assert!((x - expected / SQRT_2).abs() < 1e-6);
+        }
+
+        haar1(&mut x, 4, 1);
+        for (x, expected) in x.iter().zip([1.0, 3.0, 2.0, -2.0]) {
+            assert!((x - expected).abs() < 1e-6);
+        }
+
+        // The blocks are put in time order, the hadamard order only changes
+        // the position of the blocks.
+        let x = (0..16).map(|i| i as f32).collect::<Vec<_>>();
+        let mut y = x.clone();
+        deinterleave_hadamard(&mut y, 4, 4, false);
+        assert_eq!(y[..8], [0.0, 4.0, 8.0, 12.0, 1.0, 5.0, 9.0, 13.0]);
+
+        for (n0, stride) in [(8, 2), (4, 4), (2, 8)] {
+            let mut y = x.clone();
+            deinterleave_hadamard(&mut y, n0, stride, true);
+            interleave_hadamard(&mut y, n0, stride, true);
+            assert_eq!(y, x);
+        }
+
+        // Whatever the resolution change, a folded band ends up with the
+        // shape of the lower band once restored.
+        let buf = [0; 8];
+        let mut rd = RangeCodingDecoder::new(&buf);
+        let mut band_dec = band_decoder(&mut rd);
+        let lowband = [1.0, -2.0, 3.0, -4.0, 4.0, -3.0, 2.0, -1.0];
+        for (blocks, tf_change) in [(1, 0), (1, -1), (1, -3), (2, 1), (2, 0), (2, -1)] {
+            band_dec.tf_change = tf_change;
+
+            let mut x = [0.0; 8];
+            let mut folded = lowband;
+            let mut lowband_out = [0.0; 8];
+            let fill = (1 << blocks) - 1;
+            let cm = band_dec.quant_band(
+                &mut x,
+                Some(&mut folded),
+                Some(&mut lowband_out),
+                0,
+                blocks,
+                1,
+                1.0,
+                fill,
+            );
+            assert_eq!(cm, fill);
+
+            for ((x, out), lowband) in x.iter().zip(lowband_out).zip(lowband) {
+                assert!((x - lowband / 60f32.sqrt()).abs() < 5e-3);
+                assert!((out - 8f32.sqrt() * x).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_bands() {
+        // A mid at cos(theta) and a side at sin(theta), orthogonal to it,
+        // give a left and a right channel rotated apart by 2 * theta.
+        let mut x = [1.0, 0.0];
+        let mut y = [0.0, 0.6];
+        stereo_merge(&mut x, &mut y, 0.8);
+        for (value, expected) in x.iter().chain(&y).zip([0.8, -0.6, 0.8, 0.6]) {
+            assert!((value - expected).abs() < 1e-6);
+        }
+
+        // When a channel is almost silent, both are given the mid.
+        let mut x = [0.6, 0.8];
+        let mut y = [0.6, 0.8];
+        stereo_merge(&mut x, &mut y, 1.0);
+        assert_eq!(y, x);
+
+        // Above the intensity band, only the mid is coded, folded here, and
+        // given to both channels.
+        let buf = [0; 8];
+        let mut rd = RangeCodingDecoder::new(&buf);
+        let mut band_dec = band_decoder(&mut rd);
+        band_dec.intensity = 12;
+
+        let lowband = [1.0, -2.0, 3.0, -4.0, 4.0, -3.0, 2.0, -1.0];
+        let mut x = [0.0; 8];
+        let mut y = [0.0; 8];
+        let cm = band_dec.quant_band_stereo(
+            &mut x,
+            &mut y,
+            Some(&mut lowband.clone()),
+            None,
+            0,
+            1,
+            1,
+            1,
+        );
+        assert_eq!(cm, 1);
+
+        for ((x, y), lowband) in x.iter().zip(y).zip(lowband) {
+            assert!((x - lowband / 60f32.sqrt()).abs() < 5e-3);
+            assert!((x - y).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn denormalise() {
+        let mut dec = CeltFrameDecoder {
+            band_range: 0..13,
+            size: 1,
+            channels: Channels::Stereo,
+            downsample: 1,
+            ..Default::default()
+        };
+
+        // Every band is scaled by 2 to the power of its energy plus the mean
+        // energy, the coefficients above the coded bands are cleared.
+        for (channel, block) in dec.blocks.iter_mut().enumerate() {
+            block.coeffs.fill(0.5);
+            for (band, energy) in block.energy.iter_mut().enumerate() {
+                *energy = channel as f32 - 0.25 * band as f32;
+            }
+        }
+
+        Bands::denormalise(&mut dec);
+        for (channel, block) in dec.blocks.iter().enumerate() {
+            for band in 0..13 {
+                let gain = (channel as f32 - 0.25 * band as f32 + E_MEANS[band]).exp2();
+                let range = (FREQ_BANDS[band] as usize) << 1..(FREQ_BANDS[band + 1] as usize) << 1;
+                for x in &block.coeffs[range] {
+                    assert!((x - 0.5 * gain).abs() < 1e-4);
+                }
+            }
+
+            assert!(block.coeffs[40..].iter().all(|&x| x == 0.0));
+        }
+
+        // Hybrid frames start at band 17, and at 24 kHz the coefficients
+        // above 12 kHz are cleared.
+        dec.band_range = 17..21;
+        dec.downsample = 2;
+        dec.channels = Channels::Mono;
+        dec.blocks[0].coeffs.fill(0.5);
+        dec.blocks[0].energy.fill(0.0);
+
+        Bands::denormalise(&mut dec);
+        let coeffs = &dec.blocks[0].coeffs;
+        assert!(coeffs[..80].iter().all(|&x| x == 0.0));
+        assert!((coeffs[80] - 0.5 * E_MEANS[17].exp2()).abs() < 1e-4);
+        assert!(coeffs[120..].iter().all(|&x| x == 0.0));
+        assert!(coeffs[119] != 0.0);
+
+        // Silent frames have no coefficients at all.
+        dec.silence = true;
+        dec.blocks[0].coeffs.fill(0.5);
+        Bands::denormalise(&mut dec);
+        assert!(dec.blocks[0].coeffs.iter().all(|&x| x == 0.0));
+    }
+}
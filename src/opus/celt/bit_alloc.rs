@@ -1,9 +1,18 @@
-use crate::opus::{entropy::RangeCodingDecoder, toc::Channels};
+use crate::opus::{
+    entropy::{CeltRangeCoding, RangeCodingDecoder},
+    toc::Channels,
+};
 
-use super::{CeltFrameDecoder, MAX_BANDS};
+use super::{CeltFrameDecoder, MAX_BANDS, fine_energy::MAX_FINE_BITS};
 
 pub const VECTORS: i32 = 11;
 
+pub const ALLOC_STEPS: i32 = 6;
+
+pub const FINE_OFFSET: i32 = 21;
+
+pub const LOG_MAX_PSEUDO: usize = 6;
+
 pub const SPREAD_MODEL_DICT: [usize; 5] = [32, 7, 9, 30, 32];
 
 pub const STATIC_CAPS: [[[u8; 21]; 2]; 4] = [
@@ -57,6 +66,10 @@ pub const LOG_GREQ_RANGE: [u8; 21] = [
     0, 0, 0, 0, 0, 0, 0, 0, 8, 8, 8, 8, 16, 16, 16, 21, 21, 24, 29, 34, 36,
 ];
 
+pub const FREQ_BANDS: [u8; 22] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 34, 40, 48, 60, 78, 100,
+];
+
 pub const FREQ_RANGE: [u8; 21] = [
     1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 6, 6, 8, 12, 18, 22,
 ];
@@ -106,7 +119,43 @@ pub const STATIC_ALLOC: [[u8; 21]; 11] = [
     ],
 ];
 
-#[derive(Debug)]
+/// Index of the pulse cache of each band, for each frame size (including the
+/// halved 60-sample block of a split band)
+pub const CACHE_INDEX: [i16; 105] = [
+    -1, -1, -1, -1, -1, -1, -1, -1, 0, 0, 0, 0, 41, 41, 41, 82, 82, 123, 164, 200, 222, 0, 0, 0, 0,
+    0, 0, 0, 0, 41, 41, 41, 41, 123, 123, 123, 164, 164, 240, 266, 283, 295, 41, 41, 41, 41, 41,
+    41, 41, 41, 123, 123, 123, 123, 240, 240, 240, 266, 266, 305, 318, 328, 336, 123, 123, 123,
+    123, 123, 123, 123, 123, 240, 240, 240, 240, 305, 305, 305, 318, 318, 343, 351, 358, 364, 240,
+    240, 240, 240, 240, 240, 240, 240, 305, 305, 305, 305, 343, 343, 343, 351, 351, 370, 376, 382,
+    387,
+];
+
+/// Pulse cache, the first value of each entry is the maximum number of
+/// pseudo-pulses, followed by the number of bits (in 1/8 bit, minus one)
+/// needed for each number of pseudo-pulses
+pub const CACHE_BITS: [u8; 392] = [
+    40, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 40, 15, 23, 28, 31, 34, 36, 38, 39, 41, 42, 43, 44, 45, 46, 47,
+    47, 49, 50, 51, 52, 53, 54, 55, 55, 57, 58, 59, 60, 61, 62, 63, 63, 65, 66, 67, 68, 69, 70, 71,
+    71, 40, 20, 33, 41, 48, 53, 57, 61, 64, 66, 69, 71, 73, 75, 76, 78, 80, 82, 85, 87, 89, 91, 92,
+    94, 96, 98, 101, 103, 105, 107, 108, 110, 112, 114, 117, 119, 121, 123, 124, 126, 128, 40, 23,
+    39, 51, 60, 67, 73, 79, 83, 87, 91, 94, 97, 100, 102, 105, 107, 111, 115, 118, 121, 124, 126,
+    129, 131, 135, 139, 142, 145, 148, 150, 153, 155, 159, 163, 166, 169, 172, 174, 177, 179, 35,
+    28, 49, 65, 78, 89, 99, 107, 114, 120, 126, 132, 136, 141, 145, 149, 153, 159, 165, 171, 176,
+    180, 185, 189, 192, 199, 205, 211, 216, 220, 225, 229, 232, 239, 245, 251, 21, 33, 58, 79, 97,
+    112, 125, 137, 148, 157, 166, 174, 182, 189, 195, 201, 207, 217, 227, 235, 243, 251, 17, 35,
+    63, 86, 106, 123, 139, 152, 165, 177, 187, 197, 206, 214, 222, 230, 237, 250, 25, 31, 55, 75,
+    91, 105, 117, 128, 138, 146, 154, 161, 168, 174, 180, 185, 190, 200, 208, 215, 222, 229, 235,
+    240, 245, 255, 16, 36, 65, 89, 110, 128, 144, 159, 173, 185, 196, 207, 217, 226, 234, 242, 250,
+    11, 41, 74, 103, 128, 151, 172, 191, 209, 225, 241, 255, 9, 43, 79, 110, 138, 163, 186, 207,
+    227, 246, 12, 39, 71, 99, 123, 144, 164, 182, 198, 214, 228, 241, 253, 9, 44, 81, 113, 142,
+    168, 192, 214, 235, 255, 7, 49, 90, 127, 160, 191, 220, 247, 6, 51, 95, 134, 170, 203, 234, 7,
+    47, 87, 123, 155, 184, 212, 237, 6, 52, 97, 137, 174, 208, 240, 5, 57, 106, 151, 192, 231, 5,
+    59, 111, 158, 202, 243, 5, 55, 103, 147, 187, 224, 5, 60, 113, 161, 206, 248, 4, 65, 122, 175,
+    224, 4, 67, 127, 182, 234,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Spread {
     Light,
     Normal,
@@ -118,7 +167,7 @@ pub struct BitAlloc;
 impl BitAlloc {
     pub fn decode(dec: &mut CeltFrameDecoder, range_dec: &mut RangeCodingDecoder) {
         // spread
-        dec.spread = if range_dec.available() > 4 {
+        dec.spread = if range_dec.available() >= 4 {
             match range_dec.icdf(&SPREAD_MODEL_DICT) {
                 0 => None,
                 1 => Some(Spread::Light),
@@ -132,10 +181,10 @@ impl BitAlloc {
 
         // Initialize static allocation caps
         for i in 0..MAX_BANDS {
-            let bits =
-                (STATIC_CAPS[dec.size][dec.channels as usize - 1][i] + 64) * FREQ_RANGE[i];
+            let bits = (STATIC_CAPS[dec.size][dec.channels as usize - 1][i] as i32 + 64)
+                * FREQ_RANGE[i] as i32;
 
-            dec.caps[i] = (bits as i32) << (dec.channels as i32 - 1) << dec.size as i32 >> 2;
+            dec.caps[i] = (bits << (dec.channels as i32 - 1) << dec.size as i32) >> 2;
         }
 
         // Band boosts
@@ -145,7 +194,7 @@ impl BitAlloc {
         for i in dec.band_range.clone() {
             let mut band_dynalloc = dynalloc;
             let quanta = {
-                let it = (FREQ_RANGE[i]) << (dec.channels as u8 - 1) << dec.size;
+                let it = (FREQ_RANGE[i] as i32) << (dec.channels as i32 - 1) << dec.size;
                 (it << 3).min(it.max(6 << 3))
             };
 
@@ -156,12 +205,12 @@ impl BitAlloc {
                     break;
                 }
 
-                boost[i] += quanta as i32;
-                tbits_8ths -= quanta as i32;
+                boost[i] += quanta;
+                tbits_8ths -= quanta;
                 band_dynalloc = 1;
             }
 
-            if boost[1] > 0 {
+            if boost[i] > 0 {
                 dynalloc = 2.max(dynalloc - 1);
             }
         }
@@ -182,32 +231,30 @@ impl BitAlloc {
                 0
             };
 
-        tbits_8ths = dec.anticollapse_needed;
+        tbits_8ths = 0.max(tbits_8ths - dec.anticollapse_needed);
 
         // Band skip bit reservation
-        let mut skip_bit = 0;
-        if tbits_8ths >= 1 << 3 {
-            skip_bit = 1 << 3;
-        }
-
+        let skip_bit = if tbits_8ths >= 1 << 3 { 1 << 3 } else { 0 };
         tbits_8ths -= skip_bit;
 
         // Intensity/dual stereo bit reservation
-        let mut dualstereo_bit = 0;
         let mut intensitystereo_bit = 0;
+        let mut dualstereo_bit = 0;
         if dec.channels == Channels::Stereo {
             intensitystereo_bit = LOG2_FRAC[dec.band_range.end - dec.band_range.start] as i32;
             if intensitystereo_bit <= tbits_8ths {
                 tbits_8ths -= intensitystereo_bit;
                 if tbits_8ths >= 1 << 3 {
                     dualstereo_bit = 1 << 3;
-                    tbits_8ths -= 1 << 3;
+                    tbits_8ths -= dualstereo_bit;
                 }
             } else {
                 intensitystereo_bit = 0;
             }
         }
 
+        let channels = dec.channels as i32;
+
         // Trim offsets
         let mut trim_offset = [0i32; MAX_BANDS];
         let mut threshold = [0i32; MAX_BANDS];
@@ -215,45 +262,50 @@ impl BitAlloc {
             let trim = dec.alloc_trim - 5 - dec.size as i32;
             let band = FREQ_RANGE[i] as i32 * (dec.band_range.end as i32 - i as i32 - 1);
             let duration = dec.size as i32 + 3;
-            let scale = duration + dec.channels as i32 - 1;
+            let scale = duration + channels - 1;
 
             // PVQ minimum allocation threshold, below this value the band is skipped
-            threshold[i] = (3 * (FREQ_RANGE[i] as i32) << duration >> 4).max((dec.channels as i32) << 3);
-            trim_offset[i] = trim * (band << scale) >> 6;
+            threshold[i] = (((3 * FREQ_RANGE[i] as i32) << duration) >> 4).max(channels << 3);
+            trim_offset[i] = (trim * (band << scale)) >> 6;
 
             if (FREQ_RANGE[i] as usize) << dec.size == 1 {
-                trim_offset[i] -= (dec.channels as i32) << 3;
+                trim_offset[i] -= channels << 3;
             }
         }
 
-        // Bisection
-        let mut done = false;
-        let mut total = 0;
-        let mut bandbits = 0;
+        // The static allocation of a band for one of the allocation vectors,
+        // and its tilt by the trim.
+        let static_alloc = |vector: usize, band: usize| {
+            ((FREQ_RANGE[band] as i32 * STATIC_ALLOC[vector][band] as i32)
+                << (channels - 1)
+                << dec.size)
+                >> 2
+        };
+
+        let tilt = |bits: i32, band: usize| {
+            if bits > 0 {
+                0.max(bits + trim_offset[band])
+            } else {
+                bits
+            }
+        };
+
+        // Bisection, find the two allocation vectors the budget falls between
         let mut low = 1;
         let mut high = VECTORS - 1;
         while low <= high {
-            done = false;
-            total = 0;
+            let mut done = false;
+            let mut total = 0;
 
             let center = (low + high) >> 1;
-            for i in dec.band_range.clone().into_iter().rev() {
-                bandbits = (FREQ_RANGE[i] as i32 * STATIC_ALLOC[center as usize][i] as i32)
-                    << (dec.channels as u8 - 1)
-                    << dec.size
-                    >> 2;
-
-                if bandbits > 0 {
-                    bandbits = 0.max(bandbits as i32 + trim_offset[i] as i32);
-                }
-
-                bandbits += boost[i];
+            for i in dec.band_range.clone().rev() {
+                let bandbits = tilt(static_alloc(center as usize, i), i) + boost[i];
 
                 if bandbits >= threshold[i] || done {
                     done = true;
                     total += bandbits.min(dec.caps[i]);
-                } else if bandbits >= (dec.channels as i32) << 3 {
-                    total += (dec.channels as i32) << 3;
+                } else if bandbits >= channels << 3 {
+                    total += channels << 3;
                 }
             }
 
@@ -263,16 +315,316 @@ impl BitAlloc {
                 low = center + 1;
             }
         }
-        high = low - 1;
 
-        // Bisection
+        high = low;
+        low -= 1;
+
+        // The bands before the last boosted one are never skipped.
+        let mut skip_start = dec.band_range.start;
+        let mut bits1 = [0i32; MAX_BANDS];
+        let mut bits2 = [0i32; MAX_BANDS];
         for i in dec.band_range.clone() {
-            let mut bandbits = (FREQ_RANGE[i] as i32 * STATIC_ALLOC[low as usize][i] as i32)
-                << (dec.channels as u8 - 1)
-                << dec.size
-                >> 2;
-                
-                
+            let mut bits1j = tilt(static_alloc(low as usize, i), i);
+            let mut bits2j = tilt(
+                if high >= VECTORS {
+                    dec.caps[i]
+                } else {
+                    static_alloc(high as usize, i)
+                },
+                i,
+            );
+
+            if low > 0 {
+                bits1j += boost[i];
+            }
+
+            bits2j += boost[i];
+
+            if boost[i] > 0 {
+                skip_start = i;
+            }
+
+            bits1[i] = bits1j;
+            bits2[i] = 0.max(bits2j - bits1j);
+        }
+
+        // Interpolation between the two allocation vectors, in 1/64 steps
+        let alloc_floor = channels << 3;
+        let mut low = 0;
+        let mut high = 1 << ALLOC_STEPS;
+        for _ in 0..ALLOC_STEPS {
+            let mut done = false;
+            let mut total = 0;
+
+            let center = (low + high) >> 1;
+            for i in dec.band_range.clone().rev() {
+                let bandbits = bits1[i] + ((center * bits2[i]) >> ALLOC_STEPS);
+
+                if bandbits >= threshold[i] || done {
+                    done = true;
+                    total += bandbits.min(dec.caps[i]);
+                } else if bandbits >= alloc_floor {
+                    total += alloc_floor;
+                }
+            }
+
+            if total > tbits_8ths {
+                high = center;
+            } else {
+                low = center;
+            }
+        }
+
+        let mut total = 0;
+        let mut done = false;
+        for i in dec.band_range.clone().rev() {
+            let mut bandbits = bits1[i] + ((low * bits2[i]) >> ALLOC_STEPS);
+
+            if bandbits < threshold[i] && !done {
+                bandbits = if bandbits >= alloc_floor {
+                    alloc_floor
+                } else {
+                    0
+                };
+            } else {
+                done = true;
+            }
+
+            dec.pulses[i] = bandbits.min(dec.caps[i]);
+            total += dec.pulses[i];
+        }
+
+        // Band skipping, working backwards from the last band. Bands that were
+        // not given enough bits are skipped without signalling it.
+        let start = dec.band_range.start;
+        let band_offset = |band: usize| (FREQ_BANDS[band] - FREQ_BANDS[start]) as i32;
+
+        dec.coded_bands = dec.band_range.end;
+        loop {
+            let j = dec.coded_bands - 1;
+            if j <= skip_start {
+                // Give the reserved bit back
+                tbits_8ths += skip_bit;
+                break;
+            }
+
+            // The left-over bits this band would get, including the bits taken
+            // back from the bands skipped so far.
+            let mut remaining = tbits_8ths - total;
+            let bits = remaining / band_offset(dec.coded_bands);
+            remaining -= band_offset(dec.coded_bands) * bits;
+
+            let rem = 0.max(remaining - band_offset(j));
+            let mut band_bits = dec.pulses[j] + bits * FREQ_RANGE[j] as i32 + rem;
+
+            if band_bits >= threshold[j].max(alloc_floor + (1 << 3)) {
+                if range_dec.logp(1) {
+                    break;
+                }
+
+                total += 1 << 3;
+                band_bits -= 1 << 3;
+            }
+
+            // Take back the bits of the skipped band
+            total -= dec.pulses[j] + intensitystereo_bit;
+            if intensitystereo_bit > 0 {
+                intensitystereo_bit = LOG2_FRAC[j - start] as i32;
+            }
+
+            total += intensitystereo_bit;
+            dec.pulses[j] = if band_bits >= alloc_floor {
+                // Enough bits left for one fine energy bit per channel
+                total += alloc_floor;
+                alloc_floor
+            } else {
+                0
+            };
+
+            dec.coded_bands -= 1;
+        }
+
+        // Intensity and dual stereo parameters
+        dec.intensity_stereo = if intensitystereo_bit > 0 {
+            start + range_dec.uniform(dec.coded_bands + 1 - start)
+        } else {
+            0
+        };
+
+        if dec.intensity_stereo <= start {
+            tbits_8ths += dualstereo_bit;
+            dualstereo_bit = 0;
+        }
+
+        dec.dual_stereo = if dualstereo_bit > 0 {
+            range_dec.logp(1)
+        } else {
+            false
+        };
+
+        // Spread the remaining bits over the coded bands, proportionally to
+        // their width.
+        let mut remaining = tbits_8ths - total;
+        let bits = remaining / band_offset(dec.coded_bands);
+        remaining -= band_offset(dec.coded_bands) * bits;
+        for (pulses, &width) in dec.pulses[start..dec.coded_bands]
+            .iter_mut()
+            .zip(&FREQ_RANGE[start..])
+        {
+            let extra = remaining.min(width as i32);
+
+            *pulses += bits * width as i32 + extra;
+            remaining -= extra;
+        }
+
+        // Split the bits of each coded band between PVQ and fine energy
+        let log_m = (dec.size as i32) << 3;
+        let mut extrabits = 0;
+        for i in start..dec.coded_bands {
+            let n = (FREQ_RANGE[i] as i32) << dec.size;
+            let bits = dec.pulses[i] + extrabits;
+
+            let excess;
+            if n > 1 {
+                excess = 0.max(bits - dec.caps[i]);
+                dec.pulses[i] = bits - excess;
+
+                // Compensate for the extra degree of freedom in stereo
+                let duration = channels * n
+                    + (dec.channels == Channels::Stereo
+                        && n > 2
+                        && !dec.dual_stereo
+                        && i < dec.intensity_stereo) as i32;
+
+                let fine_offset = duration * (LOG_GREQ_RANGE[i] as i32 + log_m);
+
+                // Offset for the number of fine bits by log2(n) / 2 + FINE_OFFSET
+                // compared to their "fair share" of total / n
+                let mut offset = (fine_offset >> 1) - duration * FINE_OFFSET;
+
+                // n = 2 is the only point that does not match the curve
+                if n == 2 {
+                    offset += (duration << 3) >> 2;
+                }
+
+                // Changing the offset for allocating the second and third fine
+                // energy bit
+                if dec.pulses[i] + offset < (duration * 2) << 3 {
+                    offset += fine_offset >> 2;
+                } else if dec.pulses[i] + offset < (duration * 3) << 3 {
+                    offset += fine_offset >> 3;
+                }
+
+                // Divide with rounding
+                let mut fine_bits = 0.max(dec.pulses[i] + offset + (duration << 2));
+                fine_bits = (fine_bits / duration) >> 3;
+
+                // Make sure not to bust
+                if channels * fine_bits > dec.pulses[i] >> 3 {
+                    fine_bits = dec.pulses[i] >> (channels - 1) >> 3;
+                }
+
+                // More than that is useless because that's about as far as PVQ
+                // can go
+                dec.fine_bits[i] = fine_bits.min(MAX_FINE_BITS);
+
+                // If the band was rounded down or capped, it becomes a candidate
+                // for the final fine energy pass
+                dec.fine_priority[i] =
+                    (dec.fine_bits[i] * (duration << 3) >= dec.pulses[i] + offset) as i32;
+
+                // Remove the allocated fine bits, the rest are assigned to PVQ
+                dec.pulses[i] -= (channels * dec.fine_bits[i]) << 3;
+            } else {
+                // For n = 1, all bits go to fine energy except for a single
+                // sign bit
+                excess = 0.max(bits - (channels << 3));
+                dec.pulses[i] = bits - excess;
+                dec.fine_bits[i] = 0;
+                dec.fine_priority[i] = 1;
+            }
+
+            // Fine energy can not take advantage of the rebalancing done while
+            // decoding the bands, so do it here.
+            let mut excess = excess;
+            if excess > 0 {
+                let extra_fine =
+                    (excess >> (channels - 1) >> 3).min(MAX_FINE_BITS - dec.fine_bits[i]);
+                let extra_bits = (extra_fine * channels) << 3;
+
+                dec.fine_bits[i] += extra_fine;
+                dec.fine_priority[i] = (extra_bits >= excess - extrabits) as i32;
+                excess -= extra_bits;
+            }
+
+            extrabits = excess;
+        }
+
+        // Remaining bits over the cap, for the rebalancing of the band decoding
+        dec.balance = extrabits;
+
+        // The skipped bands use all their bits for fine energy
+        for i in dec.coded_bands..dec.band_range.end {
+            dec.fine_bits[i] = dec.pulses[i] >> (channels - 1) >> 3;
+            dec.pulses[i] = 0;
+            dec.fine_priority[i] = (dec.fine_bits[i] < 1) as i32;
+        }
+    }
+
+    /// Returns the pulse cache of a band, `lm` is the log2 of the block
+    /// size in short blocks and goes down to -1 for a split short block.
+    fn cache(band: usize, lm: i32) -> &'static [u8] {
+        &CACHE_BITS[CACHE_INDEX[(lm + 1) as usize * MAX_BANDS + band] as usize..]
+    }
+
+    /// Returns the largest number of bits (in 1/8 bit) a band can use
+    /// without being split in two.
+    pub fn max_bits(band: usize, lm: i32) -> i32 {
+        let cache = Self::cache(band, lm);
+        cache[cache[0] as usize] as i32
+    }
+
+    /// Converts a number of bits (in 1/8 bit) into the closest number of
+    /// pseudo-pulses of a band.
+    pub fn bits2pulses(band: usize, lm: i32, bits: i32) -> usize {
+        let cache = Self::cache(band, lm);
+        let bits = bits - 1;
+
+        let mut low = 0;
+        let mut high = cache[0] as usize;
+        for _ in 0..LOG_MAX_PSEUDO {
+            let center = (low + high + 1) >> 1;
+            if cache[center] as i32 >= bits {
+                high = center;
+            } else {
+                low = center;
+            }
+        }
+
+        let low_bits = if low == 0 { -1 } else { cache[low] as i32 };
+        if bits - low_bits <= cache[high] as i32 - bits {
+            low
+        } else {
+            high
+        }
+    }
+
+    /// Returns the number of bits (in 1/8 bit) used by a number of
+    /// pseudo-pulses of a band.
+    pub fn pulses2bits(band: usize, lm: i32, pulses: usize) -> i32 {
+        if pulses == 0 {
+            0
+        } else {
+            Self::cache(band, lm)[pulses] as i32 + 1
+        }
+    }
+
+    /// Converts a number of pseudo-pulses into the actual number of pulses.
+    pub fn get_pulses(pulses: usize) -> usize {
+        if pulses < 8 {
+            pulses
+        } else {
+            (8 + (pulses & 7)) << ((pulses >> 3) - 1)
         }
     }
 }
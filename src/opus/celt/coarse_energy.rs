@@ -104,7 +104,7 @@ pub struct CoarseEnergy;
 impl CoarseEnergy {
     pub fn decode(dec: &mut CeltFrameDecoder, range_dec: &mut RangeCodingDecoder) {
         // Select prediction parameters based on frame type
-        let (alpha, beta, model) = if range_dec.available() > 3 && range_dec.logp(3) {
+        let (alpha, beta, model) = if range_dec.available() >= 3 && range_dec.logp(3) {
             // Intra-frame prediction: no historical energy used, smaller beta
             (
                 0.0f32,
@@ -122,7 +122,7 @@ impl CoarseEnergy {
 
         let mut prev = [0.0f32; 2];  // Store previous energy value for each channel
         for band in 0..MAX_BANDS {
-            for channel in 0..dec.channels as usize {
+            for (channel, prev) in prev.iter_mut().enumerate().take(dec.channels as usize) {
                 let block = &mut dec.blocks[channel];
 
                 // Skip energy decoding for bands outside the valid range
@@ -136,7 +136,7 @@ impl CoarseEnergy {
                 let value = if range_dec.available() >= 15 {
                     // Decode using Laplace distribution, higher precision
                     let idx = band.min(20) << 1;
-                    range_dec.laplace((model[idx] << 7) as usize, (model[idx + 1] << 6) as isize)
+                    range_dec.laplace((model[idx] as usize) << 7, (model[idx + 1] as isize) << 6)
                 } else if available >= 2 {
                     // Decode using simple entropy coding
                     let v = range_dec.icdf(&TAPSET_MODEL_DICT) as isize;
@@ -148,12 +148,13 @@ impl CoarseEnergy {
                     -1
                 } as f32;
 
-                // Calculate new energy value and apply prediction and smoothing
-                block.energy[band] =
-                    -9.0f32.max(block.energy[band] * alpha + prev[channel] + value);
+                // Calculate new energy value and apply prediction and smoothing,
+                // the energy of the previous frame is floored at -9 before it
+                // is used as the predictor.
+                block.energy[band] = block.energy[band].max(-9.0) * alpha + *prev + value;
 
                 // Update prediction value
-                prev[channel] += beta * value;
+                *prev += beta * value;
             }
         }
     }
@@ -0,0 +1,131 @@
+//! CELT fine energy decoding implementation
+//!
+//! The coarse energy only has a resolution of 6 dB, the fine energy refines
+//! it with the bits that the bit allocation assigned to each band.
+//!
+//! The refinement happens in two passes, the first one is decoded right after
+//! the bit allocation, the second one ("final") uses whatever bits are left
+//! at the end of the frame after all band shapes have been decoded.
+
+use crate::opus::entropy::{CeltRangeCoding, RangeCodingDecoder};
+
+use super::CeltFrameDecoder;
+
+/// Maximum number of fine energy bits per band and channel.
+pub const MAX_FINE_BITS: i32 = 8;
+
+/// Fine energy decoder
+pub struct FineEnergy;
+
+impl FineEnergy {
+    pub fn decode(dec: &mut CeltFrameDecoder, range_dec: &mut RangeCodingDecoder) {
+        for band in dec.band_range.clone() {
+            let bits = dec.fine_bits[band];
+            if bits <= 0 {
+                continue;
+            }
+
+            for block in dec.blocks.iter_mut().take(dec.channels as usize) {
+                // The raw value is the index of one of 2^bits uniform steps
+                // inside the coarse quantization interval, centered on it.
+                let value = range_dec.rawbits(bits as usize) as f32;
+                block.energy[band] += (value + 0.5) / (1 << bits) as f32 - 0.5;
+            }
+        }
+    }
+
+    /// Decodes the final fine energy bits with the `bits_left` bits that the
+    /// band shapes did not use.
+    pub fn decode_final(
+        dec: &mut CeltFrameDecoder,
+        range_dec: &mut RangeCodingDecoder,
+        mut bits_left: usize,
+    ) {
+        let channels = dec.channels as usize;
+
+        // Bands with priority 0 get the leftover bits first, one bit per
+        // channel, then the ones with priority 1.
+        for priority in 0..2 {
+            for band in dec.band_range.clone() {
+                if bits_left < channels {
+                    break;
+                }
+
+                if dec.fine_bits[band] >= MAX_FINE_BITS || dec.fine_priority[band] != priority {
+                    continue;
+                }
+
+                for block in dec.blocks.iter_mut().take(channels) {
+                    let value = range_dec.rawbits(1) as f32;
+                    block.energy[band] += (value - 0.5) / (1 << (dec.fine_bits[band] + 1)) as f32;
+                    bits_left -= 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opus::{entropy::RangeCodingDecoder, toc::Channels};
+
+    use super::{super::CeltFrameDecoder, FineEnergy};
+
+    #[test]
+    fn decode_fine_energy_mono() {
+        // The raw values 1, 2, 5 and 200, then the final bits 0, 1 and 1.
+        let buf = [0, 1, 178, 45];
+        let mut rd = RangeCodingDecoder::new(&buf);
+
+        let mut dec = CeltFrameDecoder {
+            band_range: 0..5,
+            channels: Channels::Mono,
+            ..Default::default()
+        };
+
+        dec.fine_bits[..5].copy_from_slice(&[1, 2, 3, 0, 8]);
+        dec.fine_priority[..5].copy_from_slice(&[1, 0, 1, 0, 0]);
+
+        // (q + 0.5) / 2^bits - 0.5
+        FineEnergy::decode(&mut dec, &mut rd);
+        assert_eq!(
+            dec.blocks[0].energy[..5],
+            [0.25, 0.125, 0.1875, 0.0, 145.0 / 512.0]
+        );
+
+        // The priority 0 bands first, without the band at the maximum
+        // resolution, each bit moves the energy by 1 / 2^(bits + 2).
+        FineEnergy::decode_final(&mut dec, &mut rd, 3);
+        assert_eq!(
+            dec.blocks[0].energy[..5],
+            [0.375, 0.0625, 0.1875, 0.25, 145.0 / 512.0]
+        );
+    }
+
+    #[test]
+    fn decode_fine_energy_stereo() {
+        // The raw values 0, 1, 3 and 0 channel after channel, then the final
+        // bits 1, 0, 1 and 1.
+        let buf = [0, 0, 3, 78];
+        let mut rd = RangeCodingDecoder::new(&buf);
+
+        let mut dec = CeltFrameDecoder {
+            band_range: 0..3,
+            channels: Channels::Stereo,
+            ..Default::default()
+        };
+
+        dec.fine_bits[..3].copy_from_slice(&[1, 2, 0]);
+        dec.fine_priority[..3].copy_from_slice(&[0, 1, 0]);
+
+        FineEnergy::decode(&mut dec, &mut rd);
+        assert_eq!(dec.blocks[0].energy[..3], [-0.25, 0.375, 0.0]);
+        assert_eq!(dec.blocks[1].energy[..3], [0.25, -0.375, 0.0]);
+
+        // The last bit is not enough for both channels of the priority 1
+        // band.
+        FineEnergy::decode_final(&mut dec, &mut rd, 5);
+        assert_eq!(dec.blocks[0].energy[..3], [-0.125, 0.375, 0.25]);
+        assert_eq!(dec.blocks[1].energy[..3], [0.125, -0.375, 0.25]);
+    }
+}
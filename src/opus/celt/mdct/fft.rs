@@ -0,0 +1,371 @@
+//! Mixed-radix complex FFT
+//!
+//! The CELT transforms have sizes of the form 5 * 3 * 2^k, the FFT is split
+//! into radix 2, 3, 4 and 5 stages, the radix 4 stages being preferred and
+//! used last since they are the cheapest.
+
+use std::f64::consts::PI;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            re: self.re + rhs.re,
+            im: self.im + rhs.im,
+        }
+    }
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            re: self.re - rhs.re,
+            im: self.im - rhs.im,
+        }
+    }
+
+    fn mul(self, rhs: Self) -> Self {
+        Self {
+            re: self.re * rhs.re - self.im * rhs.im,
+            im: self.re * rhs.im + self.im * rhs.re,
+        }
+    }
+
+    fn scale(self, scalar: f32) -> Self {
+        Self {
+            re: self.re * scalar,
+            im: self.im * scalar,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fft {
+    /// Radix and remaining length of every stage, from the first to the last
+    factors: Vec<(usize, usize)>,
+    twiddles: Vec<Complex>,
+    bitrev: Vec<usize>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Self {
+        let factors = Self::factor(size);
+        let twiddles = (0..size)
+            .map(|i| {
+                let phase = (-2.0 * PI / size as f64) * i as f64;
+                Complex {
+                    re: phase.cos() as f32,
+                    im: phase.sin() as f32,
+                }
+            })
+            .collect();
+
+        let mut bitrev = vec![0; size];
+        Self::compute_bitrev(&mut bitrev, 0, 0, 1, &factors);
+
+        Self {
+            factors,
+            twiddles,
+            bitrev,
+        }
+    }
+
+    /// Index at which the input value `i` has to be stored for the
+    /// transform to produce its output in natural order.
+    pub fn bitrev(&self, i: usize) -> usize {
+        self.bitrev[i]
+    }
+
+    /// Splits the size into radix 4 stages first, then radix 2, 3 and 5, and
+    /// reverses the order to get the radix 4 at the end.
+    fn factor(mut size: usize) -> Vec<(usize, usize)> {
+        let mut radixes = Vec::new();
+        let mut p = 4;
+        while size > 1 {
+            while !size.is_multiple_of(p) {
+                p = match p {
+                    4 => 2,
+                    2 => 3,
+                    _ => p + 2,
+                };
+
+                if p * p > size {
+                    p = size;
+                }
+            }
+
+            size /= p;
+
+            // A radix 2 stage has to follow a radix 4 one.
+            if p == 2 && radixes.len() > 1 {
+                radixes.push(4);
+                radixes[1] = 2;
+            } else {
+                radixes.push(p);
+            }
+        }
+
+        radixes.reverse();
+
+        let mut m = radixes.iter().product::<usize>();
+        radixes
+            .into_iter()
+            .map(|p| {
+                m /= p;
+                (p, m)
+            })
+            .collect()
+    }
+
+    fn compute_bitrev(
+        bitrev: &mut [usize],
+        mut fout: usize,
+        mut index: usize,
+        fstride: usize,
+        factors: &[(usize, usize)],
+    ) {
+        let (p, m) = factors[0];
+        for _ in 0..p {
+            if m == 1 {
+                bitrev[index] = fout;
+                fout += 1;
+            } else {
+                Self::compute_bitrev(bitrev, fout, index, fstride * p, &factors[1..]);
+                fout += m;
+            }
+
+            index += fstride;
+        }
+    }
+
+    /// Transforms `buf` in place, which must already be in bit-reversed
+    /// order. The output is not scaled.
+    pub fn process(&self, buf: &mut [Complex]) {
+        let mut fstride = vec![1; self.factors.len() + 1];
+        for (i, (p, _)) in self.factors.iter().enumerate() {
+            fstride[i + 1] = fstride[i] * p;
+        }
+
+        for (i, &(p, m)) in self.factors.iter().enumerate().rev() {
+            let mm = if i != 0 { self.factors[i - 1].1 } else { 1 };
+            match p {
+                2 => Self::butterfly2(buf, fstride[i]),
+                3 => self.butterfly3(buf, fstride[i], m, mm),
+                4 => self.butterfly4(buf, fstride[i], m, mm),
+                5 => self.butterfly5(buf, fstride[i], m, mm),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Radix 2 stage, always following a radix 4 stage so `m` is 4.
+    fn butterfly2(buf: &mut [Complex], n: usize) {
+        let tw = std::f32::consts::FRAC_1_SQRT_2;
+        for fout in buf.chunks_exact_mut(8).take(n) {
+            let (a, b) = fout.split_at_mut(4);
+
+            let t = b[0];
+            b[0] = a[0].sub(t);
+            a[0] = a[0].add(t);
+
+            let t = Complex {
+                re: (b[1].re + b[1].im) * tw,
+                im: (b[1].im - b[1].re) * tw,
+            };
+
+            b[1] = a[1].sub(t);
+            a[1] = a[1].add(t);
+
+            let t = Complex {
+                re: b[2].im,
+                im: -b[2].re,
+            };
+
+            b[2] = a[2].sub(t);
+            a[2] = a[2].add(t);
+
+            let t = Complex {
+                re: (b[3].im - b[3].re) * tw,
+                im: -(b[3].im + b[3].re) * tw,
+            };
+
+            b[3] = a[3].sub(t);
+            a[3] = a[3].add(t);
+        }
+    }
+
+    fn butterfly3(&self, buf: &mut [Complex], fstride: usize, m: usize, mm: usize) {
+        let epi3 = self.twiddles[fstride * m];
+        for i in 0..fstride {
+            let base = i * mm;
+            for j in 0..m {
+                let k = base + j;
+                let s1 = buf[k + m].mul(self.twiddles[j * fstride]);
+                let s2 = buf[k + 2 * m].mul(self.twiddles[j * fstride * 2]);
+                let s3 = s1.add(s2);
+                let s0 = s1.sub(s2).scale(epi3.im);
+
+                let fm = Complex {
+                    re: buf[k].re - s3.re * 0.5,
+                    im: buf[k].im - s3.im * 0.5,
+                };
+
+                buf[k] = buf[k].add(s3);
+                buf[k + 2 * m] = Complex {
+                    re: fm.re + s0.im,
+                    im: fm.im - s0.re,
+                };
+
+                buf[k + m] = Complex {
+                    re: fm.re - s0.im,
+                    im: fm.im + s0.re,
+                };
+            }
+        }
+    }
+
+    fn butterfly4(&self, buf: &mut [Complex], fstride: usize, m: usize, mm: usize) {
+        if m == 1 {
+            // Degenerate case where all the twiddles are 1.
+            for fout in buf.chunks_exact_mut(4).take(fstride) {
+                let s0 = fout[0].sub(fout[2]);
+                fout[0] = fout[0].add(fout[2]);
+                let s1 = fout[1].add(fout[3]);
+                fout[2] = fout[0].sub(s1);
+                fout[0] = fout[0].add(s1);
+                let s1 = fout[1].sub(fout[3]);
+
+                fout[1] = Complex {
+                    re: s0.re + s1.im,
+                    im: s0.im - s1.re,
+                };
+
+                fout[3] = Complex {
+                    re: s0.re - s1.im,
+                    im: s0.im + s1.re,
+                };
+            }
+
+            return;
+        }
+
+        for i in 0..fstride {
+            let base = i * mm;
+            for j in 0..m {
+                let k = base + j;
+                let s0 = buf[k + m].mul(self.twiddles[j * fstride]);
+                let s1 = buf[k + 2 * m].mul(self.twiddles[j * fstride * 2]);
+                let s2 = buf[k + 3 * m].mul(self.twiddles[j * fstride * 3]);
+                let s5 = buf[k].sub(s1);
+                buf[k] = buf[k].add(s1);
+                let s3 = s0.add(s2);
+                let s4 = s0.sub(s2);
+                buf[k + 2 * m] = buf[k].sub(s3);
+                buf[k] = buf[k].add(s3);
+
+                buf[k + m] = Complex {
+                    re: s5.re + s4.im,
+                    im: s5.im - s4.re,
+                };
+
+                buf[k + 3 * m] = Complex {
+                    re: s5.re - s4.im,
+                    im: s5.im + s4.re,
+                };
+            }
+        }
+    }
+
+    fn butterfly5(&self, buf: &mut [Complex], fstride: usize, m: usize, mm: usize) {
+        let ya = self.twiddles[fstride * m];
+        let yb = self.twiddles[fstride * 2 * m];
+        let tw = &self.twiddles;
+        for i in 0..fstride {
+            let base = i * mm;
+            for u in 0..m {
+                let k = base + u;
+                let s0 = buf[k];
+                let s1 = buf[k + m].mul(tw[u * fstride]);
+                let s2 = buf[k + 2 * m].mul(tw[2 * u * fstride]);
+                let s3 = buf[k + 3 * m].mul(tw[3 * u * fstride]);
+                let s4 = buf[k + 4 * m].mul(tw[4 * u * fstride]);
+
+                let s7 = s1.add(s4);
+                let s10 = s1.sub(s4);
+                let s8 = s2.add(s3);
+                let s9 = s2.sub(s3);
+
+                buf[k] = Complex {
+                    re: buf[k].re + (s7.re + s8.re),
+                    im: buf[k].im + (s7.im + s8.im),
+                };
+
+                let s5 = Complex {
+                    re: s0.re + (s7.re * ya.re + s8.re * yb.re),
+                    im: s0.im + (s7.im * ya.re + s8.im * yb.re),
+                };
+
+                let s6 = Complex {
+                    re: s10.im * ya.im + s9.im * yb.im,
+                    im: -(s10.re * ya.im + s9.re * yb.im),
+                };
+
+                buf[k + m] = s5.sub(s6);
+                buf[k + 4 * m] = s5.add(s6);
+
+                let s11 = Complex {
+                    re: s0.re + (s7.re * yb.re + s8.re * ya.re),
+                    im: s0.im + (s7.im * yb.re + s8.im * ya.re),
+                };
+
+                let s12 = Complex {
+                    re: s9.im * ya.im - s10.im * yb.im,
+                    im: s10.re * yb.im - s9.re * ya.im,
+                };
+
+                buf[k + 2 * m] = s11.add(s12);
+                buf[k + 3 * m] = s11.sub(s12);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Complex, Fft};
+
+    #[test]
+    fn matches_dft() {
+        for size in [60, 120, 240, 480] {
+            let fft = Fft::new(size);
+            let input = (0..size)
+                .map(|i| Complex {
+                    re: ((i * 7 % 13) as f32 - 6.0) / 6.0,
+                    im: ((i * 5 % 11) as f32 - 5.0) / 5.0,
+                })
+                .collect::<Vec<_>>();
+
+            let mut buf = vec![Complex::default(); size];
+            for (i, value) in input.iter().enumerate() {
+                buf[fft.bitrev(i)] = *value;
+            }
+
+            fft.process(&mut buf);
+
+            for (k, value) in buf.iter().enumerate() {
+                let (mut re, mut im) = (0.0f64, 0.0f64);
+                for (i, x) in input.iter().enumerate() {
+                    let phase = -2.0 * std::f64::consts::PI * (i * k) as f64 / size as f64;
+                    re += x.re as f64 * phase.cos() - x.im as f64 * phase.sin();
+                    im += x.re as f64 * phase.sin() + x.im as f64 * phase.cos();
+                }
+
+                assert!((value.re as f64 - re).abs() < 1e-3);
+                assert!((value.im as f64 - im).abs() < 1e-3);
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! CELT inverse MDCT implementation
+//!
+//! The inverse MDCT of size `N` is computed with a complex FFT of size `N/4`
+//! surrounded by a pre-rotation and a post-rotation. The windowing only
+//! covers the low overlap between consecutive blocks, and is applied together
+//! with the time-domain aliasing cancellation (TDAC) against the tail left by
+//! the previous transform in the output buffer.
+
+mod fft;
+
+use std::f64::consts::PI;
+
+use self::fft::{Complex, Fft};
+
+use super::{MAX_FRAME_SIZE, MAX_LOG_BLOCKS, OVERLAP};
+
+/// Inverse MDCT for every CELT block size
+#[derive(Debug)]
+pub struct Mdct {
+    /// Rotation twiddles of every transform size, the largest first
+    trig: Vec<f32>,
+    /// FFTs of every transform size, indexed by shift
+    ffts: Vec<Fft>,
+    window: [f32; OVERLAP],
+}
+
+impl Default for Mdct {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mdct {
+    pub fn new() -> Self {
+        let mut trig = Vec::new();
+        let mut ffts = Vec::new();
+        for shift in 0..=MAX_LOG_BLOCKS {
+            let n = (MAX_FRAME_SIZE * 2) >> shift;
+            trig.extend(
+                (0..n / 2).map(|i| (2.0 * PI * (i as f64 + 0.125) / n as f64).cos() as f32),
+            );
+
+            ffts.push(Fft::new(n / 4));
+        }
+
+        let mut window = [0.0; OVERLAP];
+        for (i, window) in window.iter_mut().enumerate() {
+            let x = (0.5 * PI * (i as f64 + 0.5) / OVERLAP as f64).sin();
+            *window = (0.5 * PI * x * x).sin() as f32;
+        }
+
+        Self { trig, ffts, window }
+    }
+
+    /// Low-overlap window shared by consecutive blocks.
+    pub fn window(&self) -> &[f32; OVERLAP] {
+        &self.window
+    }
+
+    /// Computes the inverse MDCT of the `(MAX_FRAME_SIZE >> shift)`
+    /// coefficients of `input` taken every `stride` values.
+    ///
+    /// The first `OVERLAP` samples of `output` must hold the tail of the
+    /// previous transform, they are overlap-added with the new one. Once
+    /// done, `output` holds `MAX_FRAME_SIZE >> shift` finished samples
+    /// followed by `OVERLAP / 2` samples waiting for the next transform.
+    pub fn backward(&self, input: &[f32], stride: usize, output: &mut [f32], shift: usize) {
+        let mut n = MAX_FRAME_SIZE * 2;
+        let mut trig = &self.trig[..];
+        for _ in 0..shift {
+            trig = &trig[n / 2..];
+            n >>= 1;
+        }
+
+        let n2 = n >> 1;
+        let n4 = n >> 2;
+        let fft = &self.ffts[shift];
+
+        // Pre-rotate, storing the values directly in bit-reversed order. The
+        // real and imaginary parts are swapped because a forward FFT is used
+        // instead of an inverse one.
+        let mut buf = vec![Complex::default(); n4];
+        for i in 0..n4 {
+            let x1 = input[2 * i * stride];
+            let x2 = input[stride * (n2 - 1 - 2 * i)];
+
+            buf[fft.bitrev(i)] = Complex {
+                re: x1 * trig[i] - x2 * trig[n4 + i],
+                im: x2 * trig[i] + x1 * trig[n4 + i],
+            };
+        }
+
+        fft.process(&mut buf);
+
+        let out = &mut output[OVERLAP / 2..];
+        for (i, value) in buf.iter().enumerate() {
+            out[2 * i] = value.re;
+            out[2 * i + 1] = value.im;
+        }
+
+        // Post-rotate and de-shuffle from both ends of the buffer at once to
+        // make it in-place.
+        for i in 0..(n4 + 1) >> 1 {
+            let (yp0, yp1) = (2 * i, n2 - 2 - 2 * i);
+
+            let (re, im) = (out[yp0 + 1], out[yp0]);
+            let (t0, t1) = (trig[i], trig[n4 + i]);
+            let yr = re * t0 + im * t1;
+            let yi = re * t1 - im * t0;
+
+            let (re, im) = (out[yp1 + 1], out[yp1]);
+            out[yp0] = yr;
+            out[yp1 + 1] = yi;
+
+            let (t0, t1) = (trig[n4 - i - 1], trig[n2 - i - 1]);
+            let yr = re * t0 + im * t1;
+            let yi = re * t1 - im * t0;
+            out[yp1] = yr;
+            out[yp0 + 1] = yi;
+        }
+
+        // Mirror on both sides for TDAC
+        for i in 0..OVERLAP / 2 {
+            let x1 = output[OVERLAP - 1 - i];
+            let x2 = output[i];
+            let wp1 = self.window[i];
+            let wp2 = self.window[OVERLAP - 1 - i];
+
+            output[i] = wp2 * x2 - wp1 * x1;
+            output[OVERLAP - 1 - i] = wp1 * x2 + wp2 * x1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use super::{MAX_FRAME_SIZE, Mdct, OVERLAP};
+
+    fn basis(n: usize, j: usize, k: usize) -> f64 {
+        (2.0 * PI / n as f64 * (j as f64 + 0.5 + n as f64 / 4.0) * (k as f64 + 0.5)).cos()
+    }
+
+    #[test]
+    fn matches_imdct() {
+        let mdct = Mdct::new();
+        for shift in 0..=3 {
+            let n2 = MAX_FRAME_SIZE >> shift;
+            let n = 2 * n2;
+            let input = (0..n2)
+                .map(|i| ((i * 37 % 101) as f32 - 50.0) / 50.0)
+                .collect::<Vec<_>>();
+
+            let mut output = vec![0.0; n2 + OVERLAP];
+            mdct.backward(&input, 1, &mut output, shift);
+
+            // Without a previous tail, the block is the middle of the full
+            // length IMDCT with its start windowed.
+            for (j, value) in output[..n2 + OVERLAP / 2].iter().enumerate() {
+                let y = (0..n2)
+                    .map(|k| input[k] as f64 * basis(n, n / 4 - OVERLAP / 2 + j, k))
+                    .sum::<f64>();
+                let w = mdct.window().get(j).map_or(1.0, |&w| w as f64);
+                assert!((*value as f64 - w * y).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn tdac() {
+        let mdct = Mdct::new();
+        for shift in 0..=3 {
+            let n2 = MAX_FRAME_SIZE >> shift;
+            let n = 2 * n2;
+            let signal = (0..4 * n2 + OVERLAP)
+                .map(|i| (i as f64 * 0.05).sin() + ((i * 13 % 29) as f64 - 14.0) / 28.0)
+                .collect::<Vec<_>>();
+
+            // Forward transform each windowed block with a direct MDCT, the
+            // overlap-add of the inverse ones must give the signal back.
+            let mut output = vec![0.0; 4 * n2 + OVERLAP];
+            for b in 0..4 {
+                let input = (0..n2)
+                    .map(|k| {
+                        let x = (0..n2 + OVERLAP)
+                            .map(|j| {
+                                let w = match j {
+                                    j if j < OVERLAP => mdct.window()[j],
+                                    j if j >= n2 => mdct.window()[n2 + OVERLAP - 1 - j],
+                                    _ => 1.0,
+                                };
+                                w as f64 * signal[b * n2 + j] * basis(n, n / 4 - OVERLAP / 2 + j, k)
+                            })
+                            .sum::<f64>();
+                        (x * 2.0 / n2 as f64) as f32
+                    })
+                    .collect::<Vec<_>>();
+
+                mdct.backward(&input, 1, &mut output[b * n2..], shift);
+            }
+
+            for j in OVERLAP..4 * n2 {
+                assert!((output[j] as f64 - signal[j]).abs() < 1e-3);
+            }
+        }
+    }
+}
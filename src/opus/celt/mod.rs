@@ -1,6 +1,12 @@
+mod anti_collapse;
+mod bands;
 mod bit_alloc;
 mod coarse_energy;
+mod fine_energy;
+mod mdct;
+mod plc;
 mod post_filter;
+mod pvq;
 mod time_frequency_change;
 
 use std::ops::Range;
@@ -11,8 +17,13 @@ use super::{
 };
 
 use self::{
+    anti_collapse::AntiCollapse,
+    bands::Bands,
     bit_alloc::{BitAlloc, Spread},
     coarse_energy::CoarseEnergy,
+    fine_energy::FineEnergy,
+    mdct::Mdct,
+    plc::{LPC_ORDER, Plc},
     post_filter::PostFilter,
     time_frequency_change::TimeFrequencyChange,
 };
@@ -21,6 +32,20 @@ pub const MAX_BANDS: usize = 21;
 pub const SHORT_BLOCKSIZE: usize = 120;
 pub const MAX_LOG_BLOCKS: usize = 3;
 pub const MAX_FRAME_SIZE: usize = SHORT_BLOCKSIZE * (1 << MAX_LOG_BLOCKS);
+pub const CELT_ENERGY_SILENCE: f32 = -28.0;
+
+/// Overlap between consecutive MDCT blocks.
+pub const OVERLAP: usize = SHORT_BLOCKSIZE;
+
+/// Length of the synthesized history kept for each channel.
+pub const DECODE_BUFFER_SIZE: usize = 2048;
+
+/// Coefficient of the de-emphasis filter, the inverse of the pre-emphasis
+/// applied by the encoder.
+pub const DEEMPHASIS: f32 = 0.8500061;
+
+/// Added to the de-emphasis input to keep the filter out of denormals.
+const VERY_SMALL: f32 = 1e-30;
 
 pub trait CeltBandwidthBand {
     fn band(&self) -> usize;
@@ -38,12 +63,39 @@ impl CeltBandwidthBand for Bandwidth {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct CeltBlock {
     post_filter: PostFilter,
     energy: [f32; MAX_BANDS],
-    // coeffs: [f32; MAX_FRAME_SIZE],
+    coeffs: [f32; MAX_FRAME_SIZE],
     collapse_masks: [u8; 21],
+    /// Energies of the two previous frames, the most recent first.
+    prev_energy: [[f32; MAX_BANDS]; 2],
+    /// Synthesized time-domain samples, the last `OVERLAP / 2` ones being
+    /// the tail waiting to be overlap-added with the next frame.
+    history: [f32; DECODE_BUFFER_SIZE + OVERLAP],
+    deemphasis: f32,
+    /// LPC filter of the history, computed on the first lost frame.
+    lpc: [f32; LPC_ORDER],
+    /// Floor of the band energies, the level the noise of the concealment
+    /// decays to.
+    background_energy: [f32; MAX_BANDS],
+}
+
+impl Default for CeltBlock {
+    fn default() -> Self {
+        Self {
+            post_filter: PostFilter::default(),
+            energy: [0.0; MAX_BANDS],
+            coeffs: [0.0; MAX_FRAME_SIZE],
+            collapse_masks: [0; 21],
+            prev_energy: [[CELT_ENERGY_SILENCE; MAX_BANDS]; 2],
+            history: [0.0; DECODE_BUFFER_SIZE + OVERLAP],
+            deemphasis: 0.0,
+            lpc: [0.0; LPC_ORDER],
+            background_energy: [0.0; MAX_BANDS],
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -59,6 +111,32 @@ pub struct CeltFrameDecoder {
     caps: [i32; MAX_BANDS],
     alloc_trim: i32,
     anticollapse_needed: i32,
+    anticollapse: bool,
+    pulses: [i32; MAX_BANDS],
+    fine_bits: [i32; MAX_BANDS],
+    fine_priority: [i32; MAX_BANDS],
+    coded_bands: usize,
+    intensity_stereo: usize,
+    dual_stereo: bool,
+    balance: i32,
+    /// Seed of the noise generator, the final range of the previous frame.
+    seed: u32,
+    /// Whether the inverted side of intensity stereo is ignored, for an
+    /// output downmixed to mono where it would cancel the mid out.
+    disable_inv: bool,
+    /// Channels of the decoder output, which may differ from the coded ones.
+    output_channels: Channels,
+    /// Ratio of 48 kHz to the output sample rate, the frames are synthesized
+    /// at 48 kHz without the bands above the output bandwidth and decimated.
+    downsample: usize,
+    /// Number of frames concealed since the last decoded one.
+    loss_count: usize,
+    /// Whether the pitch-based concealment is skipped, the history not being
+    /// made of two frames decoded in a row.
+    skip_plc: bool,
+    /// Pitch period of the history, found on the first lost frame.
+    last_pitch_index: usize,
+    mdct: Mdct,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -67,10 +145,41 @@ pub enum CeltFrameDecodeError {
 }
 
 impl CeltFrameDecoder {
+    /// Creates a decoder for an output of `channels` channels at
+    /// `sample_rate` Hz.
+    pub fn new(sample_rate: u32, channels: Channels) -> Self {
+        Self {
+            disable_inv: channels == Channels::Mono,
+            output_channels: channels,
+            downsample: (48000 / sample_rate) as usize,
+            skip_plc: true,
+            ..Default::default()
+        }
+    }
+
+    /// Discards the state of the previous frames, the next frame is decoded
+    /// as the first one of a stream.
+    pub fn reset(&mut self) {
+        self.blocks = Default::default();
+        self.seed = 0;
+        self.loss_count = 0;
+        self.skip_plc = true;
+        self.last_pitch_index = 0;
+    }
+
+    /// Returns the MDCT overlap window, which also shapes the cross-fades
+    /// between CELT and SILK frames.
+    pub fn window(&self) -> &[f32; OVERLAP] {
+        self.mdct.window()
+    }
+
+    /// Decodes a CELT frame, writing the time-domain samples at the output
+    /// rate of each coded channel into `output`.
     pub fn decode(
         &mut self,
         toc: &TableOfContents,
         range_dec: &mut RangeCodingDecoder,
+        output: &mut [Vec<f32>],
     ) -> Result<(), CeltFrameDecodeError> {
         self.channels = toc.channels;
 
@@ -96,20 +205,34 @@ impl CeltFrameDecoder {
         // if there are none then the whole frame is silent. If there are no bits
         // left to read, the frame is silent. If there are no bits left to read,
         // the frame is empty or the packet is lost.
-        self.silence = if range_dec.available() > 0 {
+        //
+        // The flag is only coded at the start of the bitstream, in Hybrid mode
+        // the SILK layer has already consumed bits and the frame is never
+        // silent.
+        self.silence = if range_dec.available() == 0 {
+            true
+        } else if range_dec.tell() == 1 {
             // Because little mute is uncommon in audio, it is encoded here as a
             // probability, indicating a large probability that it is not muted
             // and only a small probability that it is.
             range_dec.logp(15)
         } else {
-            true
+            false
         };
 
         if self.silence {
             range_dec.to_end();
         }
 
-        let mut block: [CeltBlock; 2] = Default::default();
+        // The pitch-based concealment needs two frames decoded in a row.
+        self.skip_plc = self.loss_count != 0;
+
+        // The post-filter parameters only apply to the frame they are coded
+        // in, a frame without them turns the filter off.
+        for block in self.blocks.iter_mut() {
+            block.post_filter.period_new = 0;
+            block.post_filter.gains_new = [0.0; 3];
+        }
 
         // Parsing post-filter
         //
@@ -133,12 +256,13 @@ impl CeltFrameDecoder {
             false
         };
 
-        let blocks = if self.transient { 1 << self.size } else { 1 } as usize;
-        let block_size = toc.duration as usize / blocks;
-
+        // The energies of a mono frame are predicted from the louder of the
+        // two channels of the previous frame, in case the stream switched from
+        // stereo to mono.
         if self.channels == Channels::Mono {
-            for i in 0..MAX_BANDS {
-                block[0].energy[i] = block[0].energy[i].max(block[1].energy[i]);
+            let [left, right] = &mut self.blocks;
+            for (left, right) in left.energy.iter_mut().zip(right.energy) {
+                *left = left.max(right);
             }
         }
 
@@ -151,6 +275,222 @@ impl CeltFrameDecoder {
         // bit alloc
         BitAlloc::decode(self, range_dec);
 
+        // fine energy
+        FineEnergy::decode(self, range_dec);
+
+        // band shapes
+        Bands::decode(self, range_dec);
+
+        // anti-collapse flag
+        AntiCollapse::decode(self, range_dec);
+
+        // The bits left once the band shapes are decoded refine the energy a
+        // little further.
+        FineEnergy::decode_final(self, range_dec, range_dec.available());
+
+        if self.anticollapse {
+            AntiCollapse::process(self);
+        }
+
+        // Scale the band shapes by their energies
+        Bands::denormalise(self);
+
+        self.synthesis();
+        self.deemphasis(output);
+
+        if self.silence {
+            for block in self.blocks.iter_mut() {
+                block.energy = [CELT_ENERGY_SILENCE; MAX_BANDS];
+            }
+        }
+
+        // Keep the energies of both channels in step for a mono stream, so the
+        // prediction of the next frame works whichever layout it is coded in.
+        if self.channels == Channels::Mono {
+            self.blocks[1].energy = self.blocks[0].energy;
+        }
+
+        // The energy history only moves forward on frames with long blocks,
+        // the energy of a transient frame is not representative of the
+        // signal and only lowers it.
+        for block in self.blocks.iter_mut() {
+            if self.transient {
+                for (prev, energy) in block.prev_energy[0].iter_mut().zip(block.energy) {
+                    *prev = prev.min(energy);
+                }
+            } else {
+                block.prev_energy[1] = block.prev_energy[0];
+                block.prev_energy[0] = block.energy;
+            }
+        }
+
+        // The background noise level follows the energy down immediately, and
+        // up by at most 2.4 dB per second, or 6 dB per frame in DTX.
+        let max_increase = if self.loss_count < 10 {
+            (1 << self.size) as f32 * 0.001
+        } else {
+            1.0
+        };
+
+        for block in self.blocks.iter_mut() {
+            for (background, energy) in block.background_energy.iter_mut().zip(block.energy) {
+                *background = (*background + max_increase).min(energy);
+            }
+        }
+
+        // Bands outside of the coded range do not take part in the prediction
+        // of the next frame.
+        for block in self.blocks.iter_mut() {
+            for band in 0..MAX_BANDS {
+                if !self.band_range.contains(&band) {
+                    block.energy[band] = 0.0;
+                    block.prev_energy[0][band] = CELT_ENERGY_SILENCE;
+                    block.prev_energy[1][band] = CELT_ENERGY_SILENCE;
+                }
+            }
+        }
+
+        self.seed = range_dec.range();
+        self.loss_count = 0;
+
         Ok(())
     }
+
+    /// Conceals a lost frame of the duration of `toc` from the previous
+    /// frames, writing the samples at the output rate of each coded channel
+    /// into `output`.
+    ///
+    /// The bandwidth of `toc` is ignored, the concealment covers the bands of
+    /// the last decoded frame.
+    pub fn conceal(&mut self, toc: &TableOfContents, output: &mut [Vec<f32>]) {
+        self.channels = toc.channels;
+        self.band_range.start = if toc.mode == EncodeMode::Hybrid {
+            17
+        } else {
+            0
+        };
+
+        self.size = (toc.duration as usize / SHORT_BLOCKSIZE).ilog2() as usize;
+
+        // The pitch period is only extended over the first few lost frames,
+        // the later ones fade out into noise at the background level. In
+        // Hybrid mode, the low band is concealed by SILK and the bands left
+        // are noise-like.
+        if self.loss_count >= 5 || self.band_range.start != 0 || self.skip_plc {
+            Plc::noise(self);
+        } else {
+            Plc::pitch(self);
+        }
+
+        self.deemphasis(output);
+        self.loss_count += 1;
+    }
+
+    /// Runs the synthesized samples of the frame through the de-emphasis
+    /// filter and scales them to the [-1, 1] PCM range, one buffer of frame
+    /// size at the output rate per coded channel.
+    ///
+    /// The filter memory of both channels is kept up to date for a mono
+    /// stream, in case the next frames are coded in stereo.
+    fn deemphasis(&mut self, output: &mut [Vec<f32>]) {
+        let frame_size = SHORT_BLOCKSIZE << self.size;
+
+        let mut scratch = Vec::new();
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            let samples = output.get_mut(i).unwrap_or(&mut scratch);
+            samples.clear();
+
+            // The filter runs at 48 kHz, the output is decimated.
+            let history = &block.history[DECODE_BUFFER_SIZE - frame_size..DECODE_BUFFER_SIZE];
+            for (j, x) in history.iter().enumerate() {
+                let tmp = x + VERY_SMALL + block.deemphasis;
+                block.deemphasis = DEEMPHASIS * tmp;
+                if j % self.downsample == 0 {
+                    samples.push(tmp / 32768.0);
+                }
+            }
+        }
+    }
+
+    /// Transforms the coefficients of the frame back to the time domain and
+    /// applies the pitch post-filter, appending the samples to the history
+    /// of each channel.
+    fn synthesis(&mut self) {
+        let frame_size = SHORT_BLOCKSIZE << self.size;
+
+        self.inverse_transform();
+
+        for block in self.blocks.iter_mut() {
+            block.post_filter.process(
+                &mut block.history,
+                DECODE_BUFFER_SIZE - frame_size,
+                frame_size,
+                self.mdct.window(),
+            );
+        }
+    }
+
+    /// Transforms the coefficients of the frame back to the time domain,
+    /// appending the samples to the history of each channel.
+    fn inverse_transform(&mut self) {
+        let frame_size = SHORT_BLOCKSIZE << self.size;
+
+        // A transient frame is made of several interleaved short MDCTs,
+        // otherwise there is a single long one.
+        let (blocks, block_size, shift) = if self.transient {
+            (1 << self.size, SHORT_BLOCKSIZE, MAX_LOG_BLOCKS)
+        } else {
+            (1, frame_size, MAX_LOG_BLOCKS - self.size)
+        };
+
+        // Both histories keep running for a mono stream, in case the next
+        // frames are coded in stereo.
+        if self.channels == Channels::Mono {
+            let [left, right] = &mut self.blocks;
+            right.coeffs = left.coeffs;
+        }
+
+        for block in self.blocks.iter_mut() {
+            block.history.copy_within(frame_size.., 0);
+
+            let output = &mut block.history[DECODE_BUFFER_SIZE - frame_size..];
+            for b in 0..blocks {
+                self.mdct.backward(
+                    &block.coeffs[b..],
+                    blocks,
+                    &mut output[block_size * b..],
+                    shift,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CeltFrameDecoder, Channels, DECODE_BUFFER_SIZE, DEEMPHASIS};
+
+    #[test]
+    fn deemphasis_step_response() {
+        for (sample_rate, downsample) in [(48000, 1), (24000, 2)] {
+            let mut dec = CeltFrameDecoder::new(sample_rate, Channels::Mono);
+            dec.blocks[0].history[DECODE_BUFFER_SIZE - 120..DECODE_BUFFER_SIZE].fill(3276.8);
+
+            // The filter integrates the step towards 1 / (1 - DEEMPHASIS) of
+            // its height, the memory carrying over to the next frame.
+            let mut output = vec![Vec::new()];
+            for frame in 0..2 {
+                dec.deemphasis(&mut output);
+                assert_eq!(output[0].len(), 120 / downsample);
+
+                for (i, y) in output[0].iter().enumerate() {
+                    let n = (frame * 120 + i * downsample + 1) as i32;
+                    let expected = 0.1 * (1.0 - DEEMPHASIS.powi(n)) / (1.0 - DEEMPHASIS);
+                    assert!((y - expected).abs() < 1e-5);
+                }
+            }
+
+            assert!((output[0][120 / downsample - 1] - 0.1 / (1.0 - DEEMPHASIS)).abs() < 1e-5);
+        }
+    }
 }
@@ -0,0 +1,489 @@
+//! CELT packet loss concealment implementation
+//!
+//! A lost frame is rebuilt from the history of the previous frames. While the
+//! signal is periodic, the last pitch periods of the history are whitened by
+//! an LPC filter, repeated with a decaying gain and shaped back by the same
+//! filter. After a few lost frames in a row, or without a reliable history,
+//! the bands are filled with noise instead, at energies decaying towards the
+//! background noise level.
+
+use crate::opus::toc::Channels;
+
+use super::{
+    CeltBlock, CeltFrameDecoder, DECODE_BUFFER_SIZE, OVERLAP, SHORT_BLOCKSIZE,
+    bands::{Bands, lcg_rand, renormalise_vector},
+    bit_alloc::FREQ_BANDS,
+    post_filter::POSTFILTER_MINPERIOD,
+};
+
+/// Order of the LPC filter of the pitch-based concealment.
+pub const LPC_ORDER: usize = 24;
+
+/// Length of the history the excitation and its LPC filter are taken from.
+const MAX_PERIOD: usize = 1024;
+
+/// Range of the pitch periods searched in the history.
+const PLC_PITCH_LAG_MAX: usize = 720;
+const PLC_PITCH_LAG_MIN: usize = 100;
+
+/// Packet loss concealment
+pub struct Plc;
+
+impl Plc {
+    /// Conceals the frame with noise in every band, at the energies of the
+    /// previous frame lowered by a few dB, but not below the background
+    /// noise level.
+    pub fn noise(dec: &mut CeltFrameDecoder) {
+        let lm = dec.size;
+
+        // The first lost frame drops faster than the next ones. Only the
+        // output channels decay, for a mono output the second channel keeps
+        // the energies of the last decoded frame, the louder of both being
+        // the prediction of a mono frame.
+        let decay = if dec.loss_count == 0 { 1.5 } else { 0.5 };
+        for block in dec.blocks.iter_mut().take(dec.output_channels as usize) {
+            for band in dec.band_range.clone() {
+                block.energy[band] = block.background_energy[band].max(block.energy[band] - decay);
+            }
+        }
+
+        let mut seed = dec.seed;
+        for block in dec.blocks.iter_mut().take(dec.channels as usize) {
+            for band in dec.band_range.clone() {
+                let range =
+                    (FREQ_BANDS[band] as usize) << lm..(FREQ_BANDS[band + 1] as usize) << lm;
+                for x in block.coeffs[range.clone()].iter_mut() {
+                    seed = lcg_rand(seed);
+                    *x = (seed as i32 >> 20) as f32;
+                }
+
+                renormalise_vector(&mut block.coeffs[range], 1.0);
+            }
+        }
+
+        dec.seed = seed;
+
+        // The noise goes through the inverse MDCT of a regular frame, without
+        // the post-filter.
+        dec.silence = false;
+        dec.transient = false;
+        Bands::denormalise(dec);
+        dec.inverse_transform();
+    }
+
+    /// Conceals the frame by extending the last pitch periods of the history
+    /// of each channel.
+    pub fn pitch(dec: &mut CeltFrameDecoder) {
+        let frame_size = SHORT_BLOCKSIZE << dec.size;
+        let window = *dec.mdct.window();
+        let channels = dec.channels as usize;
+
+        // The pitch is searched once on the first lost frame, the next lost
+        // frames repeat the same period with a lower gain.
+        let first_loss = dec.loss_count == 0;
+        let fade = if first_loss {
+            dec.last_pitch_index = Self::search_pitch(&dec.blocks[..channels]);
+            1.0
+        } else {
+            0.8
+        };
+
+        let pitch_index = dec.last_pitch_index;
+        for block in dec.blocks.iter_mut().take(channels) {
+            Self::extrapolate(block, pitch_index, fade, first_loss, frame_size, &window);
+        }
+
+        // Both histories keep running for a mono stream, in case the next
+        // frames are coded in stereo.
+        if dec.channels == Channels::Mono {
+            let [left, right] = &mut dec.blocks;
+            right.history = left.history;
+        }
+    }
+
+    /// Finds the pitch period of the last samples of the history, on a
+    /// downmixed and 2x downsampled copy of it.
+    fn search_pitch(blocks: &[CeltBlock]) -> usize {
+        let mut lp = [0.0; DECODE_BUFFER_SIZE >> 1];
+        pitch_downsample(blocks, &mut lp);
+
+        let pitch = pitch_search(
+            &lp[PLC_PITCH_LAG_MAX >> 1..],
+            &lp,
+            DECODE_BUFFER_SIZE - PLC_PITCH_LAG_MAX,
+            PLC_PITCH_LAG_MAX - PLC_PITCH_LAG_MIN,
+        );
+
+        PLC_PITCH_LAG_MAX - pitch
+    }
+
+    /// Replaces the frame and the overlap following it in the history of
+    /// `block` with the LPC excitation of the last `pitch_index` samples,
+    /// repeated and filtered back.
+    fn extrapolate(
+        block: &mut CeltBlock,
+        pitch_index: usize,
+        fade: f32,
+        first_loss: bool,
+        frame_size: usize,
+        window: &[f32; OVERLAP],
+    ) {
+        const DBS: usize = DECODE_BUFFER_SIZE;
+
+        let buf = &mut block.history;
+
+        // The excitation of two pitch periods is enough to tell whether the
+        // signal is decaying, the LPC filter needs the samples before it.
+        let exc_length = (2 * pitch_index).min(MAX_PERIOD);
+        let mut exc = [0.0; MAX_PERIOD + LPC_ORDER];
+        exc.copy_from_slice(&buf[DBS - MAX_PERIOD - LPC_ORDER..DBS]);
+
+        if first_loss {
+            let mut ac = [0.0; LPC_ORDER + 1];
+            autocorr(&exc[LPC_ORDER..], &mut ac, Some(window));
+
+            // A noise floor of -40 dB and a lag window keep the recursion
+            // stable.
+            ac[0] *= 1.0001;
+            for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+                *ac -= *ac * (0.008 * 0.008) * i as f32 * i as f32;
+            }
+
+            lpc(&mut block.lpc, &ac);
+        }
+
+        let start = LPC_ORDER + MAX_PERIOD - exc_length;
+        let mut fir_tmp = vec![0.0; exc_length];
+        fir(&exc[start - LPC_ORDER..], &block.lpc, &mut fir_tmp);
+        exc[start..].copy_from_slice(&fir_tmp);
+
+        // Compare the energies of the last two half excitations, to avoid
+        // adding energy to a decaying signal.
+        let exc = &exc[LPC_ORDER..];
+        let decay_length = exc_length >> 1;
+        let energy = |x: &[f32]| x.iter().fold(1.0, |e, x| e + x * x);
+        let e1 = energy(&exc[MAX_PERIOD - decay_length..MAX_PERIOD]);
+        let e2 = energy(&exc[MAX_PERIOD - 2 * decay_length..MAX_PERIOD - decay_length]);
+        let decay = (e1.min(e2) / e2).sqrt();
+
+        // Make room for the frame, the overlap past the end of the buffer is
+        // rebuilt as well.
+        buf.copy_within(frame_size..DBS, 0);
+
+        // Repeat the last period, each repetition scaled down by the decay,
+        // and measure the energy of the signal whose excitation is copied.
+        let offset = MAX_PERIOD - pitch_index;
+        let len = frame_size + OVERLAP;
+        let mut attenuation = fade * decay;
+        let mut s1 = 0.0;
+        let mut j = 0;
+        for i in 0..len {
+            if j >= pitch_index {
+                j -= pitch_index;
+                attenuation *= decay;
+            }
+
+            buf[DBS - frame_size + i] = attenuation * exc[offset + j];
+
+            let x = buf[DBS - MAX_PERIOD - frame_size + offset + j];
+            s1 += x * x;
+            j += 1;
+        }
+
+        // The synthesis filter starts from the last decoded samples, for a
+        // continuous signal.
+        let mut mem = [0.0; LPC_ORDER];
+        for (i, mem) in mem.iter_mut().enumerate() {
+            *mem = buf[DBS - frame_size - 1 - i];
+        }
+
+        let frame = &mut buf[DBS - frame_size..DBS + OVERLAP];
+        iir(frame, &block.lpc, &mut mem);
+
+        // The synthesis may come out louder than the original signal, or even
+        // blow up into NaNs.
+        let s2: f32 = frame.iter().map(|x| x * x).sum();
+        if s2.is_nan() || s1 <= 0.2 * s2 {
+            frame.fill(0.0);
+        } else if s1 < s2 {
+            let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+            for (x, w) in frame.iter_mut().zip(window) {
+                *x *= 1.0 - w * (1.0 - ratio);
+            }
+
+            for x in frame[OVERLAP..].iter_mut() {
+                *x *= ratio;
+            }
+        }
+
+        // The next frame applies the post-filter again over the overlap, so
+        // it is undone here, then the TDAC of the MDCT is simulated for the
+        // overlap to blend with the next frame.
+        let mut etmp = [0.0; OVERLAP];
+        inverse_comb_filter(
+            &mut etmp,
+            buf,
+            block.post_filter.period,
+            block.post_filter.gains,
+        );
+
+        for i in 0..OVERLAP / 2 {
+            buf[DBS + i] = window[i] * etmp[OVERLAP - 1 - i] + window[OVERLAP - 1 - i] * etmp[i];
+        }
+    }
+}
+
+/// Runs the overlap following the history of `buf` through the inverse of
+/// the comb filter of `period` and `gains` into `out`.
+fn inverse_comb_filter(out: &mut [f32; OVERLAP], buf: &[f32], period: usize, gains: [f32; 3]) {
+    let x = DECODE_BUFFER_SIZE;
+    if gains[0] == 0.0 {
+        out.copy_from_slice(&buf[x..x + OVERLAP]);
+        return;
+    }
+
+    let t = period.max(POSTFILTER_MINPERIOD);
+    for (i, out) in out.iter_mut().enumerate() {
+        let j = x + i;
+        *out = buf[j]
+            - gains[0] * buf[j - t]
+            - gains[1] * (buf[j - t + 1] + buf[j - t - 1])
+            - gains[2] * (buf[j - t + 2] + buf[j - t - 2]);
+    }
+}
+
+/// Low-passes and downsamples the history of every channel by 2 into `x_lp`,
+/// then whitens the result with a 4th order LPC filter.
+fn pitch_downsample(blocks: &[CeltBlock], x_lp: &mut [f32]) {
+    x_lp.fill(0.0);
+    for block in blocks {
+        let x = &block.history;
+        x_lp[0] += 0.5 * (0.5 * x[1] + x[0]);
+        for (i, x_lp) in x_lp.iter_mut().enumerate().skip(1) {
+            *x_lp += 0.5 * (0.5 * (x[2 * i - 1] + x[2 * i + 1]) + x[2 * i]);
+        }
+    }
+
+    let mut ac = [0.0; 5];
+    autocorr(x_lp, &mut ac, None);
+
+    // Noise floor of -40 dB and lag window
+    ac[0] *= 1.0001;
+    for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+        *ac -= *ac * (0.008 * i as f32) * (0.008 * i as f32);
+    }
+
+    let mut lpc4 = [0.0; 4];
+    lpc(&mut lpc4, &ac);
+
+    let mut tmp = 1.0;
+    for lpc in lpc4.iter_mut() {
+        tmp *= 0.9;
+        *lpc *= tmp;
+    }
+
+    // Add a zero
+    const C1: f32 = 0.8;
+    let lpc2 = [
+        lpc4[0] + C1,
+        lpc4[1] + C1 * lpc4[0],
+        lpc4[2] + C1 * lpc4[1],
+        lpc4[3] + C1 * lpc4[2],
+        C1 * lpc4[3],
+    ];
+
+    let mut mem = [0.0; 5];
+    for x in x_lp.iter_mut() {
+        let sum = *x + lpc2.iter().zip(&mem).map(|(a, m)| a * m).sum::<f32>();
+        mem.copy_within(..4, 1);
+        mem[0] = *x;
+        *x = sum;
+    }
+}
+
+/// Finds the lag of `y` in `[0, max_pitch)` best correlated with the `len`
+/// samples of `x_lp`, both signals being downsampled by 2. The search starts
+/// with a 4x downsampling and is refined around the two best candidates.
+fn pitch_search(x_lp: &[f32], y: &[f32], len: usize, max_pitch: usize) -> usize {
+    let lag = len + max_pitch;
+    let inner = |x: &[f32], y: &[f32]| x.iter().zip(y).map(|(x, y)| x * y).sum::<f32>();
+
+    let x_lp4: Vec<f32> = x_lp.iter().step_by(2).take(len >> 2).copied().collect();
+    let y_lp4: Vec<f32> = y.iter().step_by(2).take(lag >> 2).copied().collect();
+
+    let mut xcorr = vec![0.0; max_pitch >> 1];
+    for (i, xcorr) in xcorr.iter_mut().enumerate().take(max_pitch >> 2) {
+        *xcorr = inner(&x_lp4, &y_lp4[i..]);
+    }
+
+    let best_pitch = find_best_pitch(&xcorr, &y_lp4, len >> 2, max_pitch >> 2);
+
+    // Finer search around the best candidates only
+    for (i, xcorr) in xcorr.iter_mut().enumerate() {
+        *xcorr = 0.0;
+        if (i as isize - 2 * best_pitch[0] as isize).abs() > 2
+            && (i as isize - 2 * best_pitch[1] as isize).abs() > 2
+        {
+            continue;
+        }
+
+        *xcorr = inner(&x_lp[..len >> 1], &y[i..]).max(-1.0);
+    }
+
+    let best_pitch = find_best_pitch(&xcorr, y, len >> 1, max_pitch >> 1);
+
+    // Pseudo-interpolation from the neighbours of the peak
+    let offset = if best_pitch[0] > 0 && best_pitch[0] < (max_pitch >> 1) - 1 {
+        let a = xcorr[best_pitch[0] - 1];
+        let b = xcorr[best_pitch[0]];
+        let c = xcorr[best_pitch[0] + 1];
+        if c - a > 0.7 * (b - a) {
+            1
+        } else if a - c > 0.7 * (b - c) {
+            -1
+        } else {
+            0
+        }
+    } else {
+        0
+    };
+
+    (2 * best_pitch[0] as isize - offset) as usize
+}
+
+/// Returns the two lags with the highest normalized correlation, the best
+/// one first.
+fn find_best_pitch(xcorr: &[f32], y: &[f32], len: usize, max_pitch: usize) -> [usize; 2] {
+    let mut syy = 1.0 + y[..len].iter().map(|y| y * y).sum::<f32>();
+    let mut best_num = [-1.0, -1.0];
+    let mut best_den = [0.0, 0.0];
+    let mut best_pitch = [0, 1];
+
+    for i in 0..max_pitch {
+        if xcorr[i] > 0.0 {
+            // Keeps the square in range
+            let xcorr16 = xcorr[i] * 1e-12;
+            let num = xcorr16 * xcorr16;
+            if num * best_den[1] > best_num[1] * syy {
+                if num * best_den[0] > best_num[0] * syy {
+                    best_num[1] = best_num[0];
+                    best_den[1] = best_den[0];
+                    best_pitch[1] = best_pitch[0];
+                    best_num[0] = num;
+                    best_den[0] = syy;
+                    best_pitch[0] = i;
+                } else {
+                    best_num[1] = num;
+                    best_den[1] = syy;
+                    best_pitch[1] = i;
+                }
+            }
+        }
+
+        syy += y[i + len] * y[i + len] - y[i] * y[i];
+        syy = syy.max(1.0);
+    }
+
+    best_pitch
+}
+
+/// Computes the autocorrelation of `x` for the lags of `ac`, with both ends
+/// of `x` tapered by the overlap window if given.
+fn autocorr(x: &[f32], ac: &mut [f32], window: Option<&[f32; OVERLAP]>) {
+    let n = x.len();
+    let mut xx = x.to_vec();
+    if let Some(window) = window {
+        for (i, w) in window.iter().enumerate() {
+            xx[i] *= w;
+            xx[n - i - 1] *= w;
+        }
+    }
+
+    // The products common to all lags are summed first, then the tail of
+    // each lag.
+    let fast_n = n - (ac.len() - 1);
+    for (k, ac) in ac.iter_mut().enumerate() {
+        *ac = (0..fast_n).map(|i| xx[i] * xx[i + k]).sum::<f32>()
+            + (k + fast_n..n).map(|i| xx[i] * xx[i - k]).sum::<f32>();
+    }
+}
+
+/// Levinson-Durbin recursion, computes the LPC coefficients of `lpc` from
+/// the autocorrelation `ac`. It stops early once the prediction gain reaches
+/// 30 dB.
+fn lpc(lpc: &mut [f32], ac: &[f32]) {
+    lpc.fill(0.0);
+    if ac[0] == 0.0 {
+        return;
+    }
+
+    let mut error = ac[0];
+    for i in 0..lpc.len() {
+        // Reflection coefficient of this iteration
+        let rr = (0..i).map(|j| lpc[j] * ac[i - j]).sum::<f32>() + ac[i + 1];
+        let r = -rr / error;
+
+        lpc[i] = r;
+        for j in 0..(i + 1) >> 1 {
+            let tmp1 = lpc[j];
+            let tmp2 = lpc[i - 1 - j];
+            lpc[j] = tmp1 + r * tmp2;
+            lpc[i - 1 - j] = tmp2 + r * tmp1;
+        }
+
+        error -= r * r * error;
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
+/// Filters `x` through the analysis filter `num` into `y`, `x` starting with
+/// the `num.len()` samples preceding the ones filtered.
+fn fir(x: &[f32], num: &[f32], y: &mut [f32]) {
+    let ord = num.len();
+    for (i, y) in y.iter_mut().enumerate() {
+        *y = x[i + ord]
+            + num
+                .iter()
+                .enumerate()
+                .map(|(j, num)| num * x[i + ord - j - 1])
+                .sum::<f32>();
+    }
+}
+
+/// Runs `x` in place through the synthesis filter `den`, `mem` holding the
+/// last outputs of the filter, the most recent first.
+fn iir(x: &mut [f32], den: &[f32], mem: &mut [f32]) {
+    for x in x.iter_mut() {
+        let sum = *x - den.iter().zip(mem.iter()).map(|(d, m)| d * m).sum::<f32>();
+        mem.copy_within(..mem.len() - 1, 1);
+        mem[0] = sum;
+        *x = sum;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fir, iir, lpc};
+
+    #[test]
+    fn lpc_filters_invert_each_other() {
+        // Autocorrelation of a first order AR process with a 0.9 pole
+        let ac: Vec<f32> = (0..5).map(|i| 0.9f32.powi(i)).collect();
+        let mut a = [0.0; 4];
+        lpc(&mut a, &ac);
+        assert!((a[0] + 0.9).abs() < 1e-4 && a[1..].iter().all(|a| a.abs() < 1e-4));
+
+        // Whitening then synthesis gives the input back.
+        let x: Vec<f32> = (0..64).map(|i| (i as f32 * 0.3).sin()).collect();
+        let mut y = vec![0.0; 60];
+        fir(&x, &a, &mut y);
+
+        let mut mem = [x[3], x[2], x[1], x[0]];
+        iir(&mut y, &a, &mut mem);
+        for (y, x) in y.iter().zip(&x[4..]) {
+            assert!((y - x).abs() < 1e-4);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::opus::entropy::{CeltRangeCoding, RangeCodingDecoder};
 
-use super::CeltFrameDecoder;
+use super::{CeltFrameDecoder, OVERLAP, SHORT_BLOCKSIZE};
 
 pub const POSTFILTER_MINPERIOD: usize = 15;
 
@@ -10,23 +10,32 @@ pub const TAPS: [[f32; 3]; 3] = [
     // g0 = 0.3066406250,
     // g1 = 0.2170410156,
     // g2 = 0.1296386719.
-    [0.3066406250, 0.2170410156, 0.1296386719],
+    [0.30664062, 0.21704102, 0.12963867],
     // Tapset one corresponds to the filter coefficients
     // g0 = 0.4638671875,
     // g1 = 0.2680664062,
     // g2 = 0.
-    [0.4638671875, 0.2680664062, 0.0],
+    [0.4638672, 0.2680664, 0.0],
     // tapset two uses filter coefficients
     // g0 = 0.7998046875,
     // g1 = 0.1000976562,
     // g2 = 0.
-    [0.7998046875, 0.1000976562, 0.0],
+    [0.7998047, 0.100097656, 0.0],
 ];
 
 pub const TAPSET_MODEL_DICT: [usize; 4] = [4, 2, 3, 4];
 
+/// Pitch post-filter state of a channel
+///
+/// The filter parameters change at most once per frame. The ones of the
+/// previous frames are kept to cross-fade between the old and new filters
+/// over the overlap window.
 #[derive(Debug, Default, Clone, Copy)]
 pub struct PostFilter {
+    pub period_old: usize,
+    pub gains_old: [f32; 3],
+    pub period: usize,
+    pub gains: [f32; 3],
     pub period_new: usize,
     pub gains_new: [f32; 3],
 }
@@ -64,4 +73,178 @@ impl PostFilter {
             };
         }
     }
+    /// Applies the comb filter in place to the frame of `frame_size` samples
+    /// starting at `offset` in `buf`, the samples before it being the already
+    /// filtered output of the previous frames.
+    pub fn process(
+        &mut self,
+        buf: &mut [f32],
+        offset: usize,
+        frame_size: usize,
+        window: &[f32; OVERLAP],
+    ) {
+        self.period = self.period.max(POSTFILTER_MINPERIOD);
+        self.period_old = self.period_old.max(POSTFILTER_MINPERIOD);
+
+        // The first short block cross-fades from the parameters of two frames
+        // ago to the ones of the previous frame, the rest of the frame from
+        // the previous ones to the new ones.
+        Self::comb_filter(
+            buf,
+            offset,
+            SHORT_BLOCKSIZE,
+            (self.period_old, self.gains_old),
+            (self.period, self.gains),
+            window,
+        );
+
+        if frame_size > SHORT_BLOCKSIZE {
+            Self::comb_filter(
+                buf,
+                offset + SHORT_BLOCKSIZE,
+                frame_size - SHORT_BLOCKSIZE,
+                (self.period, self.gains),
+                (self.period_new, self.gains_new),
+                window,
+            );
+        }
+
+        self.period_old = self.period;
+        self.gains_old = self.gains;
+        self.period = self.period_new;
+        self.gains = self.gains_new;
+
+        if frame_size > SHORT_BLOCKSIZE {
+            self.period_old = self.period;
+            self.gains_old = self.gains;
+        }
+    }
+
+    fn comb_filter(
+        buf: &mut [f32],
+        offset: usize,
+        n: usize,
+        (period0, gains0): (usize, [f32; 3]),
+        (period1, gains1): (usize, [f32; 3]),
+        window: &[f32; OVERLAP],
+    ) {
+        if gains0[0] == 0.0 && gains1[0] == 0.0 {
+            return;
+        }
+
+        // When the gain is zero, the period is zero as well, it has to be at
+        // least the minimum to avoid processing garbage data.
+        let t0 = period0.max(POSTFILTER_MINPERIOD);
+        let t1 = period1.max(POSTFILTER_MINPERIOD);
+
+        let mut x1 = buf[offset - t1 + 1];
+        let mut x2 = buf[offset - t1];
+        let mut x3 = buf[offset - t1 - 1];
+        let mut x4 = buf[offset - t1 - 2];
+
+        // If the filter didn't change, the overlap is not needed.
+        let overlap = if gains0 == gains1 && t0 == t1 {
+            0
+        } else {
+            OVERLAP.min(n)
+        };
+
+        for (i, window) in window.iter().enumerate().take(overlap) {
+            let j = offset + i;
+            let x0 = buf[j - t1 + 2];
+            let f = window * window;
+
+            buf[j] = buf[j]
+                + (1.0 - f) * gains0[0] * buf[j - t0]
+                + (1.0 - f) * gains0[1] * (buf[j - t0 + 1] + buf[j - t0 - 1])
+                + (1.0 - f) * gains0[2] * (buf[j - t0 + 2] + buf[j - t0 - 2])
+                + f * gains1[0] * x2
+                + f * gains1[1] * (x1 + x3)
+                + f * gains1[2] * (x0 + x4);
+
+            x4 = x3;
+            x3 = x2;
+            x2 = x1;
+            x1 = x0;
+        }
+
+        if gains1[0] == 0.0 {
+            return;
+        }
+
+        // The rest of the samples use the constant new filter.
+        for i in overlap..n {
+            let j = offset + i;
+            let x0 = buf[j - t1 + 2];
+
+            buf[j] = buf[j] + gains1[0] * x2 + gains1[1] * (x1 + x3) + gains1[2] * (x0 + x4);
+
+            x4 = x3;
+            x3 = x2;
+            x2 = x1;
+            x1 = x0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mdct::Mdct;
+    use super::{PostFilter, SHORT_BLOCKSIZE, TAPS};
+
+    #[test]
+    fn comb_filter_impulse_response() {
+        let window = *Mdct::new().window();
+        let gains = TAPS[0].map(|g| g * 0.5);
+
+        // With constant parameters, each output sample feeds back with the
+        // taps centred one period later.
+        let mut buf = vec![0.0; 300 + 480];
+        buf[300] = 1.0;
+
+        let mut filter = PostFilter {
+            period_old: 100,
+            gains_old: gains,
+            period: 100,
+            gains,
+            period_new: 100,
+            gains_new: gains,
+        };
+
+        filter.process(&mut buf, 300, 480, &window);
+        let response = &buf[300..];
+        assert_eq!(response[0], 1.0);
+        assert!(response[1..98].iter().all(|&x| x == 0.0));
+        assert_eq!(
+            response[98..103],
+            [gains[2], gains[1], gains[0], gains[1], gains[2]]
+        );
+        assert!(response[103..196].iter().all(|&x| x == 0.0));
+
+        let second = gains[0] * gains[0] + 2.0 * gains[1] * gains[1] + 2.0 * gains[2] * gains[2];
+        assert!((response[200] - second).abs() < 1e-6);
+
+        // Switching the filter on fades the new taps in over the window.
+        let mut buf = vec![0.0; 300 + 480];
+        buf[300 + SHORT_BLOCKSIZE] = 1.0;
+
+        let mut filter = PostFilter {
+            period_new: 100,
+            gains_new: gains,
+            ..Default::default()
+        };
+
+        filter.process(&mut buf, 300, 480, &window);
+        let response = &buf[300 + SHORT_BLOCKSIZE..];
+        let f = window[100] * window[100];
+        assert!((response[100] - f * gains[0]).abs() < 1e-6);
+
+        // Past the window, the new filter applies alone.
+        let second = gains[0] * response[100]
+            + gains[1] * (response[99] + response[101])
+            + gains[2] * (response[98] + response[102]);
+        assert!((response[200] - second).abs() < 1e-6);
+        assert_eq!(filter.period, 100);
+        assert_eq!(filter.gains, gains);
+    }
 }
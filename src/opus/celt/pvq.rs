@@ -0,0 +1,276 @@
+//! CELT pyramid vector quantization (PVQ) decoding implementation
+//!
+//! The normalized shape of a band is coded as a vector of `n` integers whose
+//! absolute values sum up to `k` pulses. The vector is transmitted as an index
+//! into the set of all such vectors, ordered the way the combinatorial
+//! "CWRS" enumeration defines, and then scaled back to unit norm.
+
+use std::f64::consts::FRAC_PI_2;
+
+use crate::opus::entropy::{CeltRangeCoding, RangeCodingDecoder};
+
+use super::bit_alloc::Spread;
+
+/// PVQ decoder
+pub struct Pvq;
+
+impl Pvq {
+    /// Decodes the shape of a band with `k` pulses into `x`, scaled to `gain`
+    /// and spread according to `spread`.
+    ///
+    /// Returns the collapse mask of the band, one bit per block telling
+    /// whether the block received any pulse.
+    pub fn decode(
+        range_dec: &mut RangeCodingDecoder,
+        x: &mut [f32],
+        k: usize,
+        spread: Option<Spread>,
+        blocks: usize,
+        gain: f32,
+    ) -> u32 {
+        let mut y = vec![0i32; x.len()];
+        let energy = Self::decode_pulses(range_dec, &mut y, k);
+
+        // Normalize the residual to unit norm, then apply the gain.
+        let gain = 1.0 / energy.sqrt() * gain;
+        for (x, y) in x.iter_mut().zip(&y) {
+            *x = gain * *y as f32;
+        }
+
+        Self::exp_rotation(x, blocks, k, spread);
+
+        Self::collapse_mask(&y, blocks)
+    }
+
+    /// Undoes the spreading rotation applied by the encoder.
+    ///
+    /// A vector with few pulses compared to its size is very sparse, which
+    /// sounds tonal. The encoder rotates it by an angle that depends on the
+    /// number of pulses, spreading the energy over neighbouring coefficients.
+    fn exp_rotation(x: &mut [f32], blocks: usize, k: usize, spread: Option<Spread>) {
+        let len = x.len();
+        let factor = match spread {
+            _ if 2 * k >= len => return,
+            None => return,
+            Some(Spread::Light) => 15,
+            Some(Spread::Normal) => 10,
+            Some(Spread::Aggressive) => 5,
+        };
+
+        let gain = len as f32 / (len + factor * k) as f32;
+        let theta = 0.5 * (gain * gain);
+
+        let c = (FRAC_PI_2 * theta as f64).cos() as f32;
+        let s = (FRAC_PI_2 * (1.0 - theta) as f64).cos() as f32;
+
+        // A second rotation over a stride of about sqrt(len / blocks) spreads
+        // the energy further in large vectors.
+        let mut stride2 = 0;
+        if len >= 8 * blocks {
+            stride2 = 1;
+            while (stride2 * stride2 + stride2) * blocks + (blocks >> 2) < len {
+                stride2 += 1;
+            }
+        }
+
+        for x in x.chunks_exact_mut(len / blocks) {
+            if stride2 != 0 {
+                Self::exp_rotation1(x, stride2, s, c);
+            }
+
+            Self::exp_rotation1(x, 1, c, s);
+        }
+    }
+
+    fn exp_rotation1(x: &mut [f32], stride: usize, c: f32, s: f32) {
+        let len = x.len();
+        for i in 0..len - stride {
+            let (x1, x2) = (x[i], x[i + stride]);
+            x[i + stride] = c * x2 + s * x1;
+            x[i] = c * x1 - s * x2;
+        }
+
+        for i in (0..len.saturating_sub(2 * stride)).rev() {
+            let (x1, x2) = (x[i], x[i + stride]);
+            x[i + stride] = c * x2 + s * x1;
+            x[i] = c * x1 - s * x2;
+        }
+    }
+
+    /// Decodes the pulse vector `y` with `k` pulses and returns its energy.
+    pub fn decode_pulses(range_dec: &mut RangeCodingDecoder, y: &mut [i32], k: usize) -> f32 {
+        let mut u = vec![0u32; k + 2];
+        let total = Self::ncwrs_urow(y.len(), k, &mut u);
+        let index = range_dec.uniform(total as usize) as u32;
+
+        Self::cwrsi(k, index, y, &mut u)
+    }
+
+    fn collapse_mask(y: &[i32], blocks: usize) -> u32 {
+        if blocks <= 1 {
+            return 1;
+        }
+
+        y.chunks(y.len() / blocks)
+            .take(blocks)
+            .enumerate()
+            .fold(0, |mask, (i, block)| {
+                mask | ((block.iter().any(|v| *v != 0) as u32) << i)
+            })
+    }
+
+    /// Computes the next row of a recurrence of the form
+    /// `u[i][j] = u[i - 1][j] + u[i][j - 1] + u[i - 1][j - 1]`, `u0` being the
+    /// first value of the new row.
+    fn unext(u: &mut [u32], mut u0: u32) {
+        for j in 1..u.len() {
+            let u1 = u[j].wrapping_add(u[j - 1]).wrapping_add(u0);
+            u[j - 1] = u0;
+            u0 = u1;
+        }
+
+        let last = u.len() - 1;
+        u[last] = u0;
+    }
+
+    /// Computes the previous row of the same recurrence as [`Self::unext`].
+    fn uprev(u: &mut [u32], mut u0: u32) {
+        for j in 1..u.len() {
+            let u1 = u[j].wrapping_sub(u[j - 1]).wrapping_sub(u0);
+            u[j - 1] = u0;
+            u0 = u1;
+        }
+
+        let last = u.len() - 1;
+        u[last] = u0;
+    }
+
+    /// Computes `V(n, k)`, the number of pulse vectors, and fills `u` with the
+    /// row `U(n, 0..k + 2)`.
+    fn ncwrs_urow(n: usize, k: usize, u: &mut [u32]) -> u32 {
+        u[0] = 0;
+        u[1] = 1;
+        for (i, u) in u.iter_mut().enumerate().skip(2) {
+            *u = ((i as u32) << 1) - 1;
+        }
+
+        for _ in 2..n {
+            Self::unext(&mut u[1..], 1);
+        }
+
+        u[k].wrapping_add(u[k + 1])
+    }
+
+    /// Returns the energy of the `index`'th pulse vector, written into `y`.
+    /// `u` must hold the row `U(n, 0..k + 2)` and is destroyed.
+    fn cwrsi(mut k: usize, mut index: u32, y: &mut [i32], u: &mut [u32]) -> f32 {
+        let mut energy = 0.0;
+        for y in y.iter_mut() {
+            // The upper half of the codebook has a negative sign
+            let p = u[k + 1];
+            let sign = if index >= p {
+                index -= p;
+                -1
+            } else {
+                0
+            };
+
+            let pulses = k;
+            let mut p = u[k];
+            while p > index {
+                k -= 1;
+                p = u[k];
+            }
+
+            index -= p;
+
+            let value = ((pulses - k) as i32 + sign) ^ sign;
+            *y = value;
+            energy += (value * value) as f32;
+
+            Self::uprev(&mut u[..k + 2], 0);
+        }
+
+        energy
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opus::entropy::RangeCodingDecoder;
+
+    use super::{Pvq, Spread};
+
+    #[test]
+    fn decode_pulses() {
+        // The pulse vectors below, coded in turn by their CWRS index.
+        let buf = [205, 85, 172, 110, 22, 0, 0, 0, 0, 0, 0, 0, 4, 63, 205, 146];
+
+        let mut rd = RangeCodingDecoder::new(&buf);
+        let mut decode = |n: usize, k: usize| {
+            let mut y = vec![0; n];
+            let energy = Pvq::decode_pulses(&mut rd, &mut y, k);
+            (y, energy)
+        };
+
+        assert_eq!(decode(2, 1), (vec![-1, 0], 1.0));
+        assert_eq!(decode(3, 5), (vec![2, -2, 1], 9.0));
+        assert_eq!(decode(8, 3), (vec![0, 1, 0, 0, -1, 0, 0, 1], 3.0));
+        assert_eq!(
+            decode(16, 10),
+            (
+                vec![0, 0, 3, -1, 0, 0, 0, 2, 0, 0, -1, 0, 0, 1, 0, -2],
+                20.0
+            )
+        );
+        assert_eq!(decode(4, 32), (vec![7, -20, 0, 5], 474.0));
+        assert_eq!(decode(6, 2), (vec![0, 0, -2, 0, 0, 0], 4.0));
+        assert_eq!(rd.tell(), 71);
+    }
+
+    #[test]
+    fn decode_shape() {
+        // The pulses [0, 0, 1, 0, 0, 0, -2, 0].
+        let buf = [86, 128, 0, 3];
+        let mut rd = RangeCodingDecoder::new(&buf);
+        let mut x = [0.0; 8];
+
+        // Unit norm without spreading, the second and fourth of the four
+        // blocks have pulses.
+        assert_eq!(Pvq::decode(&mut rd, &mut x, 3, None, 4, 1.0), 0b1010);
+        assert_eq!(x, [0.0, 0.0, 0.4472136, 0.0, 0.0, 0.0, -0.8944272, 0.0]);
+    }
+
+    #[test]
+    fn spreading_rotation() {
+        let impulse = |len| {
+            let mut x = vec![0.0; len];
+            x[3] = 1.0;
+            x
+        };
+
+        // Vectors with many pulses and unspread ones are left alone.
+        for (k, spread) in [(4, Some(Spread::Normal)), (1, None)] {
+            let mut x = impulse(8);
+            Pvq::exp_rotation(&mut x, 1, k, spread);
+            assert_eq!(x, impulse(8));
+        }
+
+        // The rotation keeps the norm and spreads the pulse over its block,
+        // the more aggressive the spreading the lower the peak left.
+        let mut peaks = Vec::new();
+        for spread in [Spread::Light, Spread::Normal, Spread::Aggressive] {
+            let mut x = impulse(16);
+            Pvq::exp_rotation(&mut x, 2, 1, Some(spread));
+
+            let norm = x.iter().map(|x| x * x).sum::<f32>();
+            assert!((norm - 1.0).abs() < 1e-5);
+            assert!(x[..8].iter().filter(|&&x| x != 0.0).count() > 2);
+            assert!(x[8..].iter().all(|&x| x == 0.0));
+
+            peaks.push(x.iter().fold(0f32, |peak, x| peak.max(x.abs())));
+        }
+
+        assert!(peaks[0] < 1.0 && peaks[0] > peaks[1] && peaks[1] > peaks[2]);
+    }
+}
@@ -46,7 +46,7 @@ impl TimeFrequencyChange {
         let mut change = false;
         // Iterate through all bands to decode the time-frequency transform flag
         for i in dec.band_range.clone() {
-            if range_dec.available() > bits + select_bit as usize {
+            if range_dec.available() >= bits + select_bit as usize {
                 diff ^= range_dec.logp(bits);
                 change |= diff;
             }
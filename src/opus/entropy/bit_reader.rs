@@ -41,7 +41,7 @@ impl<'a> BigEndianBitReader<'a> {
             // within an internal cache.
 
             if self.readable() {
-                self.cache = self.cache | self.read::<u32>() << (32 - self.left);
+                self.cache |= self.read::<u32>() << (32 - self.left);
                 self.index += 4;
                 self.left += 32;
             }
@@ -62,6 +62,18 @@ impl<'a> BigEndianBitReader<'a> {
         }) as u32
     }
 
+    /// Drops the bytes past `len`, they are then read as zeros like the end
+    /// of the buffer.
+    pub fn truncate(&mut self, len: usize) {
+        // The first bit of the cache is at this position in the buffer, only
+        // the bits before `len` stay in it.
+        let position = self.index * 8 - self.left;
+        let valid = (len * 8).saturating_sub(position).min(self.left);
+        self.cache &= !u64::MAX.checked_shr(valid as u32).unwrap_or(0);
+
+        self.bytes = &self.bytes[..len.min(self.bytes.len())];
+    }
+
     /// Tells if it is still possible to read bits from an internal buffer.
     fn readable(&self) -> bool {
         self.index < self.bytes.len()
@@ -124,7 +136,7 @@ impl<'a> LittleEndianBitReader<'a> {
             // within an internal cache.
 
             if self.readable() {
-                self.cache = self.cache | self.read::<u32>() << self.left;
+                self.cache |= self.read::<u32>() << self.left;
                 self.index += 4;
                 self.left += 32;
             }
@@ -251,4 +263,18 @@ mod test {
         assert_eq!(r.get_bits_32(8), 0b11110000);
         assert_eq!(r.get_bits_32(8), 0b00000001);
     }
+
+    #[test]
+    fn truncated_bitread() {
+        let buf = &[0xD6, 0x2D, 0xE3, 0x55, 0xAA, 0x1C, 0xF0, 0x01, 0xFF, 0xFF];
+
+        let mut r = BigEndianBitReader::new(buf);
+
+        // The bytes past the new end are read as zeros, cached or not.
+        assert_eq!(r.get_bits_32(12), 0xD62);
+        r.truncate(3);
+        assert_eq!(r.get_bits_32(8), 0xDE);
+        assert_eq!(r.get_bits_32(8), 0x30);
+        assert_eq!(r.get_bits_32(16), 0);
+    }
 }
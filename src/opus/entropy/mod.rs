@@ -11,6 +11,8 @@ use self::bit_reader::{BigEndianBitReader, LittleEndianBitReader};
 /// A simplified version of Range Decoder for decoding the range encoding format
 /// used by Opus/CELT.
 pub struct RangeCodingDecoder<'a> {
+    // Input data, the raw bits are read from its end.
+    bytes: &'a [u8],
     // Used to read forward bitstreams.
     forward_reader: BigEndianBitReader<'a>,
     // For reading reverse bitstreams (rawbits use)
@@ -99,6 +101,7 @@ impl<'a> RangeCodingDecoder<'a> {
         let coded_value = 127 - forward_reader.get_bits_32(7) as usize;
 
         let mut this = Self {
+            bytes,
             reverse_reader: LittleEndianBitReader::new(bytes),
             consumed_bits: Self::SYMBOL_BITS + 1,
             bitstream_length: bytes.len() * 8,
@@ -159,7 +162,7 @@ impl<'a> RangeCodingDecoder<'a> {
 
     /// Returns the number of bits currently consumed (with decimal precision)
     pub fn tell_frac(&self) -> usize {
-        let mut log2_range = self.current_range.ilog2() as usize - 1;
+        let mut log2_range = self.current_range.ilog2() as usize + 1;
         let mut range_q15 = self.current_range >> (log2_range - 16);
 
         // Newton's iterative approximation log2 accuracy (3 times)
@@ -167,23 +170,48 @@ impl<'a> RangeCodingDecoder<'a> {
             range_q15 = (range_q15 * range_q15) >> 15;
 
             let lastbit = range_q15 >> 16;
-            log2_range = log2_range * 2 | lastbit;
+            log2_range = (log2_range * 2) | lastbit;
             range_q15 >>= lastbit;
         }
 
         self.consumed_bits * 8 - log2_range
     }
 
+    /// Returns the current range, which is a pseudo-random value once the
+    /// frame is decoded, used to seed the noise of the next one.
+    pub fn range(&self) -> u32 {
+        self.current_range as u32
+    }
+
+    /// Drops the last `len` bytes of the input, which belong to another
+    /// frame, the raw bits are then read from the new end.
+    ///
+    /// No raw bits must have been read yet.
+    pub fn shrink(&mut self, len: usize) {
+        let bytes = &self.bytes[..self.bytes.len() - len];
+
+        self.forward_reader.truncate(bytes.len());
+        self.reverse_reader = LittleEndianBitReader::new(bytes);
+        self.bitstream_length = bytes.len() * 8;
+        self.bytes = bytes;
+    }
+
     pub fn len(&self) -> usize {
         self.bitstream_length
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.bitstream_length == 0
+    }
+
+    /// Returns the number of bits left, none once a corrupted frame has read
+    /// past the end of the data.
     pub fn available(&self) -> usize {
-        self.bitstream_length - self.tell()
+        self.bitstream_length.saturating_sub(self.tell())
     }
 
     pub fn available_frac(&self) -> usize {
-        self.bitstream_length * 8 - self.tell_frac()
+        (self.bitstream_length * 8).saturating_sub(self.tell_frac())
     }
 }
 
@@ -207,7 +235,7 @@ impl<'a> CeltRangeCoding for RangeCodingDecoder<'a> {
 
     /// Decode uniformly distributed integer values (0 to len-1)
     fn uniform(&mut self, len: usize) -> usize {
-        let bits = ((len - 1).ilog2() - 1) as usize;
+        let bits = ((len - 1).ilog2() + 1) as usize;
 
         // If bits exceeds UNI_BITS, narrow the denominator first and then add
         // rawbits; otherwise, decode directly.
@@ -222,8 +250,10 @@ impl<'a> CeltRangeCoding for RangeCodingDecoder<'a> {
         self.update_range_and_value(range_scale, symbol_idx, symbol_idx + 1, total);
 
         if bits > Self::UNIFORM_THRESHOLD_BITS {
-            symbol_idx << (bits - Self::UNIFORM_THRESHOLD_BITS)
-                | self.rawbits(bits - Self::UNIFORM_THRESHOLD_BITS)
+            // A corrupted stream can point past the end of the range.
+            (symbol_idx << (bits - Self::UNIFORM_THRESHOLD_BITS)
+                | self.rawbits(bits - Self::UNIFORM_THRESHOLD_BITS))
+            .min(len - 1)
         } else {
             symbol_idx
         }
@@ -243,7 +273,7 @@ impl<'a> CeltRangeCoding for RangeCodingDecoder<'a> {
         let (value, low) = if center >= symbol {
             let mut value = 1;
             let mut low = symbol;
-            symbol = 1 + ((32768 - 32 - symbol) * (16384 - decay as usize) >> 15);
+            symbol = 1 + (((32768 - 32 - symbol) * (16384 - decay as usize)) >> 15);
 
             while symbol > 1 && center >= low + 2 * symbol {
                 value += 1;
@@ -293,16 +323,16 @@ impl<'a> CeltRangeCoding for RangeCodingDecoder<'a> {
         } else {
             // Here the derivation of k corresponds to the CELT coding end
             // compensation interval.
-            symbol - (k0 + 1) / 2
+            symbol - (k0 + 1) * 2
         };
 
         if k <= k0 {
-            self.update_range_and_value(range_scale, 3 * (k + 0), 3 * (k + 1), total);
+            self.update_range_and_value(range_scale, 3 * k, 3 * (k + 1), total);
         } else {
             self.update_range_and_value(
                 range_scale,
-                3 * (k + 1) + (k - 1 - k0),
-                3 * (k0 + 1) + (k - 0 - k0),
+                3 * (k0 + 1) + (k - 1 - k0),
+                3 * (k0 + 1) + (k - k0),
                 total,
             );
         }
@@ -321,16 +351,16 @@ impl<'a> CeltRangeCoding for RangeCodingDecoder<'a> {
         let center = total - (self.coded_value / range_scale + 1).min(total);
 
         // Upper Triangle vs Lower Triangle distribution judgment.
-        let (k, low, symbol) = if center < total >> 1 {
+        let (k, low, symbol) = if center < (half_level * (half_level + 1)) >> 1 {
             // Bottom half: incremental fill from top left (top left to bottom right)
             let k = ((8 * center + 1).integer_sqrt() - 1) >> 1;
 
-            (k, k * (k + 1) >> 1, k + 1)
+            (k, (k * (k + 1)) >> 1, k + 1)
         } else {
             // Top: Push back from the bottom right corner to the top left corner.
             let k = (2 * (qn + 1) - (8 * (total - center - 1) + 1).integer_sqrt()) >> 1;
 
-            (k, total - ((qn + 1 - k) * (qn + 2 - k) >> 1), qn + 1 - k)
+            (k, total - (((qn + 1 - k) * (qn + 2 - k)) >> 1), qn + 1 - k)
         };
 
         self.update_range_and_value(range_scale, low, low + symbol, total);
@@ -341,7 +371,7 @@ impl<'a> CeltRangeCoding for RangeCodingDecoder<'a> {
     /// Advances the decoder to the end of the data, patching unused bits
     /// (CELT is usually called)
     fn to_end(&mut self) {
-        self.consumed_bits += self.bitstream_length - self.tell();
+        self.consumed_bits += self.available();
     }
 }
 
@@ -472,4 +502,102 @@ mod test {
             assert_eq!(ret, rd.laplace(symbol, decay));
         });
     }
+
+    #[test]
+    fn decode_step() {
+        let buf = [
+            10, 161, 6, 190, 143, 211, 253, 114, 47, 34, 205, 162, 160, 248, 166, 85, 99, 56,
+        ];
+
+        let mut rd = RangeCodingDecoder::new(&buf);
+
+        // Symbols coded with the stereo angle pdf, as (value, k0).
+        let val = [
+            (0, 14),
+            (16, 13),
+            (22, 13),
+            (1, 13),
+            (0, 2),
+            (13, 10),
+            (2, 4),
+            (10, 11),
+            (12, 8),
+            (4, 10),
+            (1, 13),
+            (8, 8),
+            (10, 6),
+            (19, 14),
+            (14, 16),
+            (2, 5),
+            (23, 14),
+            (8, 6),
+            (19, 12),
+            (9, 5),
+            (16, 11),
+            (8, 4),
+            (20, 16),
+            (21, 16),
+            (1, 2),
+            (0, 13),
+            (0, 1),
+            (8, 7),
+            (22, 13),
+            (8, 14),
+            (3, 2),
+            (1, 6),
+        ];
+
+        val.iter().for_each(|&(ret, k0)| {
+            assert_eq!(ret, rd.step(k0));
+        });
+    }
+
+    #[test]
+    fn decode_triangular() {
+        let buf = [
+            0, 223, 164, 44, 129, 131, 173, 103, 23, 146, 52, 55, 9, 146, 181, 23, 241, 0,
+        ];
+
+        let mut rd = RangeCodingDecoder::new(&buf);
+
+        // Symbols coded with the triangular pdf, as (value, qn).
+        let val = [
+            (0, 28),
+            (13, 20),
+            (16, 16),
+            (1, 12),
+            (1, 22),
+            (15, 20),
+            (4, 24),
+            (10, 10),
+            (3, 10),
+            (10, 24),
+            (1, 2),
+            (4, 12),
+            (20, 28),
+            (7, 8),
+            (4, 26),
+            (7, 30),
+            (6, 16),
+            (17, 26),
+            (3, 18),
+            (21, 26),
+            (15, 18),
+            (1, 2),
+            (3, 20),
+            (0, 14),
+            (3, 6),
+            (0, 2),
+            (6, 10),
+            (2, 6),
+            (20, 20),
+            (1, 14),
+            (11, 16),
+            (2, 10),
+        ];
+
+        val.iter().for_each(|&(ret, qn)| {
+            assert_eq!(ret, rd.triangular(qn));
+        });
+    }
 }
@@ -1,21 +1,28 @@
 pub mod celt;
 pub mod entropy;
+pub mod silk;
 pub mod toc;
 
 use bytes::Buf;
 
 use self::{
-    celt::{CeltFrameDecodeError, CeltFrameDecoder},
-    entropy::RangeCodingDecoder,
-    toc::{EncodeMode, FrameCode, TableOfContents},
+    celt::{CeltFrameDecodeError, CeltFrameDecoder, OVERLAP},
+    entropy::{CeltRangeCoding, RangeCodingDecoder},
+    silk::{SilkFrameDecodeError, SilkFrameDecoder},
+    toc::{Channels, EncodeMode, FrameCode, FrameDuration, TableOfContents},
 };
 
-#[derive(Debug)]
-pub struct OpusFrame {}
+#[derive(Debug, Default)]
+pub struct OpusFrame {
+    /// Decoded samples at the output rate of the decoder, one buffer per
+    /// output channel.
+    pub samples: Vec<Vec<f32>>,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpusFrameDecoderError {
     Celt(CeltFrameDecodeError),
+    Silk(SilkFrameDecodeError),
 }
 
 impl From<CeltFrameDecodeError> for OpusFrameDecoderError {
@@ -24,33 +31,325 @@ impl From<CeltFrameDecodeError> for OpusFrameDecoderError {
     }
 }
 
+impl From<SilkFrameDecodeError> for OpusFrameDecoderError {
+    fn from(value: SilkFrameDecodeError) -> Self {
+        Self::Silk(value)
+    }
+}
+
 impl OpusFrame {
-    pub fn deocde(toc: &TableOfContents, bytes: &[u8]) -> Result<Self, OpusFrameDecoderError> {
+    pub fn deocde(
+        dec: &mut OpusDecoder,
+        toc: &TableOfContents,
+        bytes: &[u8],
+    ) -> Result<Self, OpusFrameDecoderError> {
+        // A frame of at most one byte carries no audio (DTX), it is concealed
+        // like a lost one.
+        if bytes.len() <= 1 {
+            let samples = Self::conceal(dec, toc);
+            return Ok(Self { samples });
+        }
+
         let mut range_dec = RangeCodingDecoder::new(bytes);
 
-        let consumed = range_dec.tell();
-        let has_redundancy = if toc.mode == EncodeMode::Hybrid && consumed + 37 <= bytes.len() * 8 {
-            range_dec.logp(12)
-        } else if toc.mode == EncodeMode::SILK && consumed + 17 <= bytes.len() * 8 {
-            true
-        } else {
-            false
+        // The SILK layer comes first in the bitstream, the redundancy flag
+        // follows it.
+        let mut samples = vec![Vec::new(); toc.channels as usize];
+        if toc.mode != EncodeMode::CELT {
+            // The SILK state is stale after CELT-only frames.
+            if dec.prev_mode == Some(EncodeMode::CELT) {
+                dec.silk.reset();
+            }
+
+            dec.silk.decode(toc, &mut range_dec, &mut samples)?;
+        }
+
+        // A SILK or Hybrid frame next to a CELT one may carry a 5 ms CELT
+        // frame at its end, covering the switch between both modes. It is
+        // only coded if enough bits are left, behind a flag in Hybrid mode.
+        let mut redundancy = None;
+        let mut len = bytes.len();
+        let has_redundancy = match toc.mode {
+            EncodeMode::Hybrid => range_dec.tell() + 37 <= len * 8 && range_dec.logp(12),
+            EncodeMode::SILK => range_dec.tell() + 17 <= len * 8,
+            EncodeMode::CELT => false,
         };
 
         if has_redundancy {
-            todo!("skip redundancy info");
+            // Whether the redundant frame precedes this one (CELT to SILK) or
+            // follows it (SILK to CELT).
+            let celt_to_silk = range_dec.logp(1);
+            let size = if toc.mode == EncodeMode::Hybrid {
+                range_dec.uniform(256) + 2
+            } else {
+                len - range_dec.tell().div_ceil(8)
+            };
+
+            // The redundant frame takes the end of the data, a frame too short
+            // for it is invalid and decoded without.
+            if size <= len && (len - size) * 8 >= range_dec.tell() {
+                len -= size;
+                range_dec.shrink(size);
+                redundancy = Some((celt_to_silk, &bytes[len..]));
+            }
+        }
+
+        // Without a redundant frame, a switch between CELT and SILK fades in
+        // from the concealment of the previous mode over 5 ms, the SILK to
+        // CELT one has its redundant frame in the previous packet.
+        let downsample = dec.downsample();
+        let f2_5 = FrameDuration::VeryShort as usize / downsample;
+        let f5 = FrameDuration::Short as usize / downsample;
+        let transition_toc = TableOfContents {
+            duration: if toc.duration == FrameDuration::VeryShort {
+                FrameDuration::VeryShort
+            } else {
+                FrameDuration::Short
+            },
+            ..*toc
+        };
+
+        let transition = match dec.prev_mode {
+            Some(EncodeMode::CELT) => toc.mode != EncodeMode::CELT && redundancy.is_none(),
+            Some(_) => toc.mode == EncodeMode::CELT && !dec.prev_redundancy,
+            None => false,
+        }
+        .then(|| Self::conceal(dec, &transition_toc));
+
+        let redundant_toc = TableOfContents {
+            mode: EncodeMode::CELT,
+            duration: FrameDuration::Short,
+            ..*toc
+        };
+
+        // A CELT to SILK redundant frame right after a SILK frame is left out,
+        // the CELT frames it covered were lost and concealed as SILK.
+        let celt_to_silk = matches!(redundancy, Some((true, _)))
+            && (dec.prev_mode != Some(EncodeMode::SILK) || dec.prev_redundancy);
+
+        let mut redundant = vec![Vec::new(); toc.channels as usize];
+        if let Some((true, data)) = redundancy
+            && celt_to_silk
+        {
+            dec.celt.decode(
+                &redundant_toc,
+                &mut RangeCodingDecoder::new(data),
+                &mut redundant,
+            )?;
+        }
+
+        // The CELT state does not carry over a mode switch, unless the switch
+        // was already covered by a redundant frame.
+        if toc.mode != EncodeMode::SILK
+            && dec.prev_mode.is_some_and(|mode| mode != toc.mode)
+            && !dec.prev_redundancy
+        {
+            dec.celt.reset();
+        }
+
+        match toc.mode {
+            EncodeMode::CELT => dec.celt.decode(toc, &mut range_dec, &mut samples)?,
+            // The CELT layer carries the bands above the SILK wideband and
+            // picks up the range decoder where SILK left it, both layers are
+            // summed.
+            EncodeMode::Hybrid => {
+                let mut celt = vec![Vec::new(); toc.channels as usize];
+                dec.celt.decode(toc, &mut range_dec, &mut celt)?;
+
+                for (samples, celt) in samples.iter_mut().zip(celt) {
+                    for (sample, x) in samples.iter_mut().zip(celt) {
+                        *sample += x;
+                    }
+                }
+            }
+            // On a Hybrid to SILK switch, the CELT layer fades out through
+            // the MDCT overlap of a silence frame.
+            EncodeMode::SILK => {
+                if dec.prev_mode == Some(EncodeMode::Hybrid)
+                    && !(matches!(redundancy, Some((true, _))) && dec.prev_redundancy)
+                {
+                    let silence_toc = TableOfContents {
+                        duration: FrameDuration::VeryShort,
+                        ..redundant_toc
+                    };
+
+                    let mut celt = vec![Vec::new(); toc.channels as usize];
+                    dec.celt.decode(
+                        &silence_toc,
+                        &mut RangeCodingDecoder::new(&[0xFF, 0xFF]),
+                        &mut celt,
+                    )?;
+
+                    for (samples, celt) in samples.iter_mut().zip(celt) {
+                        for (sample, x) in samples.iter_mut().zip(celt) {
+                            *sample += x;
+                        }
+                    }
+                }
+            }
+        }
+
+        // The redundant frame is cross-faded with the first or the last 2.5 ms
+        // of the frame, a SILK to CELT one follows the SILK frame with a fresh
+        // CELT state.
+        match redundancy {
+            Some((true, _)) if celt_to_silk => {
+                for (samples, redundant) in samples.iter_mut().zip(&redundant) {
+                    samples[..f2_5].copy_from_slice(&redundant[..f2_5]);
+                    smooth_fade(
+                        &redundant[f2_5..2 * f2_5],
+                        &mut samples[f2_5..2 * f2_5],
+                        dec.celt.window(),
+                        downsample,
+                    );
+                }
+            }
+            Some((false, data)) => {
+                dec.celt.reset();
+                dec.celt.decode(
+                    &redundant_toc,
+                    &mut RangeCodingDecoder::new(data),
+                    &mut redundant,
+                )?;
+
+                for (samples, redundant) in samples.iter_mut().zip(&redundant) {
+                    let tail = samples.len() - f2_5;
+                    let mut faded = redundant[f2_5..2 * f2_5].to_vec();
+                    smooth_fade(&samples[tail..], &mut faded, dec.celt.window(), downsample);
+                    samples[tail..].copy_from_slice(&faded);
+                }
+            }
+            _ => (),
+        }
+
+        map_channels(&mut samples, toc.channels, dec.channels);
+
+        // The concealment is cross-faded with the start of the frame, a 2.5 ms
+        // frame is too short to keep any of it as is.
+        if let Some(transition) = transition {
+            for (samples, transition) in samples.iter_mut().zip(&transition) {
+                if samples.len() >= f5 {
+                    samples[..f2_5].copy_from_slice(&transition[..f2_5]);
+                    smooth_fade(
+                        &transition[f2_5..f5],
+                        &mut samples[f2_5..f5],
+                        dec.celt.window(),
+                        downsample,
+                    );
+                } else {
+                    smooth_fade(
+                        &transition[..f2_5],
+                        &mut samples[..f2_5],
+                        dec.celt.window(),
+                        downsample,
+                    );
+                }
+            }
         }
 
-        if toc.mode == EncodeMode::CELT {
-            let mut dec = CeltFrameDecoder::default();
-            dec.decode(toc, &mut range_dec)?;
+        dec.prev_mode = Some(toc.mode);
+        dec.prev_redundancy = matches!(redundancy, Some((false, _)));
+
+        Ok(Self { samples })
+    }
 
-            println!("{:?}", dec);
+    /// Conceals a missing frame of the duration and channels of `toc` from
+    /// the state of the previous frames, in the mode of the previous frame.
+    /// Returns one buffer per output channel of the decoder.
+    ///
+    /// A frame ending with a redundant SILK to CELT frame has already
+    /// switched to CELT, the concealment goes on in CELT mode.
+    fn conceal(dec: &mut OpusDecoder, toc: &TableOfContents) -> Vec<Vec<f32>> {
+        let len = toc.duration as usize / dec.downsample();
+        let Some(mode) = dec.prev_mode else {
+            return vec![vec![0.0; len]; dec.channels as usize];
+        };
+
+        let mode = if dec.prev_redundancy {
+            EncodeMode::CELT
         } else {
-            todo!("Only CELT is supported");
+            mode
+        };
+
+        // Frames longer than 20 ms are concealed 20 ms at a time.
+        const F20: usize = FrameDuration::Standard as usize;
+        if toc.duration as usize > F20 {
+            let count = toc.duration as usize / F20;
+            let toc = TableOfContents {
+                duration: FrameDuration::Standard,
+                ..*toc
+            };
+
+            let mut samples = vec![Vec::new(); dec.channels as usize];
+            for _ in 0..count {
+                for (samples, frame) in samples.iter_mut().zip(Self::conceal(dec, &toc)) {
+                    samples.extend(frame);
+                }
+            }
+
+            return samples;
         }
 
-        Ok(Self {})
+        let toc = TableOfContents { mode, ..*toc };
+        let mut samples = vec![vec![0.0; len]; toc.channels as usize];
+
+        // The SILK concealment of a frame shorter than 10 ms is cut down to
+        // it.
+        if mode != EncodeMode::CELT {
+            let mut silk = vec![Vec::new(); toc.channels as usize];
+            dec.silk.conceal(toc.duration, &mut silk);
+
+            for (samples, silk) in samples.iter_mut().zip(silk) {
+                for (sample, x) in samples.iter_mut().zip(silk) {
+                    *sample += x;
+                }
+            }
+        }
+
+        if mode != EncodeMode::SILK {
+            let mut celt = vec![Vec::new(); toc.channels as usize];
+            dec.celt.conceal(&toc, &mut celt);
+
+            for (samples, celt) in samples.iter_mut().zip(celt) {
+                for (sample, x) in samples.iter_mut().zip(celt) {
+                    *sample += x;
+                }
+            }
+        }
+
+        map_channels(&mut samples, toc.channels, dec.channels);
+
+        dec.prev_mode = Some(mode);
+        dec.prev_redundancy = false;
+
+        samples
+    }
+}
+
+/// Maps the coded channels onto the output channels of the decoder, a mono
+/// stream is copied to both sides and a stereo stream is averaged down to
+/// mono.
+fn map_channels(samples: &mut Vec<Vec<f32>>, coded: Channels, output: Channels) {
+    match (coded, output) {
+        (Channels::Mono, Channels::Stereo) => {
+            samples.push(samples[0].clone());
+        }
+        (Channels::Stereo, Channels::Mono) => {
+            let right = samples.pop().unwrap_or_default();
+            for (left, right) in samples[0].iter_mut().zip(right) {
+                *left = (*left + right) * 0.5;
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Cross-fades from `from` into `to`, in place, the weight of `to` being the
+/// squared overlap window, taken every `inc` samples below 48 kHz.
+fn smooth_fade(from: &[f32], to: &mut [f32], window: &[f32; OVERLAP], inc: usize) {
+    for ((to, from), w) in to.iter_mut().zip(from).zip(window.iter().step_by(inc)) {
+        let w = w * w;
+        *to = w * *to + (1.0 - w) * from;
     }
 }
 
@@ -77,8 +376,26 @@ impl OpusPacket {
     const MAX_FRAME_LEN: usize = 1275;
     const MAX_FRAMES: usize = 48;
 
-    pub fn decode(mut bytes: &[u8]) -> Result<Self, OpusPacketDecodeError> {
-        if bytes.len() < 1 {
+    /// Decodes a standalone packet with a fresh decoder state.
+    ///
+    /// Both CELT and SILK predict from the previous frame, so only the frames
+    /// within this packet share state. A stream of packets should be decoded
+    /// with [`OpusDecoder`] instead.
+    pub fn decode(bytes: &[u8]) -> Result<Self, OpusPacketDecodeError> {
+        let (toc, datas) = Self::parse(bytes)?;
+
+        let mut dec = OpusDecoder::with_channels(48000, toc.channels);
+        let mut frames = Vec::with_capacity(datas.len());
+        for data in datas {
+            frames.push(OpusFrame::deocde(&mut dec, &toc, data)?);
+        }
+
+        Ok(Self { toc, frames })
+    }
+
+    /// Splits a packet into its TOC and the bytes of each frame.
+    fn parse(mut bytes: &[u8]) -> Result<(TableOfContents, Vec<&[u8]>), OpusPacketDecodeError> {
+        if bytes.is_empty() {
             return Err(OpusPacketDecodeError::InvalidData);
         }
 
@@ -89,14 +406,14 @@ impl OpusPacket {
             // A packet contains only one frame of audio.
             FrameCode::Single => {
                 if bytes.len() <= Self::MAX_FRAME_LEN {
-                    datas.push(&bytes[..]);
+                    datas.push(bytes);
                 }
 
                 bytes.advance(bytes.len());
             }
             // A package contains two frames of the same size.
             FrameCode::DoubleCBR => {
-                if bytes.len() & 1 != 1 {
+                if bytes.len() & 1 != 0 {
                     return Err(OpusPacketDecodeError::InvalidData);
                 }
 
@@ -114,17 +431,15 @@ impl OpusPacket {
             FrameCode::DoubleVBR => {
                 // The 1 ~ 2 bytes after the TOC byte are the number of bytes in
                 // the first frame.
-                let len = read_variable_length(&mut bytes);
-                if len > Self::MAX_FRAME_LEN {
+                let len = read_variable_length(&mut bytes)?;
+                if len > Self::MAX_FRAME_LEN || len > bytes.len() {
                     return Err(OpusPacketDecodeError::InvalidData);
                 }
 
-                if len > 0 {
-                    datas.push(&bytes[..len]);
-                    datas.push(&bytes[len..]);
+                datas.push(&bytes[..len]);
+                datas.push(&bytes[len..]);
 
-                    bytes.advance(bytes.len());
-                }
+                bytes.advance(bytes.len());
             }
             // A packet contains any number of frames.
             FrameCode::Multiple => {
@@ -142,10 +457,14 @@ impl OpusPacket {
                 // - v equals 0 for CBR and 1 for VBR.
                 // - p equals 1 for packet containing padding bytes.
                 // - M indicates the number of frames contained in the packet.
+                if bytes.is_empty() {
+                    return Err(OpusPacketDecodeError::InvalidData);
+                }
+
                 let flag = bytes.get_u8();
                 let is_vbr = (flag & 0x80) != 0;
                 let frame_count = (flag & 0x3F) as usize;
-                let has_padding = (flag & 0x40) == 1;
+                let has_padding = (flag & 0x40) != 0;
 
                 if frame_count == 0 || frame_count > Self::MAX_FRAMES {
                     return Err(OpusPacketDecodeError::FramesOverflow);
@@ -155,6 +474,10 @@ impl OpusPacket {
                     let mut padding_len = 0;
 
                     loop {
+                        if bytes.is_empty() {
+                            return Err(OpusPacketDecodeError::InvalidData);
+                        }
+
                         let byte = bytes.get_u8() as usize;
                         if byte > u32::MAX as usize - 255 {
                             return Err(OpusPacketDecodeError::InvalidData);
@@ -168,34 +491,42 @@ impl OpusPacket {
                         }
                     }
 
+                    if padding_len > bytes.len() {
+                        return Err(OpusPacketDecodeError::InvalidData);
+                    }
+
                     bytes = &bytes[..bytes.len() - padding_len];
                 }
 
                 if is_vbr {
+                    // Every frame except the last one has its length coded,
+                    // zero-length frames are kept so that the frame count and
+                    // the duration of the packet stay intact.
                     let mut sizes = Vec::with_capacity(frame_count);
-                    for _ in 0..frame_count {
-                        let len = read_variable_length(&mut bytes);
-
-                        if len > 0 {
-                            sizes.push(len);
-                        }
+                    for _ in 0..frame_count - 1 {
+                        sizes.push(read_variable_length(&mut bytes)?);
                     }
 
                     for len in sizes {
-                        if len <= Self::MAX_FRAME_LEN {
-                            datas.push(&bytes[..len]);
+                        if len > Self::MAX_FRAME_LEN || len > bytes.len() {
+                            return Err(OpusPacketDecodeError::InvalidData);
                         }
 
+                        datas.push(&bytes[..len]);
                         bytes.advance(len);
                     }
 
                     if bytes.len() <= Self::MAX_FRAME_LEN {
-                        datas.push(&bytes[..]);
+                        datas.push(bytes);
                     }
 
                     bytes.advance(bytes.len());
                 } else {
                     let len = bytes.len() / frame_count;
+                    if len * frame_count != bytes.len() {
+                        return Err(OpusPacketDecodeError::InvalidData);
+                    }
+
                     for _ in 0..frame_count {
                         datas.push(&bytes[..len]);
 
@@ -205,12 +536,156 @@ impl OpusPacket {
             }
         };
 
-        let mut frames = Vec::with_capacity(datas.len());
-        for data in datas {
-            frames.push(OpusFrame::deocde(&toc, data)?);
+        Ok((toc, datas))
+    }
+
+    /// Returns the decoded samples of all frames in the packet at the output
+    /// rate of the decoder, concatenated in order, one buffer per channel.
+    pub fn samples(&self) -> Vec<Vec<f32>> {
+        let channels = self.frames.first().map(|it| it.samples.len()).unwrap_or(0);
+
+        let mut samples = vec![Vec::new(); channels];
+        for frame in &self.frames {
+            for (channel, buf) in samples.iter_mut().zip(&frame.samples) {
+                channel.extend_from_slice(buf);
+            }
         }
 
-        Ok(Self { toc, frames })
+        samples
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpusDecoderError {
+    UnsupportedSampleRate(u32),
+    BufferTooSmall,
+    PacketDecodeError(OpusPacketDecodeError),
+}
+
+impl From<OpusPacketDecodeError> for OpusDecoderError {
+    fn from(value: OpusPacketDecodeError) -> Self {
+        Self::PacketDecodeError(value)
+    }
+}
+
+impl From<OpusFrameDecoderError> for OpusDecoderError {
+    fn from(value: OpusFrameDecoderError) -> Self {
+        Self::PacketDecodeError(OpusPacketDecodeError::FrameDecodeError(value))
+    }
+}
+
+/// A stateful decoder for a stream of opus packets.
+///
+/// Both CELT and SILK predict from the previous frame (band energies, filter
+/// histories and the MDCT overlap), so every packet of a stream has to go
+/// through the same decoder, in order.
+#[derive(Debug)]
+pub struct OpusDecoder {
+    sample_rate: u32,
+    channels: Channels,
+    celt: CeltFrameDecoder,
+    silk: SilkFrameDecoder,
+    /// Mode of the previous frame, none before the first one.
+    prev_mode: Option<EncodeMode>,
+    /// Whether the previous frame ended with a redundant SILK to CELT frame.
+    prev_redundancy: bool,
+    /// TOC and number of frames of the previous packet, the layout of a lost
+    /// packet.
+    prev_toc: TableOfContents,
+    prev_frames: usize,
+}
+
+impl OpusDecoder {
+    const SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+    /// Creates a decoder that outputs `channels` channels at `sample_rate`,
+    /// which has to be one of 8, 12, 16, 24 or 48 kHz.
+    pub fn new(sample_rate: u32, channels: Channels) -> Result<Self, OpusDecoderError> {
+        if !Self::SAMPLE_RATES.contains(&sample_rate) {
+            return Err(OpusDecoderError::UnsupportedSampleRate(sample_rate));
+        }
+
+        Ok(Self::with_channels(sample_rate, channels))
+    }
+
+    fn with_channels(sample_rate: u32, channels: Channels) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            celt: CeltFrameDecoder::new(sample_rate, channels),
+            silk: SilkFrameDecoder::new(sample_rate, channels),
+            prev_mode: None,
+            prev_redundancy: false,
+            prev_toc: TableOfContents::default(),
+            prev_frames: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> Channels {
+        self.channels
+    }
+
+    /// Ratio of 48 kHz to the output sample rate.
+    fn downsample(&self) -> usize {
+        (48000 / self.sample_rate) as usize
+    }
+
+    /// Decodes a packet into `out` as interleaved samples, returns the number
+    /// of samples decoded per channel.
+    ///
+    /// A lost packet is passed as `None`, it is concealed with the duration
+    /// of the previous packet. Nothing is concealed before the first packet.
+    pub fn decode(
+        &mut self,
+        packet: Option<&[u8]>,
+        out: &mut [f32],
+    ) -> Result<usize, OpusDecoderError> {
+        // The frames of a lost packet are empty, which conceals them.
+        let (toc, datas) = match packet {
+            Some(packet) => OpusPacket::parse(packet)?,
+            None => (self.prev_toc, vec![&[][..]; self.prev_frames]),
+        };
+
+        let channels = self.channels as usize;
+        let frame_size = toc.duration as usize / self.downsample();
+        if out.len() < datas.len() * frame_size * channels {
+            return Err(OpusDecoderError::BufferTooSmall);
+        }
+
+        for (data, out) in datas.iter().zip(out.chunks_mut(frame_size * channels)) {
+            let frame = OpusFrame::deocde(self, &toc, data)?;
+
+            for (i, samples) in out.chunks_exact_mut(channels).enumerate() {
+                for (sample, channel) in samples.iter_mut().zip(&frame.samples) {
+                    *sample = channel[i];
+                }
+            }
+        }
+
+        self.prev_toc = toc;
+        self.prev_frames = datas.len();
+
+        Ok(datas.len() * frame_size)
+    }
+
+    /// Same as [`Self::decode`], with the samples converted to 16-bit PCM.
+    pub fn decode_i16(
+        &mut self,
+        packet: Option<&[u8]>,
+        out: &mut [i16],
+    ) -> Result<usize, OpusDecoderError> {
+        let mut buf = vec![0.0; out.len()];
+        let samples = self.decode(packet, &mut buf)?;
+
+        for (sample, value) in out.iter_mut().zip(buf) {
+            *sample = (value * 32768.0).clamp(-32768.0, 32767.0).round_ties_even() as i16;
+        }
+
+        Ok(samples)
     }
 }
 
@@ -221,20 +696,531 @@ impl OpusPacket {
 /// encoding of the frame length takes up 1 ~ 2 bytes, the rules are as follows:
 ///
 /// - the first byte takes the value 0: there is no frame data (this is usually
-/// a non-sequential transmission (DTX) or a loss of the audio packet)
+///   a non-sequential transmission (DTX) or a loss of the audio packet)
 ///
 /// - the first byte takes the value 1 ~ 251: it means the number of bytes in
-/// the first frame
+///   the first frame
 ///
 /// - the first byte takes the value 252 ~ 255: the second byte is also involved
-/// in the encoding of the frame length, and the total number of bytes in the
-/// first frame is Total number of bytes in the first frame is: (second byte * 4) + first byte
+///   in the encoding of the frame length, and the total number of bytes in the
+///   first frame is Total number of bytes in the first frame is: (second byte * 4) + first byte
 ///
-fn read_variable_length<T: Buf>(bytes: &mut T) -> usize {
+fn read_variable_length<T: Buf>(bytes: &mut T) -> Result<usize, OpusPacketDecodeError> {
+    if !bytes.has_remaining() {
+        return Err(OpusPacketDecodeError::InvalidData);
+    }
+
     let mut len = bytes.get_u8() as usize;
     if len >= 252 {
+        if !bytes.has_remaining() {
+            return Err(OpusPacketDecodeError::InvalidData);
+        }
+
         len += 4 * bytes.get_u8() as usize;
     }
 
-    len
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        OpusDecoder,
+        toc::{Channels, EncodeMode, TableOfContents},
+    };
+
+    /// A 440 Hz sine coded as 20 ms SILK wideband frames.
+    const SILK_SINE: [&[u8]; 5] = [
+        &[
+            72, 130, 226, 183, 108, 86, 183, 244, 0, 2, 4, 187, 190, 222, 131, 118, 135, 162, 55,
+            9, 176, 248, 124, 246, 70, 231, 64, 51, 134, 93, 250, 183, 118, 227, 157, 210, 33, 168,
+            158, 177, 103, 241, 253, 216, 145, 241, 147, 249, 192, 44, 119, 126, 112,
+        ],
+        &[
+            72, 170, 126, 159, 87, 227, 41, 188, 146, 165, 162, 102, 96, 98, 167, 237, 37, 211, 40,
+            249, 192, 223, 54, 249, 67, 237, 152, 144, 202, 252, 201, 74, 5, 62, 144,
+        ],
+        &[
+            72, 159, 249, 185, 101, 94, 222, 147, 164, 133, 187, 23, 102, 151, 57, 171, 2, 71, 31,
+            17, 107, 171, 204, 79, 129, 242, 18, 56, 1, 212, 56, 73, 95, 147, 117, 35, 226,
+        ],
+        &[
+            72, 159, 249, 185, 120, 92, 136, 87, 54, 73, 205, 179, 1, 149, 99, 164, 180, 122, 244,
+            57, 133, 27, 173, 56, 98, 168, 69, 131, 168, 96, 246, 40, 5, 14, 100, 21, 93, 91, 50,
+            95, 163, 142, 39, 90,
+        ],
+        &[
+            72, 159, 250, 140, 16, 89, 210, 87, 59, 42, 46, 45, 199, 139, 241, 7, 55, 113, 147, 30,
+            61, 71, 170, 226, 119, 108, 64, 165, 63, 74, 26, 27, 139, 9, 94, 128,
+        ],
+    ];
+
+    /// Frames of pseudo-random bytes behind every TOC configuration.
+    fn packets(configs: impl Iterator<Item = u8>) -> Vec<Vec<u8>> {
+        let mut seed = 1u32;
+        configs
+            .map(|config| {
+                let mut packet = vec![config << 3];
+                for _ in 0..40 + seed % 80 {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    packet.push((seed >> 24) as u8);
+                }
+
+                packet
+            })
+            .collect()
+    }
+
+    /// Decodes `packets` in turn to mono, `None` being a lost packet, every
+    /// packet of `len` samples.
+    fn decode_all(packets: &[Option<&[u8]>], len: usize) -> Vec<f32> {
+        let mut dec = OpusDecoder::new(48000, Channels::Mono).unwrap();
+        let mut buf = [0.0; 2880];
+        let mut out = Vec::new();
+        for packet in packets {
+            let n = dec.decode(*packet, &mut buf).unwrap();
+            assert_eq!(n, len);
+            out.extend_from_slice(&buf[..n]);
+        }
+
+        out
+    }
+
+    /// Largest difference between two consecutive samples.
+    fn max_jump(x: &[f32]) -> f32 {
+        x.windows(2)
+            .map(|x| (x[1] - x[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    /// Mean energy of the samples.
+    fn energy(x: &[f32]) -> f32 {
+        x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32
+    }
+
+    #[test]
+    fn mode_transitions() {
+        // SILK, Hybrid and CELT configurations in turn, over every bandwidth
+        // and frame duration.
+        let configs = (0..32).flat_map(|i| [i % 12, 12 + i % 4, 16 + i % 16, i]);
+
+        for channels in [Channels::Mono, Channels::Stereo] {
+            for stereo in [0, 4] {
+                let mut dec = OpusDecoder::new(48000, channels).unwrap();
+                let mut out = [0.0; 2880 * 2];
+
+                for packet in packets(configs.clone()) {
+                    let mut packet = packet;
+                    packet[0] |= stereo;
+
+                    let toc = TableOfContents::from(packet[0]);
+                    let switch_to_celt = toc.mode == EncodeMode::CELT
+                        && dec.prev_mode.is_some_and(|mode| mode != EncodeMode::CELT)
+                        && !dec.prev_redundancy;
+
+                    // Every packet gives a frame of its own duration, and
+                    // its mode is the one the next switch starts from.
+                    let n = dec.decode(Some(&packet), &mut out).unwrap();
+                    assert_eq!(n, toc.duration as usize);
+                    assert_eq!(dec.prev_mode, Some(toc.mode));
+                    assert!(out[..n * channels as usize].iter().all(|x| x.is_finite()));
+
+                    // Past the 5 ms fade-in, a switch to CELT decodes as the
+                    // first frame of a stream.
+                    if switch_to_celt && n > 240 {
+                        let mut first = [0.0; 2880 * 2];
+                        OpusDecoder::new(48000, channels)
+                            .unwrap()
+                            .decode(Some(&packet), &mut first)
+                            .unwrap();
+
+                        let range = 240 * channels as usize..n * channels as usize;
+                        assert_eq!(out[range.clone()], first[range]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mode_transitions_are_continuous() {
+        // A 440 Hz sine coded as SILK, Hybrid, CELT, Hybrid and SILK frames.
+        let packets: [&[u8]; 8] = [
+            SILK_SINE[0],
+            SILK_SINE[1],
+            &[
+                120, 160, 96, 158, 112, 160, 210, 239, 150, 149, 28, 23, 6, 252, 167, 66, 32, 26,
+                52, 255, 199, 221, 120, 179, 49, 178, 0, 94, 6, 176, 203, 19, 62, 142, 138, 170,
+                177, 238, 179, 107, 193, 150, 180, 150, 167, 223, 93, 190, 215, 90, 27, 101, 11,
+                88, 234, 120, 204, 83, 10, 110, 146, 18, 139, 136, 188, 179, 75, 50, 40, 183,
+            ],
+            &[
+                248, 173, 109, 197, 29, 161, 123, 13, 249, 177, 184, 251, 76, 19, 48, 218, 86, 20,
+                31, 195, 91, 238, 37, 5, 194, 25, 105, 102, 95, 225, 174,
+            ],
+            &[
+                104, 146, 146, 198, 251, 162, 247, 123, 0, 78, 218, 120, 51, 98, 118, 144, 183,
+                171, 194, 246, 226, 73, 186, 241, 163, 100, 6, 33, 1, 101, 79, 10, 21, 204, 254,
+                181, 98, 217, 107, 237, 230, 116, 181, 66, 133, 42, 252, 168, 232, 125, 206, 14,
+                144, 18, 199, 92, 76, 76, 165, 32, 180, 229, 81, 40, 2, 91, 24, 89, 90, 40, 22,
+                174, 99, 210, 10, 130, 215, 148, 9, 205, 96, 227, 239, 224, 22, 13, 209, 75, 55,
+                110,
+            ],
+            &[
+                104, 170, 127, 1, 135, 164, 221, 48, 159, 250, 146, 167, 25, 82, 91, 158, 242, 97,
+                80, 146, 141, 188, 96, 97, 32, 88, 206, 213, 154, 85, 158, 253, 219, 63, 224, 163,
+                193, 250, 118, 6, 110, 181, 82, 217, 189,
+            ],
+            &[
+                72, 161, 12, 43, 217, 40, 141, 221, 149, 166, 94, 255, 60, 21, 81, 175, 133, 87,
+                108, 154, 183, 39, 54, 176, 35, 241, 242, 206, 86, 217, 178, 222, 169, 40, 26, 252,
+                196, 23, 200, 140, 213,
+            ],
+            &[
+                72, 159, 249, 185, 101, 94, 222, 147, 164, 186, 174, 61, 235, 252, 31, 252, 105,
+                191, 79, 117, 14, 217, 131, 71, 61, 55, 178, 117, 191, 26, 83, 140, 76, 150, 48, 5,
+                149, 172, 64, 92, 160,
+            ],
+        ];
+
+        let mut dec = OpusDecoder::new(48000, Channels::Mono).unwrap();
+        let mut out = Vec::new();
+        for packet in packets {
+            let mut buf = [0.0; 960];
+            let n = dec.decode(Some(packet), &mut buf).unwrap();
+            out.extend_from_slice(&buf[..n]);
+        }
+
+        // The sine moves by less than 0.015 between two samples, a switch
+        // without cross-fade jumps by over twice as much.
+        let jump = out
+            .windows(2)
+            .map(|x| (x[1] - x[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(jump < 0.025, "{jump}");
+    }
+
+    #[test]
+    fn redundancy_after_silk_is_ignored() {
+        // A 440 Hz sine coded as 10 ms SILK frames, the fourth one carries a
+        // redundant CELT frame for a switch from CELT frames that are lost.
+        let packets: [&[u8]; 5] = [
+            &[
+                64, 131, 63, 149, 152, 206, 139, 96, 140, 172, 38, 192, 8, 213, 251, 48,
+            ],
+            &[
+                64, 173, 233, 221, 159, 224, 18, 229, 162, 187, 82, 153, 221, 112, 30, 88, 3, 39,
+                161, 215, 161, 198, 10,
+            ],
+            &[
+                64, 162, 156, 193, 50, 40, 85, 251, 31, 53, 51, 51, 185, 211, 204, 113, 38, 161,
+                116, 148, 108, 209, 146, 79, 169, 20,
+            ],
+            &[
+                64, 132, 69, 82, 170, 69, 186, 175, 229, 4, 74, 253, 227, 169, 71, 85, 24, 204, 8,
+                160, 94, 169, 226, 186, 188, 225, 217, 90, 20, 170, 232, 170, 174, 30, 177, 22, 3,
+                124, 65, 17, 62, 205, 36, 8, 82, 31, 174,
+            ],
+            &[
+                64, 172, 55, 29, 159, 224, 18, 191, 78, 236, 186, 243, 148, 169, 235, 95, 156, 233,
+                215, 199, 100, 240,
+            ],
+        ];
+
+        let out = decode_all(
+            &[
+                Some(packets[0]),
+                Some(packets[1]),
+                Some(packets[2]),
+                None,
+                None,
+                Some(packets[3]),
+                Some(packets[4]),
+            ],
+            480,
+        );
+
+        // The start of the frame is still the SILK one, not the redundant
+        // frame of a CELT state that never decoded anything.
+        let jump = max_jump(&out);
+        assert!(jump < 0.025, "{jump}");
+
+        let energy = [energy(&out[..1440]), energy(&out[2400..2520])];
+        assert!(energy[1] > 0.1 * energy[0], "{energy:?}");
+    }
+
+    #[test]
+    fn low_sample_rates_are_band_limited() {
+        // A 10 kHz sine coded as 10 ms CELT fullband frames.
+        let celt: [&[u8]; 4] = [
+            &[
+                240, 127, 40, 228, 62, 90, 120, 178, 97, 53, 192, 119, 109, 200, 161, 235, 96, 182,
+                86, 15, 226, 37, 148, 245, 164, 0, 172, 171, 173, 216, 145, 37, 8, 6, 88, 92, 124,
+                147, 160, 52, 145, 121, 67, 134, 82,
+            ],
+            &[
+                240, 97, 164, 161, 24, 137, 78, 162, 249, 169, 238, 77, 174, 130, 141, 225, 158,
+                242, 63, 67, 151, 84, 151, 242, 119, 12, 24, 1,
+            ],
+            &[
+                240, 91, 153, 12, 54, 37, 173, 149, 97, 170, 195, 128, 152, 13, 69, 88, 215, 171,
+                99, 170, 69, 64, 217, 114, 22, 157, 74, 220, 112,
+            ],
+            &[
+                240, 91, 169, 23, 209, 53, 139, 87, 72, 46, 121, 59, 21, 249, 220, 194, 233, 210,
+                51, 74, 163, 158, 78, 15, 202, 220, 154, 180, 214,
+            ],
+        ];
+
+        // A 440 Hz sine coded as 10 ms wideband SILK frames.
+        let silk: [&[u8]; 3] = [
+            &[
+                64, 131, 63, 149, 152, 206, 139, 96, 140, 172, 38, 192, 8, 213, 251, 48,
+            ],
+            &[
+                64, 173, 233, 221, 159, 224, 18, 229, 162, 187, 82, 153, 221, 112, 30, 88, 3, 39,
+                161, 215, 161, 198, 10,
+            ],
+            &[
+                64, 162, 156, 193, 50, 40, 85, 251, 31, 53, 51, 51, 185, 211, 204, 113, 38, 161,
+                116, 148, 108, 209, 146, 79, 169, 20,
+            ],
+        ];
+
+        let decode = |packets: &[&[u8]], sample_rate: u32| {
+            let mut dec = OpusDecoder::new(sample_rate, Channels::Mono).unwrap();
+            let mut out = Vec::new();
+            for packet in packets {
+                let mut buf = [0.0; 480];
+                let n = dec.decode(Some(packet), &mut buf).unwrap();
+                assert_eq!(n, sample_rate as usize / 100);
+                out.extend_from_slice(&buf[..n]);
+            }
+
+            out
+        };
+
+        // The sine is kept at 24 kHz, and left out at 16 kHz instead of
+        // aliasing down to 6 kHz.
+        let celt = [48000, 24000, 16000].map(|rate| energy(&decode(&celt, rate)));
+        assert!(celt[1] > 0.9 * celt[0], "{celt:?}");
+        assert!(celt[2] < 1e-3 * celt[0], "{celt:?}");
+
+        // SILK is resampled down from its internal 16 kHz.
+        let silk = [48000, 8000].map(|rate| energy(&decode(&silk, rate)));
+        assert!((silk[1] - silk[0]).abs() < 0.1 * silk[0], "{silk:?}");
+    }
+
+    #[test]
+    fn lost_packets_are_concealed() {
+        // A 440 Hz sine coded as 10 ms CELT frames.
+        let packets: [&[u8]; 6] = [
+            &[
+                240, 181, 64, 58, 32, 12, 175, 192, 123, 36, 67, 238, 100, 46, 93, 94, 112, 152,
+                148, 220, 223, 254, 154, 66, 107, 116, 225, 44,
+            ],
+            &[
+                240, 176, 226, 172, 26, 155, 62, 194, 134, 188, 215, 21, 54, 153, 69, 174,
+            ],
+            &[
+                240, 174, 56, 128, 22, 165, 35, 33, 50, 73, 5, 210, 98, 223, 223, 25, 21, 3, 174,
+            ],
+            &[
+                240, 173, 83, 223, 163, 122, 192, 111, 174, 195, 214, 255, 109, 90, 3, 186, 150,
+                123, 116, 207, 174,
+            ],
+            &[
+                240, 174, 46, 114, 230, 252, 90, 159, 22, 53, 131, 161, 254, 148, 193, 198, 178,
+                19, 213, 127, 174,
+            ],
+            &[
+                240, 174, 46, 126, 16, 130, 32, 88, 230, 132, 177, 120, 83, 160, 124, 214, 7, 121,
+                86, 77, 174,
+            ],
+        ];
+
+        // Nothing is concealed before the first packet.
+        let mut dec = OpusDecoder::new(48000, Channels::Mono).unwrap();
+        assert_eq!(dec.decode(None, &mut [0.0; 480]).unwrap(), 0);
+
+        // The fourth and fifth packets are lost.
+        let out = decode_all(
+            &[
+                Some(packets[0]),
+                Some(packets[1]),
+                Some(packets[2]),
+                None,
+                None,
+                Some(packets[5]),
+            ],
+            480,
+        );
+
+        let jump = max_jump(&out);
+        assert!(jump < 0.025, "{jump}");
+
+        // The concealed frames continue the sine and fade out.
+        let energy = [960, 1440, 1920].map(|i| energy(&out[i..i + 480]));
+        assert!(energy[1] > 0.2 * energy[0], "{energy:?}");
+        assert!(energy[2] < energy[1], "{energy:?}");
+    }
+
+    #[test]
+    fn lost_packet_after_redundant_switch_is_concealed_as_celt() {
+        // A 440 Hz sine coded as 10 ms SILK frames, then CELT frames, the
+        // switch is covered by a redundant CELT frame at the end of the third
+        // one.
+        let packets: [&[u8]; 6] = [
+            &[
+                64, 131, 63, 149, 152, 206, 139, 96, 140, 172, 38, 192, 8, 213, 251, 48,
+            ],
+            &[
+                64, 173, 233, 221, 159, 224, 18, 229, 162, 187, 82, 153, 221, 112, 30, 88, 3, 39,
+                161, 215, 161, 198, 10,
+            ],
+            &[
+                112, 166, 117, 225, 50, 40, 85, 249, 23, 157, 147, 194, 240, 0, 106, 82, 109, 118,
+                125, 30, 73, 191, 179, 179, 96, 54, 14, 182, 231, 102, 194, 102, 19, 126, 234, 9,
+                34, 84, 129, 112, 23, 213, 157, 40, 5,
+            ],
+            &[
+                240, 177, 60, 61, 56, 141, 159, 65, 85, 7, 132, 123, 6, 156, 211, 174,
+            ],
+            &[
+                240, 173, 34, 6, 105, 147, 147, 105, 143, 115, 193, 18, 100, 113, 31, 174,
+            ],
+            &[
+                240, 174, 46, 128, 129, 14, 78, 38, 251, 71, 107, 98, 193, 154, 167, 174,
+            ],
+        ];
+
+        // The first CELT packet is lost.
+        let out = decode_all(
+            &[
+                Some(packets[0]),
+                Some(packets[1]),
+                Some(packets[2]),
+                None,
+                Some(packets[4]),
+                Some(packets[5]),
+            ],
+            480,
+        );
+
+        // The loss is concealed from the primed CELT state, the next CELT
+        // packet follows it without another switch.
+        let jump = max_jump(&out);
+        assert!(jump < 0.025, "{jump}");
+
+        let energy = [960, 1440].map(|i| energy(&out[i..i + 480]));
+        assert!(energy[1] > 0.2 * energy[0], "{energy:?}");
+    }
+
+    #[test]
+    fn lost_silk_packets_are_concealed() {
+        // The last two packets are lost.
+        let out = decode_all(&[Some(SILK_SINE[0]), Some(SILK_SINE[1]), None, None], 960);
+
+        let jump = max_jump(&out);
+        assert!(jump < 0.025, "{jump}");
+
+        // The concealed frames continue the sine and fade out.
+        let energy = [960, 1920, 2880].map(|i| energy(&out[i..i + 960]));
+        assert!(energy[1] > 0.2 * energy[0], "{energy:?}");
+        assert!(energy[2] < energy[1], "{energy:?}");
+    }
+
+    #[test]
+    fn long_silk_frames_are_concealed_whole() {
+        // A 440 Hz sine coded as a 40 ms and a 60 ms SILK wideband frame.
+        let packets: [&[u8]; 2] = [
+            &[
+                80, 194, 20, 40, 181, 94, 48, 16, 212, 214, 189, 45, 12, 94, 238, 127, 211, 35, 86,
+                193, 48, 127, 16, 131, 70, 167, 46, 100, 77, 249, 67, 183, 88, 131, 70, 51, 254,
+                34, 229, 242, 218, 72, 81, 115, 231,
+            ],
+            &[
+                88, 225, 10, 20, 90, 175, 27, 39, 237, 138, 204, 127, 148, 9, 12, 47, 38, 25, 238,
+                202, 230, 241, 193, 219, 91, 124, 130, 247, 196, 191, 27, 154, 219, 103, 147, 77,
+                44, 239, 62, 179, 95, 186, 109, 20, 22, 162, 57, 96, 109, 85, 161, 181, 135, 212,
+                148, 30, 235, 177, 20, 65, 232, 187, 61, 183, 128,
+            ],
+        ];
+
+        // A lost packet and a DTX one, made of the TOC alone, are both
+        // concealed over the whole frame duration.
+        for (packet, len) in packets.into_iter().zip([1920, 2880]) {
+            decode_all(&[Some(packet), None, Some(&packet[..1])], len);
+        }
+    }
+
+    #[test]
+    fn silk_packet_after_loss_is_faded_in() {
+        // Four packets are lost, the concealment fades out to silence.
+        let out = decode_all(
+            &[
+                Some(SILK_SINE[0]),
+                Some(SILK_SINE[1]),
+                None,
+                None,
+                None,
+                None,
+                Some(SILK_SINE[2]),
+            ],
+            960,
+        );
+
+        let jump = max_jump(&out);
+        assert!(jump < 0.025, "{jump}");
+
+        // The decoded frame ramps up from the level of the concealment over
+        // its first 5 ms.
+        let energy = [5760, 6000].map(|i| energy(&out[i..i + 240]));
+        assert!(energy[0] < 0.25 * energy[1], "{energy:?}");
+    }
+
+    #[test]
+    fn lost_hybrid_packets_are_concealed() {
+        // A 440 Hz sine coded as 20 ms Hybrid super wideband frames.
+        let packets: [&[u8]; 4] = [
+            &[
+                104, 130, 226, 93, 108, 126, 64, 230, 0, 0, 9, 137, 250, 252, 105, 169, 250, 79,
+                35, 22, 183, 163, 223, 87, 124, 57, 30, 125, 152, 176, 149, 2, 86, 147, 75, 179,
+                111, 45, 78, 161, 49, 135, 55, 163, 65, 195, 211, 108, 125, 191, 60, 253, 196, 255,
+                38, 205, 207, 195, 68, 36, 214, 73, 67, 97, 10, 216, 179, 0, 61,
+            ],
+            &[
+                104, 168, 203, 223, 87, 227, 41, 188, 146, 165, 161, 242, 242, 134, 244, 86, 94,
+                127, 5, 159, 54, 146, 93, 53, 27, 134, 218, 55, 160, 151, 72, 108, 9, 38, 166, 160,
+                104, 156, 84, 57, 44, 193,
+            ],
+            &[
+                104, 158, 177, 89, 111, 201, 107, 153, 25, 215, 4, 229, 44, 169, 3, 253, 255, 219,
+                205, 98, 125, 193, 129, 69, 94, 193, 172, 230, 124, 76, 162, 220, 240, 146, 45,
+                151, 182, 183, 68, 8, 109, 145, 120, 24, 173, 220, 202, 128, 157, 248, 44, 1, 126,
+                36, 31, 198, 237, 202, 46,
+            ],
+            &[
+                104, 158, 177, 87, 188, 23, 192, 43, 47, 81, 42, 140, 198, 240, 90, 79, 185, 80,
+                135, 180, 36, 118, 221, 222, 156, 54, 171, 40, 80, 136, 57, 2, 81, 201, 9, 234, 7,
+                200, 52, 75, 237, 218, 75, 68, 146, 172, 154, 90, 124, 88, 228, 35, 184, 175, 12,
+                102, 174, 22,
+            ],
+        ];
+
+        // The third packet is lost.
+        let out = decode_all(
+            &[Some(packets[0]), Some(packets[1]), None, Some(packets[3])],
+            960,
+        );
+
+        let jump = max_jump(&out);
+        assert!(jump < 0.025, "{jump}");
+
+        // The sine is in the SILK low band, which is concealed along with the
+        // CELT high band.
+        let energy = [960, 1920, 2880].map(|i| energy(&out[i..i + 960]));
+        assert!(energy[1] > 0.2 * energy[0], "{energy:?}");
+        assert!(energy[2] > 0.2 * energy[0], "{energy:?}");
+    }
 }
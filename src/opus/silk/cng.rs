@@ -0,0 +1,211 @@
+//! SILK comfort noise generation implementation
+//!
+//! Over the inactive frames, the decoder keeps smoothed estimates of the
+//! spectrum, the level and the excitation of the background noise. Lost
+//! frames get a noise shaped after them on top of their concealment, which
+//! fades out faster.
+
+use super::{
+    MAX_FRAME_LENGTH, MAX_LPC_ORDER, SignalType, SilkChannel, lpc,
+    math::{rand, smultt, smulwb, smulww, sqrt_approx},
+};
+
+/// Largest mask of the random index into the excitation buffer.
+const CNG_BUF_MASK_MAX: usize = 255;
+
+/// 0.25^(1/4) in Q16, the smoothing of the gain at every subframe.
+const CNG_GAIN_SMTH_Q16: i32 = 4634;
+
+/// -3 dB in Q16, a gain this far below the smoothed one is taken at once.
+const CNG_GAIN_SMTH_THRESHOLD_Q16: i32 = 46396;
+
+/// 0.25 in Q16, the smoothing of the NLSFs at every frame.
+const CNG_NLSF_SMTH_Q16: i32 = 16348;
+
+/// State of the comfort noise of a channel
+#[derive(Debug)]
+pub struct Cng {
+    /// Internal sample rate the state is set up for.
+    fs_khz: usize,
+    /// Q14 excitation of the loudest subframes of the last inactive frames.
+    exc_buf_q14: [i32; MAX_FRAME_LENGTH],
+    /// Smoothed Q15 NLSFs of the noise.
+    smth_nlsf_q15: [i16; MAX_LPC_ORDER],
+    /// Q14 state of the synthesis filter.
+    synth_state: [i32; MAX_LPC_ORDER],
+    /// Smoothed Q16 gain of the noise.
+    smth_gain_q16: i32,
+    rand_seed: i32,
+}
+
+impl Default for Cng {
+    fn default() -> Self {
+        Self {
+            fs_khz: 0,
+            exc_buf_q14: [0; MAX_FRAME_LENGTH],
+            smth_nlsf_q15: [0; MAX_LPC_ORDER],
+            synth_state: [0; MAX_LPC_ORDER],
+            smth_gain_q16: 0,
+            rand_seed: 0,
+        }
+    }
+}
+
+impl Cng {
+    /// Updates the noise estimates with an inactive decoded frame, or adds
+    /// the noise to a concealed frame `xq`.
+    pub fn process(ch: &mut SilkChannel, xq: &mut [i16]) {
+        let cng = &mut ch.cng;
+        let order = ch.lpc_order;
+
+        // The NLSFs start evenly spread, for a flat spectrum.
+        if cng.fs_khz != ch.fs_khz {
+            let step_q15 = i16::MAX as i32 / (order as i32 + 1);
+            for (i, nlsf) in cng.smth_nlsf_q15.iter_mut().take(order).enumerate() {
+                *nlsf = (step_q15 * (i as i32 + 1)) as i16;
+            }
+
+            cng.smth_gain_q16 = 0;
+            cng.rand_seed = 3176576;
+            cng.fs_khz = ch.fs_khz;
+        }
+
+        if ch.loss_count == 0 && ch.prev_signal_type == SignalType::Inactive {
+            for (nlsf, prev) in cng.smth_nlsf_q15.iter_mut().zip(ch.prev_nlsf).take(order) {
+                *nlsf =
+                    (*nlsf as i32 + smulwb(prev as i32 - *nlsf as i32, CNG_NLSF_SMTH_Q16)) as i16;
+            }
+
+            // The excitation of the subframe of the highest gain goes first in
+            // the buffer.
+            let subfr_length = ch.subfr_length;
+            let gains = &ch.gains[..ch.nb_subfr];
+            let subfr = gains
+                .iter()
+                .enumerate()
+                .fold(
+                    (0, 0),
+                    |max, (i, gain)| if *gain > max.1 { (i, *gain) } else { max },
+                )
+                .0;
+
+            cng.exc_buf_q14
+                .copy_within(..(ch.nb_subfr - 1) * subfr_length, subfr_length);
+            cng.exc_buf_q14[..subfr_length]
+                .copy_from_slice(&ch.exc_q14[subfr * subfr_length..][..subfr_length]);
+
+            for &gain in gains {
+                cng.smth_gain_q16 += smulwb(gain - cng.smth_gain_q16, CNG_GAIN_SMTH_Q16);
+                if smulww(cng.smth_gain_q16, CNG_GAIN_SMTH_THRESHOLD_Q16) > gain {
+                    cng.smth_gain_q16 = gain;
+                }
+            }
+        }
+
+        if ch.loss_count == 0 {
+            cng.synth_state[..order].fill(0);
+            return;
+        }
+
+        // The noise makes up for the energy the concealment loses against the
+        // smoothed gain, with a larger scale for high gains.
+        let gain_q16 = ch.plc.noise_gain_q16();
+        let gain_q16 = if gain_q16 >= 1 << 21 || cng.smth_gain_q16 > 1 << 23 {
+            let gain_q16 = smultt(gain_q16, gain_q16);
+            let gain_q16 = smultt(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16 << 5);
+            sqrt_approx(gain_q16) << 16
+        } else {
+            let gain_q16 = smulww(gain_q16, gain_q16);
+            let gain_q16 = smulww(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16 << 5);
+            sqrt_approx(gain_q16) << 8
+        };
+
+        // Random samples of the excitation buffer, from the part of it the
+        // frame length covers
+        let length = xq.len();
+        let mut exc_mask = CNG_BUF_MASK_MAX;
+        while exc_mask > length {
+            exc_mask >>= 1;
+        }
+
+        let mut exc = [0; MAX_FRAME_LENGTH];
+        for exc in exc.iter_mut().take(length) {
+            cng.rand_seed = rand(cng.rand_seed);
+            *exc = cng.exc_buf_q14[((cng.rand_seed >> 24) & exc_mask as i32) as usize];
+        }
+
+        let mut a_q12 = [0; MAX_LPC_ORDER];
+        lpc::nlsf2a(&mut a_q12[..order], &cng.smth_nlsf_q15[..order]);
+
+        let mut s_lpc = [0; MAX_LPC_ORDER + MAX_FRAME_LENGTH];
+        s_lpc[..MAX_LPC_ORDER].copy_from_slice(&cng.synth_state);
+
+        let mut noise = [0; MAX_FRAME_LENGTH];
+        lpc::synthesis(
+            &mut s_lpc[..MAX_LPC_ORDER + length],
+            &a_q12[..order],
+            &exc[..length],
+            gain_q16 >> 6,
+            &mut noise[..length],
+        );
+
+        for (x, noise) in xq.iter_mut().zip(noise) {
+            *x = x.saturating_add(noise);
+        }
+
+        cng.synth_state
+            .copy_from_slice(&s_lpc[length..length + MAX_LPC_ORDER]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cng, SignalType, SilkChannel, rand};
+
+    /// Comfort noise of a lost frame, after inactive frames of `gain_q16`.
+    fn noise(gain_q16: i32) -> [i16; 320] {
+        let mut ch = SilkChannel {
+            fs_khz: 16,
+            nb_subfr: 4,
+            subfr_length: 80,
+            lpc_order: 16,
+            gains: [gain_q16; 4],
+            prev_signal_type: SignalType::Inactive,
+            ..Default::default()
+        };
+
+        let mut seed = 1;
+        for exc in ch.exc_q14.iter_mut().take(320) {
+            seed = rand(seed);
+            *exc = (seed >> 16) << 4;
+        }
+
+        for (i, nlsf) in ch.prev_nlsf.iter_mut().enumerate() {
+            *nlsf = (1927 * (i as i32 + 1)) as i16;
+        }
+
+        // The decoded frames only update the estimates.
+        let mut xq = [0; 320];
+        for _ in 0..8 {
+            Cng::process(&mut ch, &mut xq);
+        }
+
+        assert_eq!(xq, [0; 320]);
+
+        ch.loss_count = 1;
+        Cng::process(&mut ch, &mut xq);
+        xq
+    }
+
+    #[test]
+    fn process() {
+        let energy = |x: &[i16]| x.iter().map(|x| (*x as f32).powi(2)).sum::<f32>();
+
+        // A lost frame gets a noise at the level of the smoothed gain of the
+        // inactive frames.
+        let quiet = energy(&noise(1 << 16));
+        let loud = energy(&noise(1 << 17));
+        assert!(quiet > 0.0);
+        assert!(loud > 3.5 * quiet && loud < 4.5 * quiet, "{quiet} {loud}");
+    }
+}
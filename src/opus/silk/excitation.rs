@@ -0,0 +1,297 @@
+//! SILK excitation decoding implementation
+//!
+//! The excitation is coded as pulses in blocks of 16 samples. The number of
+//! pulses of every block is coded first, with a model picked by the rate
+//! level of the frame, and the pulses are then spread over the block by
+//! recursively splitting it in halves (shell coding). Large amplitudes carry
+//! extra LSBs, and the sign of every non-zero sample is coded last.
+//!
+//! The quantized pulses are offset towards zero and dithered with a pseudo
+//! random sign flip to build the excitation signal.
+
+use crate::opus::entropy::RangeCodingDecoder;
+
+use super::{MAX_FRAME_LENGTH, SignalType, SilkChannel, math::rand};
+
+/// Length of a shell coded block.
+const SHELL_CODEC_FRAME_LENGTH: usize = 16;
+
+/// Largest number of pulses of a block, larger values have extra LSBs.
+const SILK_MAX_PULSES: usize = 16;
+
+/// Maximum number of extra LSBs of a block.
+const MAX_LSHIFTS: usize = 10;
+
+/// 80 in Q10, the pulses are pulled towards zero by it.
+const QUANT_LEVEL_ADJUST_Q10: i32 = 80;
+
+/// Offset of the excitation, in Q10, by signal type and quantizer offset.
+const QUANTIZATION_OFFSETS_Q10: [[i32; 2]; 2] = [[100, 240], [32, 100]];
+
+/// Rate level, for unvoiced and voiced frames.
+const RATE_LEVEL_MODEL: [[usize; 10]; 2] = [
+    [256, 15, 66, 78, 124, 169, 182, 215, 242, 256],
+    [256, 33, 63, 99, 116, 150, 199, 217, 238, 256],
+];
+
+/// Number of pulses of a shell block for each rate level, 17 escapes to an
+/// extra LSB.
+const PULSES_PER_BLOCK_MODEL: [[usize; 19]; 10] = [
+    [
+        256, 131, 205, 230, 238, 241, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255,
+        256,
+    ],
+    [
+        256, 58, 151, 211, 234, 241, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255,
+        256,
+    ],
+    [
+        256, 43, 94, 140, 173, 197, 213, 224, 232, 238, 241, 244, 247, 249, 250, 251, 253, 254, 256,
+    ],
+    [
+        256, 17, 69, 140, 197, 228, 240, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255, 256,
+    ],
+    [
+        256, 6, 27, 68, 121, 170, 205, 226, 237, 243, 246, 248, 250, 251, 252, 253, 254, 255, 256,
+    ],
+    [
+        256, 7, 21, 43, 71, 100, 128, 153, 173, 190, 203, 214, 223, 230, 235, 239, 243, 246, 256,
+    ],
+    [
+        256, 2, 7, 21, 50, 92, 138, 179, 210, 229, 240, 246, 249, 251, 252, 253, 254, 255, 256,
+    ],
+    [
+        256, 1, 3, 7, 17, 36, 65, 100, 137, 171, 199, 219, 233, 241, 246, 250, 252, 254, 256,
+    ],
+    [
+        256, 1, 3, 5, 10, 19, 33, 53, 77, 104, 132, 158, 181, 201, 216, 227, 235, 241, 256,
+    ],
+    [
+        256, 1, 2, 3, 9, 36, 94, 150, 189, 214, 228, 238, 244, 247, 250, 252, 253, 254, 256,
+    ],
+];
+
+/// The last rate level without the escape, once the maximum number of LSBs
+/// is reached.
+const PULSES_PER_BLOCK_MAX_LSB_MODEL: [usize; 18] = [
+    256, 2, 3, 9, 36, 94, 150, 189, 214, 228, 238, 244, 247, 250, 252, 253, 254, 256,
+];
+
+/// Extra LSBs of the large amplitudes.
+const LSB_MODEL: [usize; 3] = [256, 136, 256];
+
+/// Split models of the shell coder, from the pairs of samples (0) to the
+/// halves of the block (3).
+const SHELL_CODE_TABLE0: [usize; 168] = [
+    256, 128, 256, 256, 42, 214, 256, 256, 21, 128, 235, 256, 256, 12, 72, 184, 245, 256, 256, 8,
+    42, 128, 214, 249, 256, 256, 8, 31, 86, 176, 231, 251, 256, 256, 5, 20, 58, 130, 202, 238, 253,
+    256, 256, 6, 18, 45, 97, 174, 221, 241, 251, 256, 256, 6, 25, 53, 88, 128, 168, 203, 231, 250,
+    256, 256, 4, 18, 40, 71, 108, 148, 185, 216, 238, 252, 256, 256, 3, 13, 31, 57, 90, 128, 166,
+    199, 225, 243, 253, 256, 256, 2, 10, 23, 44, 73, 109, 147, 183, 212, 233, 246, 254, 256, 256,
+    1, 6, 16, 33, 58, 90, 128, 166, 198, 223, 240, 250, 255, 256, 256, 1, 5, 12, 25, 46, 75, 110,
+    146, 181, 210, 231, 244, 251, 255, 256, 256, 1, 3, 8, 18, 35, 60, 92, 128, 164, 196, 221, 238,
+    248, 253, 255, 256, 256, 1, 3, 7, 14, 27, 48, 76, 110, 146, 180, 208, 229, 242, 249, 253, 255,
+    256,
+];
+const SHELL_CODE_TABLE1: [usize; 168] = [
+    256, 127, 256, 256, 49, 206, 256, 256, 20, 127, 236, 256, 256, 11, 71, 184, 246, 256, 256, 7,
+    43, 127, 214, 250, 256, 256, 6, 30, 87, 169, 229, 252, 256, 256, 5, 23, 62, 126, 194, 236, 252,
+    256, 256, 6, 20, 49, 96, 157, 209, 239, 253, 256, 256, 1, 16, 39, 74, 125, 175, 215, 245, 255,
+    256, 256, 1, 2, 23, 55, 97, 149, 195, 236, 254, 255, 256, 256, 1, 7, 23, 50, 86, 128, 170, 206,
+    233, 249, 255, 256, 256, 1, 6, 18, 39, 70, 108, 148, 186, 217, 238, 250, 255, 256, 256, 1, 4,
+    13, 30, 56, 90, 128, 166, 200, 226, 243, 252, 255, 256, 256, 1, 4, 11, 25, 47, 76, 110, 146,
+    180, 209, 231, 245, 252, 255, 256, 256, 1, 3, 8, 19, 37, 62, 93, 128, 163, 194, 219, 237, 248,
+    253, 255, 256, 256, 1, 2, 6, 15, 30, 51, 79, 111, 145, 177, 205, 226, 241, 250, 254, 255, 256,
+];
+const SHELL_CODE_TABLE2: [usize; 168] = [
+    256, 127, 256, 256, 53, 202, 256, 256, 22, 127, 233, 256, 256, 11, 72, 183, 246, 256, 256, 6,
+    41, 127, 215, 251, 256, 256, 4, 24, 83, 170, 232, 253, 256, 256, 3, 16, 56, 127, 200, 241, 254,
+    256, 256, 3, 12, 39, 92, 162, 218, 246, 255, 256, 256, 3, 11, 30, 67, 124, 185, 229, 249, 255,
+    256, 256, 3, 10, 25, 53, 97, 151, 200, 233, 250, 255, 256, 256, 1, 8, 21, 43, 77, 123, 171,
+    209, 237, 251, 255, 256, 256, 1, 2, 13, 35, 62, 97, 139, 186, 219, 244, 254, 255, 256, 256, 1,
+    2, 8, 22, 48, 85, 128, 171, 208, 234, 248, 254, 255, 256, 256, 1, 2, 6, 16, 36, 67, 107, 149,
+    189, 220, 240, 250, 254, 255, 256, 256, 1, 2, 5, 13, 29, 55, 90, 128, 166, 201, 227, 243, 251,
+    254, 255, 256, 256, 1, 2, 4, 10, 22, 43, 73, 109, 147, 183, 213, 234, 246, 252, 254, 255, 256,
+];
+const SHELL_CODE_TABLE3: [usize; 168] = [
+    256, 126, 256, 256, 56, 198, 256, 256, 25, 126, 230, 256, 256, 12, 72, 180, 244, 256, 256, 7,
+    42, 126, 213, 250, 256, 256, 4, 24, 83, 169, 232, 253, 256, 256, 3, 15, 53, 125, 200, 242, 254,
+    256, 256, 2, 10, 35, 89, 162, 221, 248, 255, 256, 256, 2, 7, 24, 63, 126, 191, 233, 251, 255,
+    256, 256, 1, 5, 17, 45, 94, 157, 211, 241, 252, 255, 256, 256, 1, 5, 13, 33, 70, 125, 182, 223,
+    245, 253, 255, 256, 256, 1, 4, 11, 26, 54, 98, 151, 199, 232, 248, 254, 255, 256, 256, 1, 3, 9,
+    21, 42, 77, 124, 172, 212, 237, 249, 254, 255, 256, 256, 1, 2, 6, 16, 33, 60, 97, 144, 187,
+    220, 241, 250, 254, 255, 256, 256, 1, 2, 3, 11, 25, 47, 80, 120, 163, 201, 229, 245, 253, 254,
+    255, 256, 256, 1, 2, 3, 4, 17, 35, 62, 98, 139, 180, 214, 238, 252, 253, 254, 255, 256,
+];
+
+/// Offset of the split model of each number of pulses in the shell tables.
+const SHELL_CODE_TABLE_OFFSETS: [usize; 17] = [
+    0, 0, 3, 7, 12, 18, 25, 33, 42, 52, 63, 75, 88, 102, 117, 133, 150,
+];
+
+/// Probability of a positive sign, by signal type, quantizer offset and
+/// number of pulses.
+const SIGN_ICDF: [u8; 42] = [
+    254, 49, 67, 77, 82, 93, 99, 198, 11, 18, 24, 31, 36, 45, 255, 46, 66, 78, 87, 94, 104, 208,
+    14, 21, 32, 42, 51, 66, 255, 94, 104, 109, 112, 115, 118, 248, 53, 69, 80, 88, 95, 102,
+];
+
+/// Excitation decoder
+pub struct Excitation;
+
+impl Excitation {
+    /// Decodes the signed pulses of the frame into `pulses`.
+    pub fn decode(ch: &SilkChannel, range_dec: &mut RangeCodingDecoder, pulses: &mut [i32]) {
+        let voiced = (ch.indices.signal_type == SignalType::Voiced) as usize;
+        let rate_level = range_dec.icdf(&RATE_LEVEL_MODEL[voiced]);
+
+        // 10 ms frames at 12 kHz are 120 samples long, the last block is
+        // only partially used.
+        let blocks = ch.frame_length.div_ceil(SHELL_CODEC_FRAME_LENGTH);
+
+        // The number of pulses of each block, an escape symbol adds an LSB
+        // to every sample of the block.
+        let mut sum_pulses = [0; MAX_FRAME_LENGTH / SHELL_CODEC_FRAME_LENGTH];
+        let mut lshifts = [0; MAX_FRAME_LENGTH / SHELL_CODEC_FRAME_LENGTH];
+        for i in 0..blocks {
+            sum_pulses[i] = range_dec.icdf(&PULSES_PER_BLOCK_MODEL[rate_level]);
+            while sum_pulses[i] == SILK_MAX_PULSES + 1 {
+                lshifts[i] += 1;
+
+                // No more escapes once the maximum number of LSBs is reached
+                sum_pulses[i] = range_dec.icdf(if lshifts[i] == MAX_LSHIFTS {
+                    &PULSES_PER_BLOCK_MAX_LSB_MODEL[..]
+                } else {
+                    &PULSES_PER_BLOCK_MODEL[PULSES_PER_BLOCK_MODEL.len() - 1][..]
+                });
+            }
+        }
+
+        for (i, block) in pulses
+            .chunks_exact_mut(SHELL_CODEC_FRAME_LENGTH)
+            .take(blocks)
+            .enumerate()
+        {
+            if sum_pulses[i] > 0 {
+                shell_decode(block, range_dec, sum_pulses[i]);
+            } else {
+                block.fill(0);
+            }
+        }
+
+        // The LSBs of all the blocks follow their pulses.
+        for (i, block) in pulses
+            .chunks_exact_mut(SHELL_CODEC_FRAME_LENGTH)
+            .take(blocks)
+            .enumerate()
+        {
+            if lshifts[i] > 0 {
+                for pulse in block.iter_mut() {
+                    for _ in 0..lshifts[i] {
+                        *pulse = (*pulse << 1) + range_dec.icdf(&LSB_MODEL) as i32;
+                    }
+                }
+
+                // A block with LSBs has non-zero samples whatever the number
+                // of pulses.
+                sum_pulses[i] |= lshifts[i] << 5;
+            }
+        }
+
+        // The signs of the non-zero samples, the probability depends on the
+        // number of pulses of the block.
+        let sign_icdf =
+            &SIGN_ICDF[7 * (ch.indices.quant_offset_type + 2 * ch.indices.signal_type as usize)..];
+        for (i, block) in pulses
+            .chunks_exact_mut(SHELL_CODEC_FRAME_LENGTH)
+            .take(blocks)
+            .enumerate()
+        {
+            if sum_pulses[i] > 0 {
+                let model = [
+                    256,
+                    256 - sign_icdf[(sum_pulses[i] & 0x1f).min(6)] as usize,
+                    256,
+                ];
+                for pulse in block.iter_mut().filter(|pulse| **pulse > 0) {
+                    if range_dec.icdf(&model) == 0 {
+                        *pulse = -*pulse;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Turns the pulses into the Q14 excitation of the frame.
+    pub fn dequant(ch: &mut SilkChannel, pulses: &[i32]) {
+        let voiced = (ch.indices.signal_type == SignalType::Voiced) as usize;
+        let offset_q10 = QUANTIZATION_OFFSETS_Q10[voiced][ch.indices.quant_offset_type];
+
+        let mut seed = ch.indices.seed;
+        for (exc, &pulse) in ch.exc_q14.iter_mut().zip(pulses).take(ch.frame_length) {
+            seed = rand(seed);
+
+            *exc = pulse << 14;
+            if *exc > 0 {
+                *exc -= QUANT_LEVEL_ADJUST_Q10 << 4;
+            } else if *exc < 0 {
+                *exc += QUANT_LEVEL_ADJUST_Q10 << 4;
+            }
+
+            *exc += offset_q10 << 4;
+            if seed < 0 {
+                *exc = -*exc;
+            }
+
+            seed = seed.wrapping_add(pulse);
+        }
+    }
+}
+
+/// Splits `pulses` pulses between the two halves of a partition, with the
+/// models of `table`.
+fn decode_split(range_dec: &mut RangeCodingDecoder, pulses: i32, table: &[usize]) -> (i32, i32) {
+    if pulses > 0 {
+        let left = range_dec.icdf(&table[SHELL_CODE_TABLE_OFFSETS[pulses as usize]..]) as i32;
+        (left, pulses - left)
+    } else {
+        (0, 0)
+    }
+}
+
+/// Spreads `pulses` pulses over a block of 16 samples, splitting it in
+/// halves down to single samples, depth first.
+fn shell_decode(block: &mut [i32], range_dec: &mut RangeCodingDecoder, pulses: usize) {
+    let (p3_0, p3_1) = decode_split(range_dec, pulses as i32, &SHELL_CODE_TABLE3);
+    for (i, p3) in [p3_0, p3_1].into_iter().enumerate() {
+        let (p2_0, p2_1) = decode_split(range_dec, p3, &SHELL_CODE_TABLE2);
+        for (j, p2) in [p2_0, p2_1].into_iter().enumerate() {
+            let (p1_0, p1_1) = decode_split(range_dec, p2, &SHELL_CODE_TABLE1);
+            for (k, p1) in [p1_0, p1_1].into_iter().enumerate() {
+                let offset = 8 * i + 4 * j + 2 * k;
+                let (p0_0, p0_1) = decode_split(range_dec, p1, &SHELL_CODE_TABLE0);
+                block[offset] = p0_0;
+                block[offset + 1] = p0_1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Excitation, SignalType, SilkChannel};
+
+    #[test]
+    fn dequant() {
+        let mut ch = SilkChannel {
+            frame_length: 4,
+            ..Default::default()
+        };
+
+        // The pulses are pulled towards zero and offset, the signs follow the
+        // pseudo random sequence.
+        ch.indices.signal_type = SignalType::Unvoiced;
+        Excitation::dequant(&mut ch, &[0, 1, -2, 0]);
+        assert_eq!(ch.exc_q14[..4], [1600, -16704, -29888, -1600]);
+    }
+}
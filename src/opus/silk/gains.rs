@@ -0,0 +1,118 @@
+//! SILK subframe gains decoding implementation
+//!
+//! Every subframe has a quantization gain on a 64 levels logarithmic scale
+//! from 2 to 88 dB. The first gain of a frame is coded absolutely when the
+//! frame is coded independently of the previous one, all the other ones are
+//! deltas from the previous gain.
+
+use crate::opus::entropy::RangeCodingDecoder;
+
+use super::{
+    CondCoding, SilkChannel, UNIFORM8_MODEL,
+    math::{log2lin, smulwb},
+};
+
+/// MSBs of the absolute gain, for each signal type.
+pub const GAIN_MODEL: [[usize; 9]; 3] = [
+    [256, 32, 144, 212, 241, 253, 254, 255, 256],
+    [256, 2, 19, 64, 124, 186, 233, 252, 256],
+    [256, 1, 4, 30, 101, 195, 245, 254, 256],
+];
+
+pub const DELTA_GAIN_MODEL: [usize; 42] = [
+    256, 6, 11, 22, 53, 185, 206, 214, 218, 221, 223, 225, 227, 228, 229, 230, 231, 232, 233, 234,
+    235, 236, 237, 238, 239, 240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253,
+    254, 255, 256,
+];
+
+const MIN_QGAIN_DB: i32 = 2;
+const MAX_QGAIN_DB: i32 = 88;
+const N_LEVELS_QGAIN: i32 = 64;
+const MIN_DELTA_GAIN_QUANT: i32 = -4;
+const MAX_DELTA_GAIN_QUANT: i32 = 36;
+
+const OFFSET: i32 = (MIN_QGAIN_DB * 128) / 6 + 16 * 128;
+const INV_SCALE_Q16: i32 =
+    (65536 * (((MAX_QGAIN_DB - MIN_QGAIN_DB) * 128) / 6)) / (N_LEVELS_QGAIN - 1);
+
+/// Subframe gains decoder
+pub struct Gains;
+
+impl Gains {
+    /// Decodes the gain indices of every subframe.
+    pub fn decode(ch: &mut SilkChannel, range_dec: &mut RangeCodingDecoder, cond: CondCoding) {
+        ch.indices.gains[0] = if cond == CondCoding::Conditionally {
+            range_dec.icdf(&DELTA_GAIN_MODEL) as i32
+        } else {
+            // Independent coding, in two stages: MSB bits followed by 3 LSBs
+            let msb = range_dec.icdf(&GAIN_MODEL[ch.indices.signal_type as usize]) as i32;
+            (msb << 3) + range_dec.icdf(&UNIFORM8_MODEL) as i32
+        };
+
+        for i in 1..ch.nb_subfr {
+            ch.indices.gains[i] = range_dec.icdf(&DELTA_GAIN_MODEL) as i32;
+        }
+    }
+
+    /// Turns the gain indices into Q16 gains, accumulating the deltas from
+    /// the last gain index of the previous frame.
+    pub fn dequant(ch: &mut SilkChannel, cond: CondCoding) {
+        for k in 0..ch.nb_subfr {
+            let index = ch.indices.gains[k];
+            if k == 0 && cond != CondCoding::Conditionally {
+                // Gain index is not allowed to go down more than 16 steps
+                // (~21.8 dB)
+                ch.last_gain_index = index.max(ch.last_gain_index - 16);
+            } else {
+                // Delta index, the steps are doubled above a threshold
+                let delta = index + MIN_DELTA_GAIN_QUANT;
+                let threshold = 2 * MAX_DELTA_GAIN_QUANT - N_LEVELS_QGAIN + ch.last_gain_index;
+                ch.last_gain_index += if delta > threshold {
+                    (delta << 1) - threshold
+                } else {
+                    delta
+                };
+            }
+
+            ch.last_gain_index = ch.last_gain_index.clamp(0, N_LEVELS_QGAIN - 1);
+
+            // Scale and convert to linear scale, 3967 is 31 in Q7.
+            ch.gains[k] = log2lin((smulwb(INV_SCALE_Q16, ch.last_gain_index) + OFFSET).min(3967));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CondCoding, Gains, SilkChannel};
+
+    #[test]
+    fn dequant() {
+        let mut ch = SilkChannel {
+            nb_subfr: 4,
+            last_gain_index: 10,
+            ..Default::default()
+        };
+
+        // An absolute index can only go down 16 steps from the previous one,
+        // the deltas of 4 keep the gain steady.
+        ch.indices.gains = [40, 4, 4, 4];
+        Gains::dequant(&mut ch, CondCoding::Independently);
+        assert_eq!(ch.last_gain_index, 40);
+        assert!(ch.gains.iter().all(|gain| *gain == ch.gains[0]));
+
+        ch.indices.gains = [0, 0, 0, 0];
+        Gains::dequant(&mut ch, CondCoding::Independently);
+        assert_eq!(ch.last_gain_index, 24 - 12);
+
+        // The lowest and highest levels
+        ch.last_gain_index = 0;
+        ch.indices.gains = [0, 4, 4, 4];
+        Gains::dequant(&mut ch, CondCoding::Independently);
+        assert_eq!(ch.gains[3], 81920);
+
+        ch.indices.gains = [63, 40, 40, 40];
+        Gains::dequant(&mut ch, CondCoding::Independently);
+        assert_eq!(ch.last_gain_index, 63);
+    }
+}
@@ -0,0 +1,276 @@
+//! SILK NLSF to LPC conversion and LPC filters
+//!
+//! The normalized line spectral frequencies are the roots of the sum and
+//! difference polynomials of the LPC filter, rebuilding the filter is a
+//! matter of multiplying these polynomials back together. The result goes
+//! through bandwidth expansion until it is stable once quantized to Q12.
+//!
+//! The excitation of the frame is shaped by the resulting synthesis filter.
+
+use super::{
+    MAX_LPC_ORDER,
+    math::{inverse32_varq, lshift_sat32, rshift_round, rshift_round64, smlawb, smmul, smulww},
+};
+
+/// Maximum number of bandwidth expansions applied to an unstable filter.
+const MAX_LPC_STABILIZE_ITERATIONS: u32 = 16;
+
+/// Q domain of the polynomials.
+const QA: u32 = 16;
+
+/// Q domain of the coefficients in the inverse prediction gain computation.
+const QA_INV_GAIN: u32 = 24;
+
+/// 0.99975 in Q24, the largest coefficient magnitude of a stable filter.
+const A_LIMIT: i32 = 16773022;
+
+/// 1e-4 in Q30, the lowest inverse prediction gain allowed.
+const MIN_INV_GAIN_Q30: i32 = 107374;
+
+/// Cosine table in Q12, over the [0, pi] range.
+const LSF_COS_TAB_Q12: [i32; 129] = [
+    8192, 8190, 8182, 8170, 8152, 8130, 8104, 8072, 8034, 7994, 7946, 7896, 7840, 7778, 7714, 7644,
+    7568, 7490, 7406, 7318, 7226, 7128, 7026, 6922, 6812, 6698, 6580, 6458, 6332, 6204, 6070, 5934,
+    5792, 5648, 5502, 5352, 5198, 5040, 4880, 4718, 4552, 4382, 4212, 4038, 3862, 3684, 3502, 3320,
+    3136, 2948, 2760, 2570, 2378, 2186, 1990, 1794, 1598, 1400, 1202, 1002, 802, 602, 402, 202, 0,
+    -202, -402, -602, -802, -1002, -1202, -1400, -1598, -1794, -1990, -2186, -2378, -2570, -2760,
+    -2948, -3136, -3320, -3502, -3684, -3862, -4038, -4212, -4382, -4552, -4718, -4880, -5040,
+    -5198, -5352, -5502, -5648, -5792, -5934, -6070, -6204, -6332, -6458, -6580, -6698, -6812,
+    -6922, -7026, -7128, -7226, -7318, -7406, -7490, -7568, -7644, -7714, -7778, -7840, -7896,
+    -7946, -7994, -8034, -8072, -8104, -8130, -8152, -8170, -8182, -8190, -8192,
+];
+
+/// Order in which the cosines are interleaved into the polynomials, it
+/// improves the numerical accuracy of the products.
+const ORDERING_16: [usize; 16] = [0, 15, 8, 7, 4, 11, 12, 3, 2, 13, 10, 5, 6, 9, 14, 1];
+const ORDERING_10: [usize; 10] = [0, 9, 6, 3, 4, 5, 8, 1, 2, 7];
+
+/// Converts the Q15 NLSFs into Q12 LPC coefficients of a stable filter.
+pub fn nlsf2a(a_q12: &mut [i16], nlsf: &[i16]) {
+    let d = nlsf.len();
+    let ordering = if d == 16 {
+        &ORDERING_16[..]
+    } else {
+        &ORDERING_10[..]
+    };
+
+    // 2 * cos(LSF) by linear interpolation of the table
+    let mut cos_lsf = [0; 16];
+    for (k, &f) in nlsf.iter().enumerate() {
+        let f_int = (f >> 8) as usize;
+        let f_frac = (f & 0xff) as i32;
+
+        let cos = LSF_COS_TAB_Q12[f_int];
+        let delta = LSF_COS_TAB_Q12[f_int + 1] - cos;
+        cos_lsf[ordering[k]] = rshift_round((cos << 8) + delta * f_frac, 20 - QA);
+    }
+
+    // The even and odd polynomials
+    let dd = d / 2;
+    let mut p = [0; 9];
+    let mut q = [0; 9];
+    find_poly(&mut p, &cos_lsf, dd);
+    find_poly(&mut q, &cos_lsf[1..], dd);
+
+    let mut a32 = [0; 16];
+    for k in 0..dd {
+        let p_tmp = p[k + 1] + p[k];
+        let q_tmp = q[k + 1] - q[k];
+
+        a32[k] = -q_tmp - p_tmp;
+        a32[d - k - 1] = q_tmp - p_tmp;
+    }
+
+    let a32 = &mut a32[..d];
+    lpc_fit(a_q12, a32, 12, QA + 1);
+
+    // Apply bandwidth expansion on the unscaled coefficients until the
+    // quantized filter is stable.
+    for i in 0..MAX_LPC_STABILIZE_ITERATIONS {
+        if inverse_pred_gain(a_q12) != 0 {
+            break;
+        }
+
+        bwexpander_32(a32, 65536 - (2 << i));
+        for (a, x) in a_q12.iter_mut().zip(a32.iter()) {
+            *a = rshift_round(*x, QA + 1 - 12) as i16;
+        }
+    }
+}
+
+/// Expands the polynomial of the interleaved cosines `c_lsf`, in QA.
+fn find_poly(out: &mut [i32], c_lsf: &[i32], dd: usize) {
+    out[0] = 1 << QA;
+    out[1] = -c_lsf[0];
+
+    for k in 1..dd {
+        let f = c_lsf[2 * k] as i64;
+        out[k + 1] = (out[k - 1] << 1) - rshift_round64(f * out[k] as i64, QA) as i32;
+        for n in (2..=k).rev() {
+            out[n] += out[n - 2] - rshift_round64(f * out[n - 1] as i64, QA) as i32;
+        }
+
+        out[1] -= f as i32;
+    }
+}
+
+/// Converts the coefficients from Q`q_in` to Q`q_out`, applying bandwidth
+/// expansion until they fit in 16 bits.
+fn lpc_fit(a_out: &mut [i16], a_in: &mut [i32], q_out: u32, q_in: u32) {
+    let shift = q_in - q_out;
+
+    for _ in 0..10 {
+        // The largest coefficient and its index
+        let (idx, maxabs) = a_in
+            .iter()
+            .map(|a| a.abs())
+            .enumerate()
+            .fold((0, 0), |max, (i, a)| if a > max.1 { (i, a) } else { max });
+
+        let maxabs = rshift_round(maxabs, shift);
+        if maxabs <= i16::MAX as i32 {
+            for (a, x) in a_out.iter_mut().zip(a_in.iter()) {
+                *a = rshift_round(*x, shift) as i16;
+            }
+
+            return;
+        }
+
+        // (i32::MAX >> 14) + i16::MAX
+        let maxabs = maxabs.min(163838);
+        let chirp_q16 =
+            65470 - ((maxabs - i16::MAX as i32) << 14) / ((maxabs * (idx as i32 + 1)) >> 2);
+        bwexpander_32(a_in, chirp_q16);
+    }
+
+    // Still too large, clip the coefficients
+    for (a, x) in a_out.iter_mut().zip(a_in.iter_mut()) {
+        *a = rshift_round(*x, shift).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        *x = (*a as i32) << shift;
+    }
+}
+
+/// Chirps the filter `ar` by `chirp_q16`, the i-th coefficient being scaled
+/// by `chirp^(i + 1)`.
+fn bwexpander_32(ar: &mut [i32], mut chirp_q16: i32) {
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+
+    let (last, ar) = ar.split_last_mut().unwrap();
+    for a in ar {
+        *a = smulww(chirp_q16, *a);
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    }
+
+    *last = smulww(chirp_q16, *last);
+}
+
+/// Chirps the Q12 filter `ar` by `chirp_q16`, like [`bwexpander_32`].
+pub fn bwexpander(ar: &mut [i16], mut chirp_q16: i32) {
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+
+    // The rounding of the multiplies is not biased, a bias could make the
+    // filter unstable.
+    let (last, ar) = ar.split_last_mut().unwrap();
+    for a in ar {
+        *a = rshift_round(chirp_q16 * *a as i32, 16) as i16;
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    }
+
+    *last = rshift_round(chirp_q16 * *last as i32, 16) as i16;
+}
+
+/// Computes the Q30 inverse prediction gain of the filter, 0 when the filter
+/// is unstable or too close to it.
+pub fn inverse_pred_gain(a_q12: &[i16]) -> i32 {
+    // An unstable DC response does not need the full computation.
+    if a_q12.iter().map(|a| *a as i32).sum::<i32>() >= 4096 {
+        return 0;
+    }
+
+    let mut a = [0; 16];
+    for (a, x) in a.iter_mut().zip(a_q12) {
+        *a = (*x as i32) << (QA_INV_GAIN - 12);
+    }
+
+    let mut inv_gain_q30 = 1 << 30;
+    for k in (0..a_q12.len()).rev() {
+        if a[k] > A_LIMIT || a[k] < -A_LIMIT {
+            return 0;
+        }
+
+        // The reflection coefficient is the negated AR coefficient.
+        let rc_q31 = -(a[k] << (31 - QA_INV_GAIN));
+        let rc_mult1_q30 = (1 << 30) - smmul(rc_q31, rc_q31);
+
+        inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30) << 2;
+        if inv_gain_q30 < MIN_INV_GAIN_Q30 {
+            return 0;
+        }
+
+        if k == 0 {
+            break;
+        }
+
+        // Step down to the filter of order k
+        let mult2q = 32 - rc_mult1_q30.unsigned_abs().leading_zeros();
+        let rc_mult2 = inverse32_varq(rc_mult1_q30, mult2q + 30) as i64;
+
+        for n in 0..(k + 1) >> 1 {
+            let tmp1 = a[n];
+            let tmp2 = a[k - n - 1];
+
+            let frac = |x: i32| rshift_round64(x as i64 * rc_q31 as i64, 31) as i32;
+            let x = rshift_round64(tmp1.saturating_sub(frac(tmp2)) as i64 * rc_mult2, mult2q);
+            let y = rshift_round64(tmp2.saturating_sub(frac(tmp1)) as i64 * rc_mult2, mult2q);
+
+            let (Ok(x), Ok(y)) = (i32::try_from(x), i32::try_from(y)) else {
+                return 0;
+            };
+
+            a[n] = x;
+            a[k - n - 1] = y;
+        }
+    }
+
+    inv_gain_q30
+}
+
+/// Filters `input` through the whitening filter `b` in Q12, the first
+/// `b.len()` samples of `out` are zeroed.
+pub fn analysis_filter(out: &mut [i16], input: &[i16], b: &[i16]) {
+    let d = b.len();
+
+    out[..d].fill(0);
+    for ix in d..input.len() {
+        // The prediction is allowed to wrap around, the wraps cancel out.
+        let pred_q12 = b.iter().enumerate().fold(0i32, |pred, (j, b)| {
+            pred.wrapping_add(input[ix - j - 1] as i32 * *b as i32)
+        });
+
+        let out_q12 = ((input[ix] as i32) << 12).wrapping_sub(pred_q12);
+        out[ix] = rshift_round(out_q12, 12).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    }
+}
+
+/// Runs the Q14 residual `res` through the synthesis filter `a_q12` and
+/// scales the result by `gain_q10` into `xq`.
+///
+/// `s_lpc` holds the last `MAX_LPC_ORDER` outputs of the filter followed by
+/// room for the subframe.
+pub fn synthesis(s_lpc: &mut [i32], a_q12: &[i16], res: &[i32], gain_q10: i32, xq: &mut [i16]) {
+    for (i, (&res, xq)) in res.iter().zip(xq).enumerate() {
+        // Starting from half the order avoids the bias of the multiplies
+        // always rounding towards -inf.
+        let pred_q10 = a_q12
+            .iter()
+            .enumerate()
+            .fold(a_q12.len() as i32 >> 1, |pred, (j, a)| {
+                smlawb(pred, s_lpc[MAX_LPC_ORDER + i - j - 1], *a as i32)
+            });
+
+        s_lpc[MAX_LPC_ORDER + i] = res.saturating_add(lshift_sat32(pred_q10, 4));
+
+        let out = rshift_round(smulww(s_lpc[MAX_LPC_ORDER + i], gain_q10), 8);
+        *xq = out.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    }
+}
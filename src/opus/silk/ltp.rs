@@ -0,0 +1,336 @@
+//! SILK long-term prediction implementation
+//!
+//! Voiced frames are predicted from the signal one pitch period back by a 5
+//! taps filter. The pitch lag is coded once per frame, either absolutely or
+//! as a delta from the previous frame, and a contour codebook spreads it
+//! over the subframes. The filter taps of every subframe come from one of
+//! three codebooks of increasing periodicity.
+
+use crate::opus::entropy::RangeCodingDecoder;
+
+use super::{
+    CondCoding, MAX_LTP_MEM_LENGTH, MAX_NB_SUBFR, SignalType, SilkChannel, UNIFORM4_MODEL,
+    UNIFORM6_MODEL, UNIFORM8_MODEL,
+    lpc::analysis_filter,
+    math::{smlawb, smulwb, smulww},
+};
+
+/// Number of taps of the long-term prediction filter.
+pub const LTP_ORDER: usize = 5;
+
+/// Range of the pitch lag, in ms.
+const PE_MIN_LAG_MS: i32 = 2;
+const PE_MAX_LAG_MS: i32 = 18;
+
+/// Scaling of the long-term prediction state of the first subframe, in Q14.
+const LTP_SCALES_Q14: [i32; 3] = [15565, 12288, 8192];
+
+pub const PITCH_LAG_MODEL: [usize; 33] = [
+    256, 3, 6, 12, 23, 44, 74, 106, 125, 136, 146, 158, 171, 184, 196, 207, 216, 224, 231, 237,
+    241, 243, 245, 247, 248, 249, 250, 251, 252, 253, 254, 255, 256,
+];
+pub const PITCH_DELTA_MODEL: [usize; 22] = [
+    256, 46, 48, 50, 53, 57, 63, 73, 88, 114, 152, 182, 204, 219, 229, 236, 242, 246, 250, 252,
+    254, 256,
+];
+pub const PITCH_CONTOUR_MODEL: [usize; 35] = [
+    256, 33, 55, 73, 89, 104, 118, 132, 145, 158, 168, 177, 186, 194, 200, 206, 212, 217, 221, 225,
+    229, 232, 235, 238, 240, 242, 244, 246, 248, 250, 252, 253, 254, 255, 256,
+];
+pub const PITCH_CONTOUR_NB_MODEL: [usize; 12] =
+    [256, 68, 80, 101, 118, 137, 159, 189, 213, 230, 246, 256];
+pub const PITCH_CONTOUR_10_MS_MODEL: [usize; 13] = [
+    256, 91, 137, 176, 195, 209, 221, 229, 236, 242, 247, 252, 256,
+];
+pub const PITCH_CONTOUR_10_MS_NB_MODEL: [usize; 4] = [256, 143, 193, 256];
+pub const LTP_PER_INDEX_MODEL: [usize; 4] = [256, 77, 157, 256];
+pub const LTP_GAIN_0_MODEL: [usize; 9] = [256, 185, 200, 213, 226, 235, 244, 250, 256];
+pub const LTP_GAIN_1_MODEL: [usize; 17] = [
+    256, 57, 91, 112, 132, 147, 160, 172, 185, 195, 205, 214, 224, 233, 241, 248, 256,
+];
+pub const LTP_GAIN_2_MODEL: [usize; 33] = [
+    256, 15, 31, 45, 57, 69, 81, 92, 103, 114, 124, 133, 142, 151, 160, 168, 176, 184, 192, 199,
+    206, 212, 218, 223, 227, 232, 236, 240, 244, 247, 251, 254, 256,
+];
+pub const LTP_SCALE_MODEL: [usize; 4] = [256, 128, 192, 256];
+
+const CB_LAGS_STAGE2: [[i8; 11]; 4] = [
+    [0, 2, -1, -1, -1, 0, 0, 1, 1, 0, 1],
+    [0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0],
+    [0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0],
+    [0, -1, 2, 1, 0, 1, 1, 0, 0, -1, -1],
+];
+const CB_LAGS_STAGE3: [[i8; 34]; 4] = [
+    [
+        0, 0, 1, -1, 0, 1, -1, 0, -1, 1, -2, 2, -2, -2, 2, -3, 2, 3, -3, -4, 3, -4, 4, 4, -5, 5,
+        -6, -5, 6, -7, 6, 5, 8, -9,
+    ],
+    [
+        0, 0, 1, 0, 0, 0, 0, 0, 0, 0, -1, 1, 0, 0, 1, -1, 0, 1, -1, -1, 1, -1, 2, 1, -1, 2, -2, -2,
+        2, -2, 2, 2, 3, -3,
+    ],
+    [
+        0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 1, -1, 1, 0, 0, 2, 1, -1, 2, -1, -1, 2, -1, 2, 2,
+        -1, 3, -2, -2, -2, 3,
+    ],
+    [
+        0, 1, 0, 0, 1, 0, 1, -1, 2, -1, 2, -1, 2, 3, -2, 3, -2, -2, 4, 4, -3, 5, -3, -4, 6, -4, 6,
+        5, -5, 8, -6, -5, -7, 9,
+    ],
+];
+const CB_LAGS_STAGE2_10_MS: [[i8; 3]; 2] = [[0, 1, 0], [0, 0, 1]];
+const CB_LAGS_STAGE3_10_MS: [[i8; 12]; 2] = [
+    [0, 0, 1, -1, 1, -1, 2, -2, 2, -2, 3, -3],
+    [0, 1, 0, 1, -1, 2, -1, 2, -2, 3, -2, 3],
+];
+const LTP_GAIN_VQ_0: [[i8; 5]; 8] = [
+    [4, 6, 24, 7, 5],
+    [0, 0, 2, 0, 0],
+    [12, 28, 41, 13, -4],
+    [-9, 15, 42, 25, 14],
+    [1, -2, 62, 41, -9],
+    [-10, 37, 65, -4, 3],
+    [-6, 4, 66, 7, -8],
+    [16, 14, 38, -3, 33],
+];
+const LTP_GAIN_VQ_1: [[i8; 5]; 16] = [
+    [13, 22, 39, 23, 12],
+    [-1, 36, 64, 27, -6],
+    [-7, 10, 55, 43, 17],
+    [1, 1, 8, 1, 1],
+    [6, -11, 74, 53, -9],
+    [-12, 55, 76, -12, 8],
+    [-3, 3, 93, 27, -4],
+    [26, 39, 59, 3, -8],
+    [2, 0, 77, 11, 9],
+    [-8, 22, 44, -6, 7],
+    [40, 9, 26, 3, 9],
+    [-7, 20, 101, -7, 4],
+    [3, -8, 42, 26, 0],
+    [-15, 33, 68, 2, 23],
+    [-2, 55, 46, -2, 15],
+    [3, -1, 21, 16, 41],
+];
+const LTP_GAIN_VQ_2: [[i8; 5]; 32] = [
+    [-6, 27, 61, 39, 5],
+    [-11, 42, 88, 4, 1],
+    [-2, 60, 65, 6, -4],
+    [-1, -5, 73, 56, 1],
+    [-9, 19, 94, 29, -9],
+    [0, 12, 99, 6, 4],
+    [8, -19, 102, 46, -13],
+    [3, 2, 13, 3, 2],
+    [9, -21, 84, 72, -18],
+    [-11, 46, 104, -22, 8],
+    [18, 38, 48, 23, 0],
+    [-16, 70, 83, -21, 11],
+    [5, -11, 117, 22, -8],
+    [-6, 23, 117, -12, 3],
+    [3, -8, 95, 28, 4],
+    [-10, 15, 77, 60, -15],
+    [-1, 4, 124, 2, -4],
+    [3, 38, 84, 24, -25],
+    [2, 13, 42, 13, 31],
+    [21, -4, 56, 46, -1],
+    [-1, 35, 79, -13, 19],
+    [-7, 65, 88, -9, -14],
+    [20, 4, 81, 49, -29],
+    [20, 0, 75, 3, -17],
+    [5, -9, 44, 92, -8],
+    [1, -3, 22, 69, 31],
+    [-6, 95, 41, -12, 5],
+    [39, 67, 16, -4, 1],
+    [0, -6, 120, 55, -36],
+    [-13, 44, 122, 4, -24],
+    [81, 5, 11, 3, 7],
+    [2, 0, 9, 10, 88],
+];
+
+/// Taps of the three periodicity codebooks, in Q7.
+const LTP_GAIN_VQ: [&[[i8; LTP_ORDER]]; 3] = [&LTP_GAIN_VQ_0, &LTP_GAIN_VQ_1, &LTP_GAIN_VQ_2];
+const LTP_GAIN_MODELS: [&[usize]; 3] = [&LTP_GAIN_0_MODEL, &LTP_GAIN_1_MODEL, &LTP_GAIN_2_MODEL];
+
+/// Long-term prediction decoder
+pub struct Ltp;
+
+impl Ltp {
+    /// Decodes the pitch lag, the contour, the filter taps and the scaling
+    /// indices of a voiced frame.
+    pub fn decode(ch: &mut SilkChannel, range_dec: &mut RangeCodingDecoder, cond: CondCoding) {
+        // The lag can be coded as a delta from the previous voiced frame, a
+        // delta of 0 escapes to an absolute lag.
+        let mut delta = 0;
+        if cond == CondCoding::Conditionally && ch.ec_prev_signal_type == SignalType::Voiced {
+            delta = range_dec.icdf(&PITCH_DELTA_MODEL) as i32;
+            if delta > 0 {
+                ch.indices.lag_index = ch.ec_prev_lag_index + delta - 9;
+            }
+        }
+
+        if delta == 0 {
+            let low_bits_model = match ch.fs_khz {
+                8 => &UNIFORM4_MODEL[..],
+                12 => &UNIFORM6_MODEL[..],
+                _ => &UNIFORM8_MODEL[..],
+            };
+
+            ch.indices.lag_index = range_dec.icdf(&PITCH_LAG_MODEL) as i32
+                * (ch.fs_khz as i32 >> 1)
+                + range_dec.icdf(low_bits_model) as i32;
+        }
+
+        ch.ec_prev_lag_index = ch.indices.lag_index;
+
+        let contour_model = match (ch.fs_khz, ch.nb_subfr) {
+            (8, MAX_NB_SUBFR) => &PITCH_CONTOUR_NB_MODEL[..],
+            (8, _) => &PITCH_CONTOUR_10_MS_NB_MODEL[..],
+            (_, MAX_NB_SUBFR) => &PITCH_CONTOUR_MODEL[..],
+            _ => &PITCH_CONTOUR_10_MS_MODEL[..],
+        };
+
+        ch.indices.contour_index = range_dec.icdf(contour_model);
+
+        // The periodicity selects the codebook of the filter taps.
+        ch.indices.per_index = range_dec.icdf(&LTP_PER_INDEX_MODEL);
+        for k in 0..ch.nb_subfr {
+            ch.indices.ltp_index[k] = range_dec.icdf(LTP_GAIN_MODELS[ch.indices.per_index]);
+        }
+
+        // The scaling is only coded for frames that do not depend on the
+        // previous one.
+        ch.indices.ltp_scale_index = if cond == CondCoding::Independently {
+            range_dec.icdf(&LTP_SCALE_MODEL)
+        } else {
+            0
+        };
+    }
+
+    /// Turns the indices into the pitch lag and the Q14 filter taps of every
+    /// subframe, and the Q14 scaling of the prediction state.
+    pub fn dequant(ch: &mut SilkChannel) {
+        if ch.indices.signal_type != SignalType::Voiced {
+            ch.pitch_lags = [0; MAX_NB_SUBFR];
+            ch.ltp_coef = [0; LTP_ORDER * MAX_NB_SUBFR];
+            ch.indices.per_index = 0;
+            ch.ltp_scale = 0;
+            return;
+        }
+
+        // The contour codebook offsets the lag of each subframe.
+        let fs_khz = ch.fs_khz as i32;
+        let min_lag = PE_MIN_LAG_MS * fs_khz;
+        let max_lag = PE_MAX_LAG_MS * fs_khz;
+        let lag = min_lag + ch.indices.lag_index;
+        let contour = ch.indices.contour_index;
+
+        for k in 0..ch.nb_subfr {
+            let offset = match (fs_khz, ch.nb_subfr) {
+                (8, MAX_NB_SUBFR) => CB_LAGS_STAGE2[k][contour],
+                (8, _) => CB_LAGS_STAGE2_10_MS[k][contour],
+                (_, MAX_NB_SUBFR) => CB_LAGS_STAGE3[k][contour],
+                _ => CB_LAGS_STAGE3_10_MS[k][contour],
+            };
+
+            ch.pitch_lags[k] = (lag + offset as i32).clamp(min_lag, max_lag);
+        }
+
+        let codebook = LTP_GAIN_VQ[ch.indices.per_index];
+        for k in 0..ch.nb_subfr {
+            let taps = &codebook[ch.indices.ltp_index[k]];
+            for (coef, tap) in ch.ltp_coef[k * LTP_ORDER..].iter_mut().zip(taps) {
+                *coef = (*tap as i16) << 7;
+            }
+        }
+
+        ch.ltp_scale = LTP_SCALES_Q14[ch.indices.ltp_scale_index];
+    }
+
+    /// Adds the long-term prediction of subframe `k` to its excitation `res`.
+    ///
+    /// The prediction state `s_ltp` holds the Q15 past residual up to
+    /// `*idx`. At the start of a frame, and at the third subframe when the
+    /// LPC filter changes, it is rebuilt by whitening the past output with
+    /// the current LPC filter, `xq` being the output of the frame so far.
+    #[allow(clippy::too_many_arguments)]
+    pub fn synthesis(
+        ch: &mut SilkChannel,
+        k: usize,
+        xq: &[i16],
+        s_ltp: &mut [i32],
+        idx: &mut usize,
+        mut inv_gain_q31: i32,
+        gain_adj_q16: i32,
+        res: &mut [i32],
+    ) {
+        let lag = ch.pitch_lags[k] as usize;
+        let ltp_mem_length = ch.ltp_mem_length;
+        let subfr_length = ch.subfr_length;
+
+        if k == 0 || (k == 2 && ch.indices.nlsf_interp_coef < 4) {
+            let start = ltp_mem_length - lag - ch.lpc_order - LTP_ORDER / 2;
+            if k == 2 {
+                ch.out_buf[ltp_mem_length..][..2 * subfr_length]
+                    .copy_from_slice(&xq[..2 * subfr_length]);
+            }
+
+            let mut whitened = [0; MAX_LTP_MEM_LENGTH];
+            analysis_filter(
+                &mut whitened[start..ltp_mem_length],
+                &ch.out_buf[start + k * subfr_length..][..ltp_mem_length - start],
+                &ch.lpc[k >> 1][..ch.lpc_order],
+            );
+
+            // The state of the first subframe is scaled down to reduce the
+            // dependency on the previous packet.
+            if k == 0 {
+                inv_gain_q31 = smulwb(inv_gain_q31, ch.ltp_scale) << 2;
+            }
+
+            for i in 0..lag + LTP_ORDER / 2 {
+                s_ltp[*idx - i - 1] = smulwb(inv_gain_q31, whitened[ltp_mem_length - i - 1] as i32);
+            }
+        } else if gain_adj_q16 != 1 << 16 {
+            // The state follows the gain changes.
+            for i in 0..lag + LTP_ORDER / 2 {
+                s_ltp[*idx - i - 1] = smulww(gain_adj_q16, s_ltp[*idx - i - 1]);
+            }
+        }
+
+        let taps = &ch.ltp_coef[k * LTP_ORDER..][..LTP_ORDER];
+        for res in res.iter_mut().take(subfr_length) {
+            // Starts at 2 to avoid the bias of rounding towards -inf
+            let pred_q13 = taps.iter().enumerate().fold(2, |pred, (j, tap)| {
+                smlawb(pred, s_ltp[*idx + LTP_ORDER / 2 - lag - j], *tap as i32)
+            });
+
+            *res = res.wrapping_add(pred_q13 << 1);
+            s_ltp[*idx] = *res << 1;
+            *idx += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ltp, SignalType, SilkChannel};
+
+    #[test]
+    fn pitch_lags() {
+        let mut ch = SilkChannel {
+            fs_khz: 16,
+            nb_subfr: 4,
+            ..Default::default()
+        };
+
+        // The lags are offset by the contour and clamped to 2-18 ms.
+        ch.indices.signal_type = SignalType::Voiced;
+        ch.indices.lag_index = 100;
+        ch.indices.contour_index = 33;
+        Ltp::dequant(&mut ch);
+        assert_eq!(ch.pitch_lags, [132 - 9, 132 - 3, 132 + 3, 132 + 9]);
+
+        ch.indices.lag_index = 255;
+        Ltp::dequant(&mut ch);
+        assert_eq!(ch.pitch_lags, [287 - 9, 287 - 3, 288, 288]);
+    }
+}
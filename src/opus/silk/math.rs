@@ -0,0 +1,173 @@
+//! SILK fixed-point arithmetic
+//!
+//! The SILK decoder is entirely specified in fixed-point, these are the
+//! helpers it is written with. The `w` variants multiply by a Q16 value and
+//! the `b` variants by the bottom 16 bits of the second operand.
+
+/// Multiplies the bottom 16 bits of both values.
+pub fn smulbb(a: i32, b: i32) -> i32 {
+    a as i16 as i32 * b as i16 as i32
+}
+
+/// `(a * b[15:0]) >> 16`
+pub fn smulwb(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i16 as i64) >> 16) as i32
+}
+
+/// `a + ((b * c[15:0]) >> 16)`
+pub fn smlawb(a: i32, b: i32, c: i32) -> i32 {
+    a.wrapping_add(smulwb(b, c))
+}
+
+/// Approximation of `2^(x / 128)`.
+pub fn log2lin(x: i32) -> i32 {
+    if x < 0 {
+        return 0;
+    } else if x >= 3967 {
+        return i32::MAX;
+    }
+
+    let out = 1 << (x >> 7);
+    let frac = x & 0x7f;
+
+    // Piece-wise parabolic approximation
+    let frac = smlawb(frac, smulbb(frac, 128 - frac), -174);
+    if x < 2048 {
+        out + ((out * frac) >> 7)
+    } else {
+        out + (out >> 7) * frac
+    }
+}
+
+/// `(a * b) >> 16`
+pub fn smulww(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i64) >> 16) as i32
+}
+
+/// Multiplies the top 16 bits of both values.
+pub fn smultt(a: i32, b: i32) -> i32 {
+    (a >> 16) * (b >> 16)
+}
+
+/// `(a * b) >> 32`
+pub fn smmul(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i64) >> 32) as i32
+}
+
+/// Linear congruential pseudo random generator
+const RAND_MULTIPLIER: i32 = 196314165;
+const RAND_INCREMENT: i32 = 907633515;
+
+/// Next value of the pseudo random generator.
+pub fn rand(seed: i32) -> i32 {
+    RAND_INCREMENT.wrapping_add(seed.wrapping_mul(RAND_MULTIPLIER))
+}
+
+/// Approximation of the square root, within 2.5% above 120.
+pub fn sqrt_approx(x: i32) -> i32 {
+    if x <= 0 {
+        return 0;
+    }
+
+    // The leading zeros and the 7 bits following the leading one
+    let lz = x.leading_zeros() as i32;
+    let frac_q7 = (x as u32).rotate_right((24 - lz).rem_euclid(32) as u32) as i32 & 0x7f;
+
+    // sqrt(2) * 32768 for an even number of leading zeros
+    let y = if lz & 1 != 0 { 32768 } else { 46214 };
+    let y = y >> (lz >> 1);
+    smlawb(y, y, smulbb(213, frac_q7))
+}
+
+/// Energy of `x`, shifted right to fit in an `i32` with two bits of
+/// headroom. Returns the energy and the shift.
+pub fn sum_sqr_shift(x: &[i16]) -> (i32, u32) {
+    let sum = |shift: u32| {
+        x.chunks(2).fold(0u32, |nrg, x| {
+            let tmp = x.iter().fold(0u32, |tmp, x| {
+                tmp.wrapping_add((*x as i32 * *x as i32) as u32)
+            });
+
+            nrg.wrapping_add(tmp >> shift)
+        })
+    };
+
+    // A first pass with the largest shift the length may need, starting
+    // from the length to be conservative with the rounding
+    let shift = 31 - (x.len() as u32).leading_zeros();
+    let nrg = sum(shift).wrapping_add(x.len() as u32);
+
+    let shift = (shift + 3).saturating_sub((nrg as i32).leading_zeros());
+    (sum(shift) as i32, shift)
+}
+
+/// Right shift with rounding to nearest.
+pub fn rshift_round(a: i32, shift: u32) -> i32 {
+    if shift == 1 {
+        (a >> 1) + (a & 1)
+    } else {
+        ((a >> (shift - 1)) + 1) >> 1
+    }
+}
+
+/// Right shift with rounding to nearest of a 64-bit value.
+pub fn rshift_round64(a: i64, shift: u32) -> i64 {
+    if shift == 1 {
+        (a >> 1) + (a & 1)
+    } else {
+        ((a >> (shift - 1)) + 1) >> 1
+    }
+}
+
+/// Left shift saturating to the range of an `i32`.
+pub fn lshift_sat32(a: i32, shift: u32) -> i32 {
+    a.clamp(i32::MIN >> shift, i32::MAX >> shift) << shift
+}
+
+/// Approximation of `(1 << q) / b`.
+pub fn inverse32_varq(b: i32, q: u32) -> i32 {
+    // Normalize the input and take its inverse with 14 bits of precision
+    let b_headrm = b.unsigned_abs().leading_zeros() - 1;
+    let b_nrm = b << b_headrm;
+    let b_inv = (i32::MAX >> 2) / (b_nrm >> 16);
+
+    // Refine the first approximation with the residual
+    let result = b_inv << 16;
+    let err_q32 = ((1 << 29) - smulwb(b_nrm, b_inv)) << 3;
+    let result = result.wrapping_add(smulww(err_q32, b_inv));
+
+    let lshift = 61 - b_headrm as i32 - q as i32;
+    if lshift <= 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result >> lshift
+    } else {
+        0
+    }
+}
+
+/// Approximation of `(a << q) / b`.
+pub fn div32_varq(a: i32, b: i32, q: u32) -> i32 {
+    // Normalize the inputs and take the inverse of `b` with 14 bits of
+    // precision
+    let a_headrm = a.unsigned_abs().leading_zeros() - 1;
+    let a_nrm = a << a_headrm;
+    let b_headrm = b.unsigned_abs().leading_zeros() - 1;
+    let b_nrm = b << b_headrm;
+    let b_inv = (i32::MAX >> 2) / (b_nrm >> 16);
+
+    // Refine the first approximation with the residual, which is small
+    // enough for the wrapping arithmetic
+    let result = smulwb(a_nrm, b_inv);
+    let a_nrm = a_nrm.wrapping_sub(smmul(b_nrm, result).wrapping_shl(3));
+    let result = smlawb(result, a_nrm, b_inv);
+
+    let lshift = 29 + a_headrm as i32 - b_headrm as i32 - q as i32;
+    if lshift < 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result >> lshift
+    } else {
+        0
+    }
+}
@@ -0,0 +1,678 @@
+mod cng;
+mod excitation;
+mod gains;
+mod lpc;
+mod ltp;
+mod math;
+mod nlsf;
+mod plc;
+mod resampler;
+mod stereo;
+
+use super::{
+    entropy::RangeCodingDecoder,
+    toc::{Bandwidth, Channels, FrameDuration, TableOfContents},
+};
+
+use self::{
+    cng::Cng,
+    excitation::Excitation,
+    gains::Gains,
+    ltp::{LTP_ORDER, Ltp},
+    math::{div32_varq, inverse32_varq, smulww},
+    nlsf::Nlsf,
+    plc::Plc,
+    resampler::{MAX_FS_OUT_KHZ, Resampler},
+    stereo::Stereo,
+};
+
+pub const MAX_NB_SUBFR: usize = 4;
+pub const MAX_FRAMES_PER_PACKET: usize = 3;
+pub const SUB_FRAME_LENGTH_MS: usize = 5;
+pub const MAX_FS_KHZ: usize = 16;
+pub const MAX_SUB_FRAME_LENGTH: usize = SUB_FRAME_LENGTH_MS * MAX_FS_KHZ;
+pub const MAX_FRAME_LENGTH: usize = MAX_SUB_FRAME_LENGTH * MAX_NB_SUBFR;
+pub const LTP_MEM_LENGTH_MS: usize = 20;
+pub const MAX_LTP_MEM_LENGTH: usize = LTP_MEM_LENGTH_MS * MAX_FS_KHZ;
+pub const MIN_LPC_ORDER: usize = 10;
+pub const MAX_LPC_ORDER: usize = 16;
+
+pub const LBRR_FLAGS_2_MODEL: [usize; 4] = [256, 53, 106, 256];
+pub const LBRR_FLAGS_3_MODEL: [usize; 8] = [256, 41, 61, 90, 131, 146, 174, 256];
+pub const TYPE_OFFSET_VAD_MODEL: [usize; 5] = [256, 24, 98, 246, 256];
+pub const TYPE_OFFSET_NO_VAD_MODEL: [usize; 3] = [256, 26, 256];
+pub const UNIFORM4_MODEL: [usize; 5] = [256, 64, 128, 192, 256];
+pub const UNIFORM6_MODEL: [usize; 7] = [256, 43, 85, 128, 171, 213, 256];
+pub const UNIFORM8_MODEL: [usize; 9] = [256, 32, 64, 96, 128, 160, 192, 224, 256];
+
+/// Kind of signal coded in a frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SignalType {
+    #[default]
+    Inactive,
+    Unvoiced,
+    Voiced,
+}
+
+/// How the parameters of a frame depend on the previous frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CondCoding {
+    Independently,
+    IndependentlyNoLtpScaling,
+    Conditionally,
+}
+
+/// Quantization indices of the side information of a frame
+#[derive(Debug, Default)]
+pub struct SideInfo {
+    signal_type: SignalType,
+    quant_offset_type: usize,
+    gains: [i32; MAX_NB_SUBFR],
+    /// First stage codebook index followed by the residual indices.
+    nlsf: [i32; MAX_LPC_ORDER + 1],
+    /// Interpolation factor of the NLSFs of the first half of the frame, in
+    /// Q2, 4 meaning no interpolation.
+    nlsf_interp_coef: i32,
+    /// Pitch lag, from the shortest lag.
+    lag_index: i32,
+    contour_index: usize,
+    /// Periodicity, the codebook of the LTP filter taps.
+    per_index: usize,
+    ltp_index: [usize; MAX_NB_SUBFR],
+    ltp_scale_index: usize,
+    /// Seed of the pseudo random dithering of the excitation.
+    seed: i32,
+}
+
+/// State of the SILK decoder for a single channel
+#[derive(Debug)]
+pub struct SilkChannel {
+    /// Internal sample rate in kHz, 0 until the first frame is decoded.
+    fs_khz: usize,
+    nb_subfr: usize,
+    frame_length: usize,
+    subfr_length: usize,
+    frames_per_packet: usize,
+    frames_decoded: usize,
+    vad_flags: [bool; MAX_FRAMES_PER_PACKET],
+    lbrr_flag: bool,
+    lbrr_flags: [bool; MAX_FRAMES_PER_PACKET],
+    indices: SideInfo,
+    /// Gain index of the last subframe of the previous frame.
+    last_gain_index: i32,
+    /// Q16 gain of every subframe.
+    gains: [i32; MAX_NB_SUBFR],
+    lpc_order: usize,
+    /// Set when the state has just been reset, the previous frame cannot be
+    /// relied on.
+    first_frame_after_reset: bool,
+    /// Q15 NLSFs of the previous frame.
+    prev_nlsf: [i16; MAX_LPC_ORDER],
+    /// Q12 LPC coefficients of both halves of the frame.
+    lpc: [[i16; MAX_LPC_ORDER]; 2],
+    /// Signal type and lag index of the previous frame, the lag can be
+    /// coded relative to it.
+    ec_prev_signal_type: SignalType,
+    ec_prev_lag_index: i32,
+    /// Pitch lag of every subframe.
+    pitch_lags: [i32; MAX_NB_SUBFR],
+    /// Q14 LTP filter taps of every subframe.
+    ltp_coef: [i16; LTP_ORDER * MAX_NB_SUBFR],
+    /// Q14 scaling of the LTP state at the start of the frame.
+    ltp_scale: i32,
+    ltp_mem_length: usize,
+    /// Q16 gain of the last subframe of the previous frame.
+    prev_gain_q16: i32,
+    /// Pitch lag of the last subframe of the previous frame.
+    lag_prev: i32,
+    /// Q14 excitation of the frame.
+    exc_q14: [i32; MAX_FRAME_LENGTH],
+    /// Q14 state of the LPC synthesis filter.
+    s_lpc_q14: [i32; MAX_LPC_ORDER],
+    /// Past output, whitened to rebuild the state of the long-term
+    /// prediction.
+    out_buf: [i16; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+    /// Number of frames lost in a row.
+    loss_count: usize,
+    /// Signal type of the last decoded frame.
+    prev_signal_type: SignalType,
+    plc: Plc,
+    cng: Cng,
+    resampler: Resampler,
+}
+
+impl Default for SilkChannel {
+    fn default() -> Self {
+        Self {
+            fs_khz: 0,
+            nb_subfr: 0,
+            frame_length: 0,
+            subfr_length: 0,
+            frames_per_packet: 0,
+            frames_decoded: 0,
+            vad_flags: [false; MAX_FRAMES_PER_PACKET],
+            lbrr_flag: false,
+            lbrr_flags: [false; MAX_FRAMES_PER_PACKET],
+            indices: SideInfo::default(),
+            last_gain_index: 0,
+            gains: [0; MAX_NB_SUBFR],
+            lpc_order: 0,
+            first_frame_after_reset: true,
+            prev_nlsf: [0; MAX_LPC_ORDER],
+            lpc: [[0; MAX_LPC_ORDER]; 2],
+            ec_prev_signal_type: SignalType::Inactive,
+            ec_prev_lag_index: 0,
+            pitch_lags: [0; MAX_NB_SUBFR],
+            ltp_coef: [0; LTP_ORDER * MAX_NB_SUBFR],
+            ltp_scale: 0,
+            ltp_mem_length: 0,
+            prev_gain_q16: 1 << 16,
+            lag_prev: 0,
+            exc_q14: [0; MAX_FRAME_LENGTH],
+            s_lpc_q14: [0; MAX_LPC_ORDER],
+            out_buf: [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+            loss_count: 0,
+            prev_signal_type: SignalType::Inactive,
+            plc: Plc::default(),
+            cng: Cng::default(),
+            resampler: Resampler::default(),
+        }
+    }
+}
+
+impl SilkChannel {
+    /// Sets up the frame layout for `fs_khz`, resetting the prediction state
+    /// of the channel when the internal sample rate changes. The output is
+    /// resampled to `fs_out_khz`.
+    fn set_fs(&mut self, fs_khz: usize, fs_out_khz: usize) {
+        self.subfr_length = SUB_FRAME_LENGTH_MS * fs_khz;
+        self.frame_length = self.nb_subfr * self.subfr_length;
+
+        if self.fs_khz != fs_khz {
+            self.lpc_order = if fs_khz == 16 {
+                MAX_LPC_ORDER
+            } else {
+                MIN_LPC_ORDER
+            };
+
+            self.ltp_mem_length = LTP_MEM_LENGTH_MS * fs_khz;
+            self.fs_khz = fs_khz;
+            self.resampler = Resampler::new(fs_khz, fs_out_khz);
+            self.reset();
+        }
+    }
+
+    /// Resets the prediction state of the channel.
+    fn reset(&mut self) {
+        self.out_buf.fill(0);
+        self.s_lpc_q14.fill(0);
+        self.lag_prev = 100;
+        self.last_gain_index = 10;
+        self.prev_signal_type = SignalType::Inactive;
+        self.first_frame_after_reset = true;
+    }
+
+    /// Decodes the quantization indices of the side information of a frame.
+    fn decode_indices(
+        &mut self,
+        range_dec: &mut RangeCodingDecoder,
+        frame: usize,
+        lbrr: bool,
+        cond: CondCoding,
+    ) {
+        // The signal type and the quantizer offset are coded jointly, only an
+        // active frame can be voiced.
+        let ix = if lbrr || self.vad_flags[frame] {
+            range_dec.icdf(&TYPE_OFFSET_VAD_MODEL) + 2
+        } else {
+            range_dec.icdf(&TYPE_OFFSET_NO_VAD_MODEL)
+        };
+
+        self.indices.signal_type = match ix >> 1 {
+            0 => SignalType::Inactive,
+            1 => SignalType::Unvoiced,
+            _ => SignalType::Voiced,
+        };
+
+        self.indices.quant_offset_type = ix & 1;
+
+        Gains::decode(self, range_dec, cond);
+        Nlsf::decode(self, range_dec);
+
+        if self.indices.signal_type == SignalType::Voiced {
+            Ltp::decode(self, range_dec, cond);
+        }
+
+        self.ec_prev_signal_type = self.indices.signal_type;
+        self.indices.seed = range_dec.icdf(&UNIFORM4_MODEL) as i32;
+    }
+
+    /// Decodes a frame of the channel into `xq`.
+    fn decode_frame(
+        &mut self,
+        range_dec: &mut RangeCodingDecoder,
+        cond: CondCoding,
+        xq: &mut [i16],
+    ) {
+        self.decode_indices(range_dec, self.frames_decoded, false, cond);
+
+        let mut pulses = [0; MAX_FRAME_LENGTH];
+        Excitation::decode(self, range_dec, &mut pulses);
+
+        Gains::dequant(self, cond);
+        Nlsf::dequant(self);
+        Ltp::dequant(self);
+        Excitation::dequant(self, &pulses);
+
+        self.decode_core(xq);
+        Plc::update(self);
+
+        self.loss_count = 0;
+        self.prev_signal_type = self.indices.signal_type;
+        self.first_frame_after_reset = false;
+
+        self.finish_frame(xq);
+    }
+
+    /// Conceals a lost frame of the channel into `xq`.
+    fn conceal_frame(&mut self, xq: &mut [i16]) {
+        Plc::conceal(self, xq);
+        self.loss_count += 1;
+
+        self.finish_frame(xq);
+    }
+
+    /// Updates the state of the channel with the frame `xq`, decoded or
+    /// concealed.
+    fn finish_frame(&mut self, xq: &mut [i16]) {
+        let xq = &mut xq[..self.frame_length];
+
+        // Keep the last output samples for the long-term prediction of the
+        // next frame.
+        let mv_len = self.ltp_mem_length - self.frame_length;
+        self.out_buf
+            .copy_within(self.frame_length..self.ltp_mem_length, 0);
+        self.out_buf[mv_len..self.ltp_mem_length].copy_from_slice(xq);
+
+        Cng::process(self, xq);
+        Plc::glue_frames(self, xq);
+
+        self.lag_prev = self.pitch_lags[self.nb_subfr - 1];
+    }
+
+    /// Reconstructs the frame from its excitation into `xq`.
+    fn decode_core(&mut self, xq: &mut [i16]) {
+        let subfr_length = self.subfr_length;
+
+        // The LTP state holds the past residual followed by the residual of
+        // the frame.
+        let mut s_ltp = [0; MAX_LTP_MEM_LENGTH + MAX_FRAME_LENGTH];
+        let mut s_ltp_idx = self.ltp_mem_length;
+        let mut res = [0; MAX_SUB_FRAME_LENGTH];
+
+        // The LPC state holds the last outputs of the synthesis filter
+        // followed by the subframe.
+        let mut s_lpc = [0; MAX_LPC_ORDER + MAX_SUB_FRAME_LENGTH];
+        s_lpc[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14);
+
+        for k in 0..self.nb_subfr {
+            let gain_q16 = self.gains[k];
+            let inv_gain_q31 = inverse32_varq(gain_q16, 47);
+
+            // The states are scaled by the gain change.
+            let gain_adj_q16 = if gain_q16 != self.prev_gain_q16 {
+                let gain_adj_q16 = div32_varq(self.prev_gain_q16, gain_q16, 16);
+                for x in &mut s_lpc[..MAX_LPC_ORDER] {
+                    *x = smulww(gain_adj_q16, *x);
+                }
+
+                gain_adj_q16
+            } else {
+                1 << 16
+            };
+
+            self.prev_gain_q16 = gain_q16;
+
+            let res = &mut res[..subfr_length];
+            res.copy_from_slice(&self.exc_q14[k * subfr_length..][..subfr_length]);
+
+            // An unvoiced frame after a lost voiced one still starts from the
+            // pitch pulses of the concealment, through a single tap at the
+            // previous lag.
+            let mut voiced = self.indices.signal_type == SignalType::Voiced;
+            if self.loss_count > 0
+                && self.prev_signal_type == SignalType::Voiced
+                && !voiced
+                && k < 2
+            {
+                self.ltp_coef[k * LTP_ORDER..][..LTP_ORDER].copy_from_slice(&[0, 0, 4096, 0, 0]);
+                self.pitch_lags[k] = self.lag_prev;
+                voiced = true;
+            }
+
+            if voiced {
+                Ltp::synthesis(
+                    self,
+                    k,
+                    xq,
+                    &mut s_ltp,
+                    &mut s_ltp_idx,
+                    inv_gain_q31,
+                    gain_adj_q16,
+                    res,
+                );
+            }
+
+            lpc::synthesis(
+                &mut s_lpc[..MAX_LPC_ORDER + subfr_length],
+                &self.lpc[k >> 1][..self.lpc_order],
+                res,
+                gain_q16 >> 6,
+                &mut xq[k * subfr_length..][..subfr_length],
+            );
+
+            s_lpc.copy_within(subfr_length..subfr_length + MAX_LPC_ORDER, 0);
+        }
+
+        self.s_lpc_q14.copy_from_slice(&s_lpc[..MAX_LPC_ORDER]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SilkFrameDecodeError {
+    /// SILK frames are 10, 20, 40 or 60 ms long.
+    InvalidFrameDuration,
+}
+
+#[derive(Debug, Default)]
+pub struct SilkFrameDecoder {
+    /// Channels of the output of the decoder.
+    output_channels: Channels,
+    /// Sample rate of the output in kHz.
+    fs_out_khz: usize,
+    /// Channels coded in the previous frame.
+    stream_channels: Channels,
+    channels: [SilkChannel; 2],
+    stereo: Stereo,
+    /// Whether only the mid channel was coded in the previous frame.
+    prev_decode_only_middle: bool,
+}
+
+impl SilkFrameDecoder {
+    /// Creates a decoder for an output of `channels` channels at
+    /// `sample_rate` Hz.
+    pub fn new(sample_rate: u32, channels: Channels) -> Self {
+        Self {
+            output_channels: channels,
+            fs_out_khz: sample_rate as usize / 1000,
+            ..Default::default()
+        }
+    }
+
+    /// Resets the decoder to its initial state, for a stream switching back
+    /// from CELT-only frames.
+    pub fn reset(&mut self) {
+        *self = Self {
+            stream_channels: self.stream_channels,
+            ..Self::new(self.fs_out_khz as u32 * 1000, self.output_channels)
+        };
+    }
+
+    /// Decodes the SILK frames of an Opus frame into `output`, as samples at
+    /// the output rate in the [-1, 1] range, one buffer per coded channel.
+    pub fn decode(
+        &mut self,
+        toc: &TableOfContents,
+        range_dec: &mut RangeCodingDecoder,
+        output: &mut [Vec<f32>],
+    ) -> Result<(), SilkFrameDecodeError> {
+        // An Opus frame of 40 or 60 ms carries several 20 ms SILK frames, a
+        // 10 ms frame is made of only two subframes.
+        let (frames_per_packet, nb_subfr) = match toc.duration {
+            FrameDuration::Medium => (1, MAX_NB_SUBFR / 2),
+            FrameDuration::Standard => (1, MAX_NB_SUBFR),
+            FrameDuration::Long => (2, MAX_NB_SUBFR),
+            FrameDuration::VeryLong => (3, MAX_NB_SUBFR),
+            _ => return Err(SilkFrameDecodeError::InvalidFrameDuration),
+        };
+
+        // The SILK layer of a Hybrid frame is always wideband.
+        let fs_khz = match toc.bandwidth {
+            Bandwidth::Narrow => 8,
+            Bandwidth::Medium => 12,
+            _ => 16,
+        };
+
+        // The side channel starts from a clean state when a stream switches
+        // from mono to stereo.
+        let stereo = toc.channels == Channels::Stereo;
+        let mono_to_stereo = stereo && self.stream_channels == Channels::Mono;
+        if mono_to_stereo {
+            self.channels[1] = SilkChannel::default();
+        }
+
+        self.stream_channels = toc.channels;
+
+        let stream_channels = toc.channels as usize;
+        for ch in self.channels.iter_mut().take(stream_channels) {
+            ch.frames_per_packet = frames_per_packet;
+            ch.frames_decoded = 0;
+            ch.nb_subfr = nb_subfr;
+            ch.set_fs(fs_khz, self.fs_out_khz);
+        }
+
+        // The right channel picks up from the resampler of the mono output.
+        if mono_to_stereo && self.output_channels == Channels::Stereo {
+            self.stereo.reset_side();
+            self.channels[1].resampler = self.channels[0].resampler.clone();
+        }
+
+        // The VAD flags of every frame and the LBRR flag of every channel
+        for ch in self.channels.iter_mut().take(stream_channels) {
+            for flag in ch.vad_flags.iter_mut().take(frames_per_packet) {
+                *flag = range_dec.logp(1);
+            }
+
+            ch.lbrr_flag = range_dec.logp(1);
+        }
+
+        // Which frames have LBRR data, only coded for packets of more than
+        // one frame.
+        for ch in self.channels.iter_mut().take(stream_channels) {
+            ch.lbrr_flags = [false; MAX_FRAMES_PER_PACKET];
+            if ch.lbrr_flag {
+                if frames_per_packet == 1 {
+                    ch.lbrr_flags[0] = true;
+                } else {
+                    let symbol = range_dec.icdf(if frames_per_packet == 2 {
+                        &LBRR_FLAGS_2_MODEL[..]
+                    } else {
+                        &LBRR_FLAGS_3_MODEL[..]
+                    }) + 1;
+
+                    for (i, flag) in ch.lbrr_flags.iter_mut().enumerate() {
+                        *flag = symbol >> i & 1 != 0;
+                    }
+                }
+            }
+        }
+
+        // The LBRR frames are only of use to conceal a lost packet, they are
+        // decoded to be skipped over.
+        let mut pulses = [0; MAX_FRAME_LENGTH];
+        for i in 0..frames_per_packet {
+            for n in 0..stream_channels {
+                if !self.channels[n].lbrr_flags[i] {
+                    continue;
+                }
+
+                if stereo && n == 0 {
+                    Stereo::decode_pred(range_dec);
+                    if !self.channels[1].lbrr_flags[i] {
+                        Stereo::decode_mid_only(range_dec);
+                    }
+                }
+
+                let ch = &mut self.channels[n];
+                let cond = if i > 0 && ch.lbrr_flags[i - 1] {
+                    CondCoding::Conditionally
+                } else {
+                    CondCoding::Independently
+                };
+
+                ch.decode_indices(range_dec, i, true, cond);
+                Excitation::decode(ch, range_dec, &mut pulses);
+            }
+        }
+
+        for samples in output.iter_mut() {
+            samples.clear();
+        }
+
+        for i in 0..frames_per_packet {
+            // The prediction weights of the side channel, an inactive side
+            // channel may not be coded at all.
+            let mut pred_q13 = [0; 2];
+            let mut decode_only_middle = false;
+            if stereo {
+                pred_q13 = Stereo::decode_pred(range_dec);
+                if !self.channels[1].vad_flags[i] {
+                    decode_only_middle = Stereo::decode_mid_only(range_dec);
+                }
+            }
+
+            // The side channel cannot predict from frames it skipped.
+            if stereo && !decode_only_middle && self.prev_decode_only_middle {
+                self.channels[1].reset();
+            }
+
+            // Every frame goes after the last two samples of the previous
+            // one.
+            let mut xq = [[0; MAX_FRAME_LENGTH + 2]; 2];
+            for (n, ch) in self.channels.iter_mut().take(stream_channels).enumerate() {
+                if n == 0 || !decode_only_middle {
+                    let cond = if i == 0 {
+                        CondCoding::Independently
+                    } else if n > 0 && self.prev_decode_only_middle {
+                        // The LTP state of a side channel that skipped a
+                        // frame is well-defined, it does not need scaling.
+                        CondCoding::IndependentlyNoLtpScaling
+                    } else {
+                        CondCoding::Conditionally
+                    };
+
+                    ch.decode_frame(range_dec, cond, &mut xq[n][2..]);
+                }
+
+                ch.frames_decoded += 1;
+            }
+
+            self.prev_decode_only_middle = decode_only_middle;
+            self.output(&mut xq, pred_q13, output);
+        }
+
+        // A stereo stream decoded to mono only keeps its mid channel, on both
+        // sides for the downmix to leave it intact.
+        if stereo && self.output_channels == Channels::Mono {
+            output[1] = output[0].clone();
+        }
+
+        Ok(())
+    }
+
+    /// Conceals a lost SILK frame of `duration` into `output`, one buffer
+    /// per channel of the previous frame, from the state of the previous
+    /// frames.
+    ///
+    /// Lost frames are concealed 10 or 20 ms at a time, the concealment of a
+    /// shorter frame is 10 ms long.
+    pub fn conceal(&mut self, duration: FrameDuration, output: &mut [Vec<f32>]) {
+        for samples in output.iter_mut() {
+            samples.clear();
+        }
+
+        // Nothing to conceal from before the first frame.
+        let fs_khz = self.channels[0].fs_khz;
+        if fs_khz == 0 {
+            return;
+        }
+
+        let nb_subfr = if duration == FrameDuration::Standard {
+            MAX_NB_SUBFR
+        } else {
+            MAX_NB_SUBFR / 2
+        };
+
+        let stream_channels = self.stream_channels as usize;
+        for ch in self.channels.iter_mut().take(stream_channels) {
+            ch.frames_per_packet = 1;
+            ch.frames_decoded = 0;
+            ch.nb_subfr = nb_subfr;
+            ch.set_fs(fs_khz, self.fs_out_khz);
+        }
+
+        // The side channel is only concealed if it was coded in the previous
+        // frame, it keeps the prediction weights of that frame.
+        let stereo = self.stream_channels == Channels::Stereo;
+        let has_side = !self.prev_decode_only_middle;
+        if stereo && !has_side {
+            self.channels[1].reset();
+        }
+
+        let mut xq = [[0; MAX_FRAME_LENGTH + 2]; 2];
+        for (n, ch) in self.channels.iter_mut().take(stream_channels).enumerate() {
+            if n == 0 || has_side {
+                ch.conceal_frame(&mut xq[n][2..]);
+            }
+
+            ch.frames_decoded += 1;
+
+            // The gains are not limited against the concealed ones, which
+            // would keep a fading level from going back down.
+            ch.last_gain_index = 10;
+        }
+
+        let pred_q13 = self.stereo.pred_prev();
+        self.output(&mut xq, pred_q13, output);
+
+        for n in 1..output.len() {
+            if output[n].is_empty() {
+                output[n] = output[0].clone();
+            }
+        }
+    }
+
+    /// Converts the frame `xq` of every coded channel to the channels of the
+    /// output and appends it to `output` at the output sample rate.
+    fn output(
+        &mut self,
+        xq: &mut [[i16; MAX_FRAME_LENGTH + 2]; 2],
+        pred_q13: [i32; 2],
+        output: &mut [Vec<f32>],
+    ) {
+        let fs_khz = self.channels[0].fs_khz;
+        let frame_length = self.channels[0].frame_length;
+        let [x1, x2] = xq;
+        let channels = if self.stream_channels == Channels::Stereo
+            && self.output_channels == Channels::Stereo
+        {
+            self.stereo.ms_to_lr(x1, x2, pred_q13, fs_khz, frame_length);
+            2
+        } else {
+            self.stereo.buffer_mid(x1, frame_length);
+            1
+        };
+
+        // Every channel of the output is brought to the output sample rate.
+        let mut resampled = [0; MAX_FRAME_LENGTH / MAX_FS_KHZ * MAX_FS_OUT_KHZ];
+        let resampled = &mut resampled[..frame_length / fs_khz * self.fs_out_khz];
+        for (n, ch) in self.channels.iter_mut().take(channels).enumerate() {
+            ch.resampler
+                .resample(resampled, &xq[n][1..frame_length + 1]);
+
+            if let Some(output) = output.get_mut(n) {
+                output.extend(resampled.iter().map(|x| *x as f32 / 32768.0));
+            }
+        }
+    }
+}
@@ -0,0 +1,480 @@
+//! SILK normalized line spectral frequencies decoding implementation
+//!
+//! The short-term prediction filter of a frame is coded as NLSFs with a two
+//! stages vector quantizer. The first stage picks a vector from a codebook of
+//! 32, the second stage refines every coefficient with a residual predicted
+//! backwards from the next one. The decoded vector is stabilized so that the
+//! frequencies stay ordered and apart, and for 20 ms frames the filter of the
+//! first half of the frame can be interpolated from the previous frame.
+
+use crate::opus::entropy::RangeCodingDecoder;
+
+use super::{
+    MAX_LPC_ORDER, MAX_NB_SUBFR, SignalType, SilkChannel,
+    lpc::{bwexpander, nlsf2a},
+    math::{rshift_round, smlawb, smulbb},
+};
+
+/// Maximum magnitude of a residual index, larger ones use an extension.
+const NLSF_QUANT_MAX_AMPLITUDE: i32 = 4;
+
+/// 0.1 in Q10, the reconstruction levels are pulled towards zero by it.
+const NLSF_QUANT_LEVEL_ADJ_Q10: i32 = 102;
+
+/// Maximum number of iterations of the stabilization before falling back to
+/// sorting the frequencies.
+const MAX_STABILIZE_LOOPS: usize = 20;
+
+/// 0.97 in Q16, the bandwidth expansion of the filters of the first frame
+/// after a loss.
+const BWE_AFTER_LOSS_Q16: i32 = 63570;
+
+pub const NLSF_EXT_MODEL: [usize; 8] = [256, 156, 216, 240, 249, 253, 255, 256];
+pub const NLSF_INTERP_FACTOR_MODEL: [usize; 6] = [256, 13, 35, 64, 75, 256];
+
+/// Two stages NLSF codebook
+pub struct NlsfCodebook {
+    order: usize,
+    quant_step_size_q16: i32,
+    /// First stage vectors, in Q8.
+    cb1_nlsf: &'static [u8],
+    /// Weights of the residuals of each first stage vector, in Q9.
+    cb1_wght: &'static [i16],
+    /// First stage index, for unvoiced and voiced frames.
+    cb1_model: &'static [[usize; 33]; 2],
+    /// Backward predictors of the residuals, in Q8.
+    pred: &'static [u8],
+    /// Residual models and predictors of each first stage vector, packed in
+    /// nibbles.
+    ec_sel: &'static [u8],
+    ec_model: &'static [[usize; 10]; 8],
+    /// Minimum distances between consecutive frequencies, in Q15.
+    delta_min: &'static [i16],
+}
+
+/// Codebook of the narrowband and mediumband frames, with 10 coefficients.
+pub const NLSF_CB_NB_MB: NlsfCodebook = NlsfCodebook {
+    order: 10,
+    quant_step_size_q16: 11796,
+    cb1_nlsf: &CB1_NLSF_NB_MB_Q8,
+    cb1_wght: &CB1_WGHT_NB_MB_Q9,
+    cb1_model: &CB1_NB_MB_MODEL,
+    pred: &PRED_NB_MB_Q8,
+    ec_sel: &CB2_SELECT_NB_MB,
+    ec_model: &CB2_NB_MB_MODEL,
+    delta_min: &DELTA_MIN_NB_MB_Q15,
+};
+
+/// Codebook of the wideband frames, with 16 coefficients.
+pub const NLSF_CB_WB: NlsfCodebook = NlsfCodebook {
+    order: 16,
+    quant_step_size_q16: 9830,
+    cb1_nlsf: &CB1_NLSF_WB_Q8,
+    cb1_wght: &CB1_WGHT_WB_Q9,
+    cb1_model: &CB1_WB_MODEL,
+    pred: &PRED_WB_Q8,
+    ec_sel: &CB2_SELECT_WB,
+    ec_model: &CB2_WB_MODEL,
+    delta_min: &DELTA_MIN_WB_Q15,
+};
+
+const CB1_NLSF_NB_MB_Q8: [u8; 320] = [
+    12, 35, 60, 83, 108, 132, 157, 180, 206, 228, 15, 32, 55, 77, 101, 125, 151, 175, 201, 225, 19,
+    42, 66, 89, 114, 137, 162, 184, 209, 230, 12, 25, 50, 72, 97, 120, 147, 172, 200, 223, 26, 44,
+    69, 90, 114, 135, 159, 180, 205, 225, 13, 22, 53, 80, 106, 130, 156, 180, 205, 228, 15, 25, 44,
+    64, 90, 115, 142, 168, 196, 222, 19, 24, 62, 82, 100, 120, 145, 168, 190, 214, 22, 31, 50, 79,
+    103, 120, 151, 170, 203, 227, 21, 29, 45, 65, 106, 124, 150, 171, 196, 224, 30, 49, 75, 97,
+    121, 142, 165, 186, 209, 229, 19, 25, 52, 70, 93, 116, 143, 166, 192, 219, 26, 34, 62, 75, 97,
+    118, 145, 167, 194, 217, 25, 33, 56, 70, 91, 113, 143, 165, 196, 223, 21, 34, 51, 72, 97, 117,
+    145, 171, 196, 222, 20, 29, 50, 67, 90, 117, 144, 168, 197, 221, 22, 31, 48, 66, 95, 117, 146,
+    168, 196, 222, 24, 33, 51, 77, 116, 134, 158, 180, 200, 224, 21, 28, 70, 87, 106, 124, 149,
+    170, 194, 217, 26, 33, 53, 64, 83, 117, 152, 173, 204, 225, 27, 34, 65, 95, 108, 129, 155, 174,
+    210, 225, 20, 26, 72, 99, 113, 131, 154, 176, 200, 219, 34, 43, 61, 78, 93, 114, 155, 177, 205,
+    229, 23, 29, 54, 97, 124, 138, 163, 179, 209, 229, 30, 38, 56, 89, 118, 129, 158, 178, 200,
+    231, 21, 29, 49, 63, 85, 111, 142, 163, 193, 222, 27, 48, 77, 103, 133, 158, 179, 196, 215,
+    232, 29, 47, 74, 99, 124, 151, 176, 198, 220, 237, 33, 42, 61, 76, 93, 121, 155, 174, 207, 225,
+    29, 53, 87, 112, 136, 154, 170, 188, 208, 227, 24, 30, 52, 84, 131, 150, 166, 186, 203, 229,
+    37, 48, 64, 84, 104, 118, 156, 177, 201, 230,
+];
+const CB1_WGHT_NB_MB_Q9: [i16; 320] = [
+    2897, 2314, 2314, 2314, 2287, 2287, 2314, 2300, 2327, 2287, 2888, 2580, 2394, 2367, 2314, 2274,
+    2274, 2274, 2274, 2194, 2487, 2340, 2340, 2314, 2314, 2314, 2340, 2340, 2367, 2354, 3216, 2766,
+    2340, 2340, 2314, 2274, 2221, 2207, 2261, 2194, 2460, 2474, 2367, 2394, 2394, 2394, 2394, 2367,
+    2407, 2314, 3479, 3056, 2127, 2207, 2274, 2274, 2274, 2287, 2314, 2261, 3282, 3141, 2580, 2394,
+    2247, 2221, 2207, 2194, 2194, 2114, 4096, 3845, 2221, 2620, 2620, 2407, 2314, 2394, 2367, 2074,
+    3178, 3244, 2367, 2221, 2553, 2434, 2340, 2314, 2167, 2221, 3338, 3488, 2726, 2194, 2261, 2460,
+    2354, 2367, 2207, 2101, 2354, 2420, 2327, 2367, 2394, 2420, 2420, 2420, 2460, 2367, 3779, 3629,
+    2434, 2527, 2367, 2274, 2274, 2300, 2207, 2048, 3254, 3225, 2713, 2846, 2447, 2327, 2300, 2300,
+    2274, 2127, 3263, 3300, 2753, 2806, 2447, 2261, 2261, 2247, 2127, 2101, 2873, 2981, 2633, 2367,
+    2407, 2354, 2194, 2247, 2247, 2114, 3225, 3197, 2633, 2580, 2274, 2181, 2247, 2221, 2221, 2141,
+    3178, 3310, 2740, 2407, 2274, 2274, 2274, 2287, 2194, 2114, 3141, 3272, 2460, 2061, 2287, 2500,
+    2367, 2487, 2434, 2181, 3507, 3282, 2314, 2700, 2647, 2474, 2367, 2394, 2340, 2127, 3423, 3535,
+    3038, 3056, 2300, 1950, 2221, 2274, 2274, 2274, 3404, 3366, 2087, 2687, 2873, 2354, 2420, 2274,
+    2474, 2540, 3760, 3488, 1950, 2660, 2897, 2527, 2394, 2367, 2460, 2261, 3028, 3272, 2740, 2888,
+    2740, 2154, 2127, 2287, 2234, 2247, 3695, 3657, 2025, 1969, 2660, 2700, 2580, 2500, 2327, 2367,
+    3207, 3413, 2354, 2074, 2888, 2888, 2340, 2487, 2247, 2167, 3338, 3366, 2846, 2780, 2327, 2154,
+    2274, 2287, 2114, 2061, 2327, 2300, 2181, 2167, 2181, 2367, 2633, 2700, 2700, 2553, 2407, 2434,
+    2221, 2261, 2221, 2221, 2340, 2420, 2607, 2700, 3038, 3244, 2806, 2888, 2474, 2074, 2300, 2314,
+    2354, 2380, 2221, 2154, 2127, 2287, 2500, 2793, 2793, 2620, 2580, 2367, 3676, 3713, 2234, 1838,
+    2181, 2753, 2726, 2673, 2513, 2207, 2793, 3160, 2726, 2553, 2846, 2513, 2181, 2394, 2221, 2181,
+];
+const CB1_NB_MB_MODEL: [[usize; 33]; 2] = [
+    [
+        256, 44, 78, 108, 127, 148, 160, 171, 174, 177, 179, 195, 197, 199, 200, 205, 207, 208,
+        211, 214, 215, 216, 218, 220, 222, 225, 226, 235, 244, 246, 253, 255, 256,
+    ],
+    [
+        256, 1, 11, 12, 20, 23, 31, 39, 53, 66, 80, 81, 95, 107, 120, 131, 142, 154, 165, 175, 185,
+        196, 204, 213, 221, 228, 236, 237, 238, 244, 245, 251, 256,
+    ],
+];
+const PRED_NB_MB_Q8: [u8; 18] = [
+    179, 138, 140, 148, 151, 149, 153, 151, 163, 116, 67, 82, 59, 92, 72, 100, 89, 92,
+];
+const CB2_SELECT_NB_MB: [u8; 160] = [
+    16, 0, 0, 0, 0, 99, 66, 36, 36, 34, 36, 34, 34, 34, 34, 83, 69, 36, 52, 34, 116, 102, 70, 68,
+    68, 176, 102, 68, 68, 34, 65, 85, 68, 84, 36, 116, 141, 152, 139, 170, 132, 187, 184, 216, 137,
+    132, 249, 168, 185, 139, 104, 102, 100, 68, 68, 178, 218, 185, 185, 170, 244, 216, 187, 187,
+    170, 244, 187, 187, 219, 138, 103, 155, 184, 185, 137, 116, 183, 155, 152, 136, 132, 217, 184,
+    184, 170, 164, 217, 171, 155, 139, 244, 169, 184, 185, 170, 164, 216, 223, 218, 138, 214, 143,
+    188, 218, 168, 244, 141, 136, 155, 170, 168, 138, 220, 219, 139, 164, 219, 202, 216, 137, 168,
+    186, 246, 185, 139, 116, 185, 219, 185, 138, 100, 100, 134, 100, 102, 34, 68, 68, 100, 68, 168,
+    203, 221, 218, 168, 167, 154, 136, 104, 70, 164, 246, 171, 137, 139, 137, 155, 218, 219, 139,
+];
+const CB2_NB_MB_MODEL: [[usize; 10]; 8] = [
+    [256, 1, 2, 3, 18, 242, 253, 254, 255, 256],
+    [256, 1, 2, 4, 38, 221, 253, 254, 255, 256],
+    [256, 1, 2, 6, 48, 197, 252, 254, 255, 256],
+    [256, 1, 2, 10, 62, 185, 246, 254, 255, 256],
+    [256, 1, 4, 20, 73, 174, 248, 254, 255, 256],
+    [256, 1, 4, 21, 76, 166, 239, 254, 255, 256],
+    [256, 1, 8, 32, 85, 159, 226, 252, 255, 256],
+    [256, 1, 2, 20, 83, 161, 219, 249, 255, 256],
+];
+const DELTA_MIN_NB_MB_Q15: [i16; 11] = [250, 3, 6, 3, 3, 3, 4, 3, 3, 3, 461];
+const CB1_NLSF_WB_Q8: [u8; 512] = [
+    7, 23, 38, 54, 69, 85, 100, 116, 131, 147, 162, 178, 193, 208, 223, 239, 13, 25, 41, 55, 69,
+    83, 98, 112, 127, 142, 157, 171, 187, 203, 220, 236, 15, 21, 34, 51, 61, 78, 92, 106, 126, 136,
+    152, 167, 185, 205, 225, 240, 10, 21, 36, 50, 63, 79, 95, 110, 126, 141, 157, 173, 189, 205,
+    221, 237, 17, 20, 37, 51, 59, 78, 89, 107, 123, 134, 150, 164, 184, 205, 224, 240, 10, 15, 32,
+    51, 67, 81, 96, 112, 129, 142, 158, 173, 189, 204, 220, 236, 8, 21, 37, 51, 65, 79, 98, 113,
+    126, 138, 155, 168, 179, 192, 209, 218, 12, 15, 34, 55, 63, 78, 87, 108, 118, 131, 148, 167,
+    185, 203, 219, 236, 16, 19, 32, 36, 56, 79, 91, 108, 118, 136, 154, 171, 186, 204, 220, 237,
+    11, 28, 43, 58, 74, 89, 105, 120, 135, 150, 165, 180, 196, 211, 226, 241, 6, 16, 33, 46, 60,
+    75, 92, 107, 123, 137, 156, 169, 185, 199, 214, 225, 11, 19, 30, 44, 57, 74, 89, 105, 121, 135,
+    152, 169, 186, 202, 218, 234, 12, 19, 29, 46, 57, 71, 88, 100, 120, 132, 148, 165, 182, 199,
+    216, 233, 17, 23, 35, 46, 56, 77, 92, 106, 123, 134, 152, 167, 185, 204, 222, 237, 14, 17, 45,
+    53, 63, 75, 89, 107, 115, 132, 151, 171, 188, 206, 221, 240, 9, 16, 29, 40, 56, 71, 88, 103,
+    119, 137, 154, 171, 189, 205, 222, 237, 16, 19, 36, 48, 57, 76, 87, 105, 118, 132, 150, 167,
+    185, 202, 218, 236, 12, 17, 29, 54, 71, 81, 94, 104, 126, 136, 149, 164, 182, 201, 221, 237,
+    15, 28, 47, 62, 79, 97, 115, 129, 142, 155, 168, 180, 194, 208, 223, 238, 8, 14, 30, 45, 62,
+    78, 94, 111, 127, 143, 159, 175, 192, 207, 223, 239, 17, 30, 49, 62, 79, 92, 107, 119, 132,
+    145, 160, 174, 190, 204, 220, 235, 14, 19, 36, 45, 61, 76, 91, 108, 121, 138, 154, 172, 189,
+    205, 222, 238, 12, 18, 31, 45, 60, 76, 91, 107, 123, 138, 154, 171, 187, 204, 221, 236, 13, 17,
+    31, 43, 53, 70, 83, 103, 114, 131, 149, 167, 185, 203, 220, 237, 17, 22, 35, 42, 58, 78, 93,
+    110, 125, 139, 155, 170, 188, 206, 224, 240, 8, 15, 34, 50, 67, 83, 99, 115, 131, 146, 162,
+    178, 193, 209, 224, 239, 13, 16, 41, 66, 73, 86, 95, 111, 128, 137, 150, 163, 183, 206, 225,
+    241, 17, 25, 37, 52, 63, 75, 92, 102, 119, 132, 144, 160, 175, 191, 212, 231, 19, 31, 49, 65,
+    83, 100, 117, 133, 147, 161, 174, 187, 200, 213, 227, 242, 18, 31, 52, 68, 88, 103, 117, 126,
+    138, 149, 163, 177, 192, 207, 223, 239, 16, 29, 47, 61, 76, 90, 106, 119, 133, 147, 161, 176,
+    193, 209, 224, 240, 15, 21, 35, 50, 61, 73, 86, 97, 110, 119, 129, 141, 175, 198, 218, 237,
+];
+const CB1_WGHT_WB_Q9: [i16; 512] = [
+    3657, 2925, 2925, 2925, 2925, 2925, 2925, 2925, 2925, 2925, 2925, 2925, 2963, 2963, 2925, 2846,
+    3216, 3085, 2972, 3056, 3056, 3010, 3010, 3010, 2963, 2963, 3010, 2972, 2888, 2846, 2846, 2726,
+    3920, 4014, 2981, 3207, 3207, 2934, 3056, 2846, 3122, 3244, 2925, 2846, 2620, 2553, 2780, 2925,
+    3516, 3197, 3010, 3103, 3019, 2888, 2925, 2925, 2925, 2925, 2888, 2888, 2888, 2888, 2888, 2753,
+    5054, 5054, 2934, 3573, 3385, 3056, 3085, 2793, 3160, 3160, 2972, 2846, 2513, 2540, 2753, 2888,
+    4428, 4149, 2700, 2753, 2972, 3010, 2925, 2846, 2981, 3019, 2925, 2925, 2925, 2925, 2888, 2726,
+    3620, 3019, 2972, 3056, 3056, 2873, 2806, 3056, 3216, 3047, 2981, 3291, 3291, 2981, 3310, 2991,
+    5227, 5014, 2540, 3338, 3526, 3385, 3197, 3094, 3376, 2981, 2700, 2647, 2687, 2793, 2846, 2673,
+    5081, 5174, 4615, 4428, 2460, 2897, 3047, 3207, 3169, 2687, 2740, 2888, 2846, 2793, 2846, 2700,
+    3122, 2888, 2963, 2925, 2925, 2925, 2925, 2963, 2963, 2963, 2963, 2925, 2925, 2963, 2963, 2963,
+    4202, 3207, 2981, 3103, 3010, 2888, 2888, 2925, 2972, 2873, 2916, 3019, 2972, 3010, 3197, 2873,
+    3760, 3760, 3244, 3103, 2981, 2888, 2925, 2888, 2972, 2934, 2793, 2793, 2846, 2888, 2888, 2660,
+    3854, 4014, 3207, 3122, 3244, 2934, 3047, 2963, 2963, 3085, 2846, 2793, 2793, 2793, 2793, 2580,
+    3845, 4080, 3357, 3516, 3094, 2740, 3010, 2934, 3122, 3085, 2846, 2846, 2647, 2647, 2846, 2806,
+    5147, 4894, 3225, 3845, 3441, 3169, 2897, 3413, 3451, 2700, 2580, 2673, 2740, 2846, 2806, 2753,
+    4109, 3789, 3291, 3160, 2925, 2888, 2888, 2925, 2793, 2740, 2793, 2740, 2793, 2846, 2888, 2806,
+    5081, 5054, 3047, 3545, 3244, 3056, 3085, 2944, 3103, 2897, 2740, 2740, 2740, 2846, 2793, 2620,
+    4309, 4309, 2860, 2527, 3207, 3376, 3376, 3075, 3075, 3376, 3056, 2846, 2647, 2580, 2726, 2753,
+    3056, 2916, 2806, 2888, 2740, 2687, 2897, 3103, 3150, 3150, 3216, 3169, 3056, 3010, 2963, 2846,
+    4375, 3882, 2925, 2888, 2846, 2888, 2846, 2846, 2888, 2888, 2888, 2846, 2888, 2925, 2888, 2846,
+    2981, 2916, 2916, 2981, 2981, 3056, 3122, 3216, 3150, 3056, 3010, 2972, 2972, 2972, 2925, 2740,
+    4229, 4149, 3310, 3347, 2925, 2963, 2888, 2981, 2981, 2846, 2793, 2740, 2846, 2846, 2846, 2793,
+    4080, 4014, 3103, 3010, 2925, 2925, 2925, 2888, 2925, 2925, 2846, 2846, 2846, 2793, 2888, 2780,
+    4615, 4575, 3169, 3441, 3207, 2981, 2897, 3038, 3122, 2740, 2687, 2687, 2687, 2740, 2793, 2700,
+    4149, 4269, 3789, 3657, 2726, 2780, 2888, 2888, 3010, 2972, 2925, 2846, 2687, 2687, 2793, 2888,
+    4215, 3554, 2753, 2846, 2846, 2888, 2888, 2888, 2925, 2925, 2888, 2925, 2925, 2925, 2963, 2888,
+    5174, 4921, 2261, 3432, 3789, 3479, 3347, 2846, 3310, 3479, 3150, 2897, 2460, 2487, 2753, 2925,
+    3451, 3685, 3122, 3197, 3357, 3047, 3207, 3207, 2981, 3216, 3085, 2925, 2925, 2687, 2540, 2434,
+    2981, 3010, 2793, 2793, 2740, 2793, 2846, 2972, 3056, 3103, 3150, 3150, 3150, 3103, 3010, 3010,
+    2944, 2873, 2687, 2726, 2780, 3010, 3432, 3545, 3357, 3244, 3056, 3010, 2963, 2925, 2888, 2846,
+    3019, 2944, 2897, 3010, 3010, 2972, 3019, 3103, 3056, 3056, 3010, 2888, 2846, 2925, 2925, 2888,
+    3920, 3967, 3010, 3197, 3357, 3216, 3291, 3291, 3479, 3704, 3441, 2726, 2181, 2460, 2580, 2607,
+];
+const CB1_WB_MODEL: [[usize; 33]; 2] = [
+    [
+        256, 31, 52, 55, 72, 73, 81, 98, 102, 103, 121, 137, 141, 143, 146, 147, 157, 158, 161,
+        177, 188, 204, 206, 208, 211, 213, 224, 225, 229, 238, 246, 253, 256,
+    ],
+    [
+        256, 1, 5, 21, 26, 44, 55, 60, 74, 89, 90, 93, 105, 118, 132, 146, 152, 166, 178, 180, 186,
+        187, 199, 211, 222, 232, 235, 245, 250, 251, 252, 253, 256,
+    ],
+];
+const PRED_WB_Q8: [u8; 30] = [
+    175, 148, 160, 176, 178, 173, 174, 164, 177, 174, 196, 182, 198, 192, 182, 68, 62, 66, 60, 72,
+    117, 85, 90, 118, 136, 151, 142, 160, 142, 155,
+];
+const CB2_SELECT_WB: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 1, 100, 102, 102, 68, 68, 36, 34, 96, 164, 107, 158, 185, 180, 185, 139,
+    102, 64, 66, 36, 34, 34, 0, 1, 32, 208, 139, 141, 191, 152, 185, 155, 104, 96, 171, 104, 166,
+    102, 102, 102, 132, 1, 0, 0, 0, 0, 16, 16, 0, 80, 109, 78, 107, 185, 139, 103, 101, 208, 212,
+    141, 139, 173, 153, 123, 103, 36, 0, 0, 0, 0, 0, 0, 1, 48, 0, 0, 0, 0, 0, 0, 32, 68, 135, 123,
+    119, 119, 103, 69, 98, 68, 103, 120, 118, 118, 102, 71, 98, 134, 136, 157, 184, 182, 153, 139,
+    134, 208, 168, 248, 75, 189, 143, 121, 107, 32, 49, 34, 34, 34, 0, 17, 2, 210, 235, 139, 123,
+    185, 137, 105, 134, 98, 135, 104, 182, 100, 183, 171, 134, 100, 70, 68, 70, 66, 66, 34, 131,
+    64, 166, 102, 68, 36, 2, 1, 0, 134, 166, 102, 68, 34, 34, 66, 132, 212, 246, 158, 139, 107,
+    107, 87, 102, 100, 219, 125, 122, 137, 118, 103, 132, 114, 135, 137, 105, 171, 106, 50, 34,
+    164, 214, 141, 143, 185, 151, 121, 103, 192, 34, 0, 0, 0, 0, 0, 1, 208, 109, 74, 187, 134, 249,
+    159, 137, 102, 110, 154, 118, 87, 101, 119, 101, 0, 2, 0, 36, 36, 66, 68, 35, 96, 164, 102,
+    100, 36, 0, 2, 33, 167, 138, 174, 102, 100, 84, 2, 2, 100, 107, 120, 119, 36, 197, 24, 0,
+];
+const CB2_WB_MODEL: [[usize; 10]; 8] = [
+    [256, 1, 2, 3, 12, 244, 253, 254, 255, 256],
+    [256, 1, 2, 4, 32, 218, 253, 254, 255, 256],
+    [256, 1, 2, 5, 47, 199, 252, 254, 255, 256],
+    [256, 1, 2, 12, 61, 187, 252, 254, 255, 256],
+    [256, 1, 5, 24, 72, 172, 249, 254, 255, 256],
+    [256, 1, 2, 16, 70, 170, 242, 254, 255, 256],
+    [256, 1, 2, 17, 78, 165, 226, 251, 255, 256],
+    [256, 1, 8, 29, 79, 156, 237, 254, 255, 256],
+];
+const DELTA_MIN_WB_Q15: [i16; 17] = [100, 3, 40, 3, 3, 3, 5, 14, 14, 10, 11, 3, 8, 9, 7, 3, 347];
+/// NLSF decoder
+pub struct Nlsf;
+
+impl Nlsf {
+    /// Decodes the NLSF indices of a frame and the interpolation factor.
+    pub fn decode(ch: &mut SilkChannel, range_dec: &mut RangeCodingDecoder) {
+        let cb = codebook(ch);
+
+        // The first stage codebook index depends on whether the frame is
+        // voiced.
+        let voiced = (ch.indices.signal_type == SignalType::Voiced) as usize;
+        let index = range_dec.icdf(&cb.cb1_model[voiced]);
+        ch.indices.nlsf[0] = index as i32;
+
+        let (ec_ix, _) = unpack(cb, index);
+        for (i, ec_ix) in ec_ix.iter().take(cb.order).enumerate() {
+            // The largest magnitudes are extended with another symbol.
+            let mut ix = range_dec.icdf(&cb.ec_model[*ec_ix]) as i32;
+            if ix == 0 {
+                ix -= range_dec.icdf(&NLSF_EXT_MODEL) as i32;
+            } else if ix == 2 * NLSF_QUANT_MAX_AMPLITUDE {
+                ix += range_dec.icdf(&NLSF_EXT_MODEL) as i32;
+            }
+
+            ch.indices.nlsf[i + 1] = ix - NLSF_QUANT_MAX_AMPLITUDE;
+        }
+
+        // Only frames of 4 subframes can be interpolated.
+        ch.indices.nlsf_interp_coef = if ch.nb_subfr == MAX_NB_SUBFR {
+            range_dec.icdf(&NLSF_INTERP_FACTOR_MODEL) as i32
+        } else {
+            4
+        };
+    }
+
+    /// Turns the NLSF indices into the Q12 LPC coefficients of both halves
+    /// of the frame.
+    pub fn dequant(ch: &mut SilkChannel) {
+        let cb = codebook(ch);
+        let order = cb.order;
+
+        let mut nlsf = [0; MAX_LPC_ORDER];
+        dequant(&mut nlsf[..order], &ch.indices.nlsf, cb);
+        nlsf2a(&mut ch.lpc[1][..order], &nlsf[..order]);
+
+        // The interpolation is not allowed right after a reset, the previous
+        // NLSFs are meaningless.
+        if ch.first_frame_after_reset {
+            ch.indices.nlsf_interp_coef = 4;
+        }
+
+        if ch.indices.nlsf_interp_coef < 4 {
+            let mut nlsf0 = [0; MAX_LPC_ORDER];
+            for i in 0..order {
+                let prev = ch.prev_nlsf[i] as i32;
+                nlsf0[i] =
+                    (prev + ((ch.indices.nlsf_interp_coef * (nlsf[i] as i32 - prev)) >> 2)) as i16;
+            }
+
+            nlsf2a(&mut ch.lpc[0][..order], &nlsf0[..order]);
+        } else {
+            ch.lpc[0] = ch.lpc[1];
+        }
+
+        // The filters are smoothed right after a loss, against the transients
+        // of the concealment.
+        if ch.loss_count > 0 {
+            for lpc in &mut ch.lpc {
+                bwexpander(&mut lpc[..order], BWE_AFTER_LOSS_Q16);
+            }
+        }
+
+        ch.prev_nlsf = nlsf;
+    }
+}
+
+/// Selects the codebook matching the LPC order of the channel.
+fn codebook(ch: &SilkChannel) -> &'static NlsfCodebook {
+    if ch.lpc_order == NLSF_CB_WB.order {
+        &NLSF_CB_WB
+    } else {
+        &NLSF_CB_NB_MB
+    }
+}
+
+/// Unpacks the residual models and the backward predictors of the first
+/// stage vector `index`.
+fn unpack(cb: &NlsfCodebook, index: usize) -> ([usize; MAX_LPC_ORDER], [i32; MAX_LPC_ORDER]) {
+    let mut ec_ix = [0; MAX_LPC_ORDER];
+    let mut pred = [0; MAX_LPC_ORDER];
+
+    let ec_sel = &cb.ec_sel[index * cb.order / 2..];
+    for (i, &entry) in (0..cb.order).step_by(2).zip(ec_sel) {
+        let entry = entry as usize;
+
+        ec_ix[i] = (entry >> 1) & 7;
+        pred[i] = cb.pred[i + (entry & 1) * (cb.order - 1)] as i32;
+        ec_ix[i + 1] = (entry >> 5) & 7;
+        pred[i + 1] = cb.pred[i + ((entry >> 4) & 1) * (cb.order - 1) + 1] as i32;
+    }
+
+    (ec_ix, pred)
+}
+
+/// Reconstructs the stabilized Q15 NLSFs from the codebook indices.
+fn dequant(nlsf: &mut [i16], indices: &[i32], cb: &NlsfCodebook) {
+    let (_, pred) = unpack(cb, indices[0] as usize);
+
+    // The residuals are predicted backwards from the next coefficient.
+    let mut res = [0i16; MAX_LPC_ORDER];
+    let mut out = 0;
+    for i in (0..cb.order).rev() {
+        let pred_q10 = smulbb(out, pred[i]) >> 8;
+
+        out = indices[i + 1] << 10;
+        if out > 0 {
+            out -= NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else if out < 0 {
+            out += NLSF_QUANT_LEVEL_ADJ_Q10;
+        }
+
+        out = smlawb(pred_q10, out, cb.quant_step_size_q16);
+        res[i] = out as i16;
+    }
+
+    // The residuals are weighted by the inverse of the sensitivity of the
+    // first stage vector.
+    let base = indices[0] as usize * cb.order;
+    for (i, nlsf) in nlsf.iter_mut().enumerate() {
+        let value = (((res[i] as i32) << 14) / cb.cb1_wght[base + i] as i32)
+            + ((cb.cb1_nlsf[base + i] as i32) << 7);
+        *nlsf = value.clamp(0, 32767) as i16;
+    }
+
+    stabilize(nlsf, cb.delta_min);
+}
+
+/// Moves the NLSFs apart so that they are at least `delta_min` from each
+/// other and from 0 and pi.
+fn stabilize(nlsf: &mut [i16], delta_min: &[i16]) {
+    let l = nlsf.len();
+
+    for _ in 0..MAX_STABILIZE_LOOPS {
+        // The smallest distance
+        let mut min_diff = nlsf[0] as i32 - delta_min[0] as i32;
+        let mut index = 0;
+        for i in 1..l {
+            let diff = nlsf[i] as i32 - (nlsf[i - 1] as i32 + delta_min[i] as i32);
+            if diff < min_diff {
+                min_diff = diff;
+                index = i;
+            }
+        }
+
+        let diff = (1 << 15) - (nlsf[l - 1] as i32 + delta_min[l] as i32);
+        if diff < min_diff {
+            min_diff = diff;
+            index = l;
+        }
+
+        if min_diff >= 0 {
+            return;
+        }
+
+        if index == 0 {
+            nlsf[0] = delta_min[0];
+        } else if index == l {
+            nlsf[l - 1] = ((1 << 15) - delta_min[l] as i32) as i16;
+        } else {
+            // Move the pair apart around their center, within the range
+            // left by the minimum distances of the other frequencies.
+            let half = delta_min[index] as i32 >> 1;
+
+            let min_center = delta_min[..index].iter().map(|d| *d as i32).sum::<i32>() + half;
+            let max_center = (1 << 15)
+                - delta_min[index + 1..]
+                    .iter()
+                    .map(|d| *d as i32)
+                    .sum::<i32>()
+                - half;
+
+            let center = rshift_round(nlsf[index - 1] as i32 + nlsf[index] as i32, 1)
+                .clamp(min_center, max_center);
+
+            nlsf[index - 1] = (center - half) as i16;
+            nlsf[index] = nlsf[index - 1] + delta_min[index];
+        }
+    }
+
+    // Fall back to sorting and clamping to the distances
+    nlsf.sort_unstable();
+
+    nlsf[0] = nlsf[0].max(delta_min[0]);
+    for i in 1..l {
+        nlsf[i] = nlsf[i].max(nlsf[i - 1].saturating_add(delta_min[i]));
+    }
+
+    nlsf[l - 1] = nlsf[l - 1].min(((1 << 15) - delta_min[l] as i32) as i16);
+    for i in (0..l - 1).rev() {
+        nlsf[i] = nlsf[i].min(nlsf[i + 1] - delta_min[i + 1]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NLSF_CB_WB, stabilize};
+
+    #[test]
+    fn stabilize_nlsf() {
+        let delta_min = NLSF_CB_WB.delta_min;
+
+        // Crossed, too close and out of range frequencies
+        let mut nlsf = [
+            0, 2000, 1900, 6000, 6001, 10000, 12000, 14000, 16000, 18000, 20000, 22000, 24000,
+            26000, 28000, 32767,
+        ];
+        stabilize(&mut nlsf, delta_min);
+
+        assert!(nlsf[0] >= delta_min[0]);
+        for i in 1..nlsf.len() {
+            assert!(nlsf[i] - nlsf[i - 1] >= delta_min[i]);
+        }
+
+        assert!(nlsf[15] as i32 <= (1 << 15) - delta_min[16] as i32);
+    }
+}
@@ -0,0 +1,440 @@
+//! SILK packet loss concealment implementation
+//!
+//! A lost frame is extrapolated from the last one: the LPC filter of the last
+//! frame is run over an excitation made of a long-term prediction from the
+//! past excitation, with a single tap of the largest pitch gain, plus noise
+//! taken from the past excitation. Both parts are attenuated subframe after
+//! subframe, and the pitch lag slowly drifts up.
+//!
+//! The first frame received after a loss is faded in from the energy of the
+//! concealment when it is louder, to avoid a burst.
+
+use super::{
+    LTP_ORDER, MAX_FRAME_LENGTH, MAX_LPC_ORDER, MAX_LTP_MEM_LENGTH, MAX_NB_SUBFR,
+    MAX_SUB_FRAME_LENGTH, SignalType, SilkChannel, lpc,
+    math::{
+        inverse32_varq, rand, rshift_round, smlawb, smulbb, smulwb, smulww, sqrt_approx,
+        sum_sqr_shift,
+    },
+};
+
+/// 0.99 in Q16, the bandwidth expansion of the LPC filter at every lost
+/// frame.
+const BWE_COEF_Q16: i32 = 64881;
+
+/// 0.7 and 0.95 in Q14, the range of the pitch gain at the start of the
+/// concealment.
+const V_PITCH_GAIN_START_MIN_Q14: i32 = 11469;
+const V_PITCH_GAIN_START_MAX_Q14: i32 = 15565;
+
+/// Longest pitch lag the drift goes up to.
+const MAX_PITCH_LAG_MS: usize = 18;
+
+/// Size of the past excitation the noise is drawn from.
+const RAND_BUF_SIZE: usize = 128;
+const RAND_BUF_MASK: i32 = RAND_BUF_SIZE as i32 - 1;
+
+/// Bounds of the LPC gain of the noise of unvoiced frames, as the log2 of the
+/// inverse gain: 8 and 24 dB.
+const LOG2_INV_LPC_GAIN_HIGH_THRES: u32 = 3;
+const LOG2_INV_LPC_GAIN_LOW_THRES: u32 = 8;
+
+/// 0.01 in Q16, the relative increase of the pitch lag at every subframe.
+const PITCH_DRIFT_FAC_Q16: i32 = 655;
+
+/// Attenuation of the pitch and noise gains at every subframe, in Q15, for
+/// the first lost frame and the following ones.
+const HARM_ATT_Q15: [i32; 2] = [32440, 31130];
+const PLC_RAND_ATTENUATE_V_Q15: [i32; 2] = [31130, 26214];
+const PLC_RAND_ATTENUATE_UV_Q15: [i32; 2] = [32440, 29491];
+
+/// State of the concealment of a channel
+#[derive(Debug)]
+pub struct Plc {
+    /// Internal sample rate the state is set up for.
+    fs_khz: usize,
+    /// Pitch lag in Q8.
+    pitch_l_q8: i32,
+    /// Q14 LTP filter taps, attenuated over the lost frames.
+    ltp_coef_q14: [i16; LTP_ORDER],
+    /// Q12 LPC coefficients of the last frame, expanded over the lost
+    /// frames.
+    prev_lpc_q12: [i16; MAX_LPC_ORDER],
+    prev_ltp_scale_q14: i32,
+    /// Q16 gains of the last two subframes.
+    prev_gain_q16: [i32; 2],
+    subfr_length: usize,
+    nb_subfr: usize,
+    rand_seed: i32,
+    /// Q14 gain of the noise part of the excitation.
+    rand_scale_q14: i16,
+    /// Energy of the last concealed frame, shifted right by
+    /// `conc_energy_shift`.
+    conc_energy: i32,
+    conc_energy_shift: u32,
+    last_frame_lost: bool,
+}
+
+impl Default for Plc {
+    fn default() -> Self {
+        Self {
+            fs_khz: 0,
+            pitch_l_q8: 0,
+            ltp_coef_q14: [0; LTP_ORDER],
+            prev_lpc_q12: [0; MAX_LPC_ORDER],
+            prev_ltp_scale_q14: 0,
+            prev_gain_q16: [1 << 16; 2],
+            subfr_length: 20,
+            nb_subfr: 2,
+            rand_seed: 0,
+            rand_scale_q14: 0,
+            conc_energy: 0,
+            conc_energy_shift: 0,
+            last_frame_lost: false,
+        }
+    }
+}
+
+impl Plc {
+    /// Sets the state up for the internal sample rate of the channel, when it
+    /// changes.
+    fn set_fs(ch: &mut SilkChannel) {
+        let plc = &mut ch.plc;
+        if plc.fs_khz != ch.fs_khz {
+            plc.pitch_l_q8 = (ch.frame_length as i32) << 7;
+            plc.prev_gain_q16 = [1 << 16; 2];
+            plc.subfr_length = 20;
+            plc.nb_subfr = 2;
+            plc.fs_khz = ch.fs_khz;
+        }
+    }
+
+    /// Returns the Q16 gain of the noise at the end of the last concealed
+    /// frame.
+    pub fn noise_gain_q16(&self) -> i32 {
+        smulww(self.rand_scale_q14 as i32, self.prev_gain_q16[1])
+    }
+
+    /// Keeps the parameters of a decoded frame, for the concealment of the
+    /// next one.
+    pub fn update(ch: &mut SilkChannel) {
+        Self::set_fs(ch);
+
+        let plc = &mut ch.plc;
+        if ch.indices.signal_type == SignalType::Voiced {
+            // The pitch lag of the subframe with the largest pitch gain,
+            // among the last pitch period.
+            let mut ltp_gain_q14 = 0;
+            for j in 0..ch.nb_subfr {
+                if j * ch.subfr_length >= ch.pitch_lags[ch.nb_subfr - 1] as usize {
+                    break;
+                }
+
+                let k = ch.nb_subfr - 1 - j;
+                let gain_q14 = ch.ltp_coef[k * LTP_ORDER..][..LTP_ORDER]
+                    .iter()
+                    .map(|tap| *tap as i32)
+                    .sum();
+
+                if gain_q14 > ltp_gain_q14 {
+                    ltp_gain_q14 = gain_q14;
+                    plc.pitch_l_q8 = ch.pitch_lags[k] << 8;
+                }
+            }
+
+            // A single tap with the whole gain, kept between 0.7 and 0.95
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+            plc.ltp_coef_q14[LTP_ORDER / 2] = ltp_gain_q14 as i16;
+
+            if ltp_gain_q14 < V_PITCH_GAIN_START_MIN_Q14 {
+                let scale_q10 = (V_PITCH_GAIN_START_MIN_Q14 << 10) / ltp_gain_q14.max(1);
+                for tap in &mut plc.ltp_coef_q14 {
+                    *tap = (smulbb(*tap as i32, scale_q10) >> 10) as i16;
+                }
+            } else if ltp_gain_q14 > V_PITCH_GAIN_START_MAX_Q14 {
+                let scale_q14 = (V_PITCH_GAIN_START_MAX_Q14 << 14) / ltp_gain_q14.max(1);
+                for tap in &mut plc.ltp_coef_q14 {
+                    *tap = (smulbb(*tap as i32, scale_q14) >> 14) as i16;
+                }
+            }
+        } else {
+            plc.pitch_l_q8 = ((MAX_PITCH_LAG_MS * ch.fs_khz) << 8) as i32;
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+        }
+
+        plc.prev_lpc_q12 = ch.lpc[1];
+        plc.prev_ltp_scale_q14 = ch.ltp_scale;
+        plc.prev_gain_q16
+            .copy_from_slice(&ch.gains[ch.nb_subfr - 2..ch.nb_subfr]);
+        plc.subfr_length = ch.subfr_length;
+        plc.nb_subfr = ch.nb_subfr;
+    }
+
+    /// Extrapolates a lost frame into `xq` from the last decoded one.
+    pub fn conceal(ch: &mut SilkChannel, xq: &mut [i16]) {
+        Self::set_fs(ch);
+
+        let plc = &mut ch.plc;
+        let order = ch.lpc_order;
+        let subfr_length = ch.subfr_length;
+        let prev_gain_q10 = plc.prev_gain_q16.map(|gain| gain >> 6);
+
+        if ch.first_frame_after_reset {
+            plc.prev_lpc_q12 = [0; MAX_LPC_ORDER];
+        }
+
+        // The noise is drawn from the excitation of the quietest of the last
+        // two subframes.
+        let mut energy = [(0, 0); 2];
+        for (k, energy) in energy.iter_mut().enumerate() {
+            let mut exc = [0; MAX_SUB_FRAME_LENGTH];
+            let start = (k + ch.nb_subfr - 2) * subfr_length;
+            for (x, exc_q14) in exc.iter_mut().zip(&ch.exc_q14[start..][..subfr_length]) {
+                *x = (smulww(*exc_q14, prev_gain_q10[k]) >> 8)
+                    .clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            }
+
+            *energy = sum_sqr_shift(&exc[..subfr_length]);
+        }
+
+        let [(energy1, shift1), (energy2, shift2)] = energy;
+        let rand_end = if energy1 >> shift2 < energy2 >> shift1 {
+            (plc.nb_subfr - 1) * plc.subfr_length
+        } else {
+            plc.nb_subfr * plc.subfr_length
+        };
+
+        let rand_buf = &ch.exc_q14[rand_end.saturating_sub(RAND_BUF_SIZE)..][..RAND_BUF_SIZE];
+
+        // Both parts of the excitation fade faster after the first lost
+        // frame.
+        let att = ch.loss_count.min(HARM_ATT_Q15.len() - 1);
+        let harm_gain_q15 = HARM_ATT_Q15[att];
+        let mut rand_gain_q15 = if ch.prev_signal_type == SignalType::Voiced {
+            PLC_RAND_ATTENUATE_V_Q15[att]
+        } else {
+            PLC_RAND_ATTENUATE_UV_Q15[att]
+        };
+
+        lpc::bwexpander(&mut plc.prev_lpc_q12[..order], BWE_COEF_Q16);
+        let a_q12 = plc.prev_lpc_q12;
+
+        if ch.loss_count == 0 {
+            plc.rand_scale_q14 = 1 << 14;
+
+            if ch.prev_signal_type == SignalType::Voiced {
+                // The noise makes up for the pitch gain.
+                for tap in plc.ltp_coef_q14 {
+                    plc.rand_scale_q14 = plc.rand_scale_q14.wrapping_sub(tap);
+                }
+
+                let rand_scale_q14 = plc.rand_scale_q14.max(3277) as i32;
+                plc.rand_scale_q14 = (smulbb(rand_scale_q14, plc.prev_ltp_scale_q14) >> 14) as i16;
+            } else {
+                // Less noise for a filter of high gain
+                let inv_gain_q30 = lpc::inverse_pred_gain(&a_q12[..order]);
+                let down_scale_q30 = inv_gain_q30.clamp(
+                    (1 << 30) >> LOG2_INV_LPC_GAIN_LOW_THRES,
+                    (1 << 30) >> LOG2_INV_LPC_GAIN_HIGH_THRES,
+                ) << LOG2_INV_LPC_GAIN_HIGH_THRES;
+
+                rand_gain_q15 = smulwb(down_scale_q30, rand_gain_q15) >> 14;
+            }
+        }
+
+        // The LTP state is the past output whitened by the LPC filter, at the
+        // level of the last gain.
+        let ltp_mem_length = ch.ltp_mem_length;
+        let mut lag = rshift_round(plc.pitch_l_q8, 8) as usize;
+        let start = ltp_mem_length - lag - order - LTP_ORDER / 2;
+
+        let mut whitened = [0; MAX_LTP_MEM_LENGTH];
+        lpc::analysis_filter(
+            &mut whitened[start..ltp_mem_length],
+            &ch.out_buf[start..ltp_mem_length],
+            &a_q12[..order],
+        );
+
+        let inv_gain_q30 = inverse32_varq(plc.prev_gain_q16[1], 46).min(i32::MAX >> 1);
+
+        let mut s_ltp = [0; MAX_LTP_MEM_LENGTH + MAX_FRAME_LENGTH];
+        for i in start + order..ltp_mem_length {
+            s_ltp[i] = smulwb(inv_gain_q30, whitened[i] as i32);
+        }
+
+        let mut idx = ltp_mem_length;
+        for _ in 0..ch.nb_subfr {
+            for _ in 0..subfr_length {
+                // Starts at 2 to avoid the bias of rounding towards -inf
+                let pred_q12 = plc
+                    .ltp_coef_q14
+                    .iter()
+                    .enumerate()
+                    .fold(2, |pred, (j, tap)| {
+                        smlawb(pred, s_ltp[idx + LTP_ORDER / 2 - lag - j], *tap as i32)
+                    });
+
+                plc.rand_seed = rand(plc.rand_seed);
+                let noise = rand_buf[((plc.rand_seed >> 25) & RAND_BUF_MASK) as usize];
+
+                s_ltp[idx] = smlawb(pred_q12, noise, plc.rand_scale_q14 as i32) << 2;
+                idx += 1;
+            }
+
+            for tap in &mut plc.ltp_coef_q14 {
+                *tap = (smulbb(harm_gain_q15, *tap as i32) >> 15) as i16;
+            }
+
+            plc.rand_scale_q14 = (smulbb(plc.rand_scale_q14 as i32, rand_gain_q15) >> 15) as i16;
+
+            plc.pitch_l_q8 = smlawb(plc.pitch_l_q8, plc.pitch_l_q8, PITCH_DRIFT_FAC_Q16)
+                .min(((MAX_PITCH_LAG_MS * ch.fs_khz) << 8) as i32);
+            lag = rshift_round(plc.pitch_l_q8, 8) as usize;
+        }
+
+        let frame_length = ch.frame_length;
+        let mut s_lpc = [0; MAX_LPC_ORDER + MAX_FRAME_LENGTH];
+        s_lpc[..MAX_LPC_ORDER].copy_from_slice(&ch.s_lpc_q14);
+        lpc::synthesis(
+            &mut s_lpc[..MAX_LPC_ORDER + frame_length],
+            &a_q12[..order],
+            &s_ltp[ltp_mem_length..][..frame_length],
+            prev_gain_q10[1],
+            &mut xq[..frame_length],
+        );
+
+        ch.s_lpc_q14
+            .copy_from_slice(&s_lpc[frame_length..frame_length + MAX_LPC_ORDER]);
+
+        ch.pitch_lags = [lag as i32; MAX_NB_SUBFR];
+    }
+
+    /// Keeps the energy of a concealed frame `xq`, or fades a decoded frame
+    /// in from the energy of the concealment preceding it.
+    pub fn glue_frames(ch: &mut SilkChannel, xq: &mut [i16]) {
+        let plc = &mut ch.plc;
+        if ch.loss_count > 0 {
+            (plc.conc_energy, plc.conc_energy_shift) = sum_sqr_shift(xq);
+            plc.last_frame_lost = true;
+            return;
+        }
+
+        if plc.last_frame_lost {
+            let (mut energy, shift) = sum_sqr_shift(xq);
+
+            // Both energies with the same shift
+            if shift > plc.conc_energy_shift {
+                plc.conc_energy >>= shift - plc.conc_energy_shift;
+            } else {
+                energy >>= plc.conc_energy_shift - shift;
+            }
+
+            if energy > plc.conc_energy {
+                let lz = plc.conc_energy.leading_zeros() - 1;
+                plc.conc_energy <<= lz;
+                energy >>= 24u32.saturating_sub(lz);
+
+                let frac_q24 = plc.conc_energy / energy.max(1);
+
+                // The gain goes up four times faster than over the frame, not
+                // to miss an onset after DTX.
+                let mut gain_q16 = sqrt_approx(frac_q24) << 4;
+                let slope_q16 = (((1 << 16) - gain_q16) / xq.len() as i32) << 2;
+
+                for x in xq.iter_mut() {
+                    *x = smulwb(gain_q16, *x as i32) as i16;
+                    gain_q16 += slope_q16;
+                    if gain_q16 > 1 << 16 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        plc.last_frame_lost = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LTP_ORDER, Plc, SignalType, SilkChannel};
+
+    #[test]
+    fn conceal() {
+        let mut ch = SilkChannel {
+            fs_khz: 16,
+            nb_subfr: 4,
+            subfr_length: 80,
+            frame_length: 320,
+            lpc_order: 16,
+            ltp_mem_length: 320,
+            ltp_scale: 1 << 14,
+            gains: [1 << 16; 4],
+            pitch_lags: [100; 4],
+            prev_signal_type: SignalType::Voiced,
+            ..Default::default()
+        };
+
+        // A voiced frame of a period of 100 samples, with a single tap of 0.8
+        // and no LPC filter.
+        let sine = |n: usize| (4000.0 * (n as f32 * std::f32::consts::TAU / 100.0).sin()) as i16;
+        for (n, x) in ch.out_buf.iter_mut().take(320).enumerate() {
+            *x = sine(n);
+            ch.exc_q14[n] = (*x as i32) << 10;
+        }
+
+        for taps in ch.ltp_coef.chunks_mut(LTP_ORDER) {
+            taps[LTP_ORDER / 2] = 13107;
+        }
+
+        ch.indices.signal_type = SignalType::Voiced;
+        Plc::update(&mut ch);
+
+        let energy = |x: &[i16]| x.iter().map(|x| (*x as f32).powi(2)).sum::<f32>();
+        let next: Vec<_> = (320..640).map(sine).collect();
+
+        // The concealment goes on with the period, attenuated.
+        let mut xq = [0; 320];
+        Plc::conceal(&mut ch, &mut xq);
+        let corr = xq
+            .iter()
+            .zip(&next)
+            .map(|(x, y)| *x as f32 * *y as f32)
+            .sum::<f32>();
+        assert!(corr / (energy(&xq) * energy(&next)).sqrt() > 0.95);
+        assert!(energy(&xq) < energy(&next) && energy(&xq) > 0.25 * energy(&next));
+
+        // The next lost frame fades further.
+        let mut faded = [0; 320];
+        ch.loss_count = 1;
+        Plc::conceal(&mut ch, &mut faded);
+        assert!(energy(&faded) < energy(&xq));
+    }
+
+    #[test]
+    fn glue_frames() {
+        let mut ch = SilkChannel {
+            loss_count: 1,
+            ..Default::default()
+        };
+
+        // The energy of a concealed frame is kept, the frame is left as is.
+        let mut xq = [100; 160];
+        Plc::glue_frames(&mut ch, &mut xq);
+        assert_eq!(xq, [100; 160]);
+
+        // The first decoded frame after it starts at the level of the
+        // concealment and ramps up over the first quarter of the frame.
+        let mut xq = [1000; 160];
+        ch.loss_count = 0;
+        Plc::glue_frames(&mut ch, &mut xq);
+        assert!((xq[0] - 100).abs() <= 10, "{}", xq[0]);
+        assert!(xq.windows(2).all(|x| x[0] <= x[1]));
+        assert!(xq[41..].iter().all(|x| *x == 1000));
+
+        // The frames after it are untouched.
+        let mut xq = [1000; 160];
+        Plc::glue_frames(&mut ch, &mut xq);
+        assert_eq!(xq, [1000; 160]);
+    }
+}
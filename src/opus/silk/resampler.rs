@@ -0,0 +1,358 @@
+//! SILK output resampler implementation
+//!
+//! The SILK frames are brought from their internal sample rate to the output
+//! one directly: a 2x all-pass based upsampler for an exact doubling, the
+//! same upsampler followed by a fractional FIR interpolation for the other
+//! higher rates, and an AR2 low-pass followed by a fractional FIR decimation
+//! for the lower ones. The input is delayed by a few samples depending on
+//! both rates, so that the SILK output lines up with the CELT one.
+
+use super::{
+    MAX_FS_KHZ,
+    math::{rshift_round, smlawb, smulbb, smulwb, smulww},
+};
+
+/// Highest output sample rate in kHz.
+pub const MAX_FS_OUT_KHZ: usize = 48;
+
+/// Largest batch of input samples processed at once.
+const RESAMPLER_MAX_BATCH_SIZE_MS: usize = 10;
+
+/// Order of the interpolation filter.
+const RESAMPLER_ORDER_FIR_12: usize = 8;
+
+/// Orders of the decimation filters, with several phases and a single one.
+const RESAMPLER_DOWN_ORDER_FIR0: usize = 18;
+const RESAMPLER_DOWN_ORDER_FIR1: usize = 24;
+
+/// Delay of the input in samples, by input (8, 12, 16 kHz) and output (8,
+/// 12, 16, 24, 48 kHz) rate.
+const DELAY_MATRIX: [[usize; 5]; 3] = [[4, 0, 2, 0, 0], [0, 9, 4, 7, 4], [0, 3, 12, 7, 7]];
+
+/// Coefficients of the all-pass sections of the even and odd output samples
+/// of the 2x upsampler, in Q16.
+const UP2_HQ_0: [i32; 3] = [1746, 14986, 39083 - 65536];
+const UP2_HQ_1: [i32; 3] = [6854, 25769, 55542 - 65536];
+
+/// First half of the symmetric interpolation filter for 12 phases, in Q15.
+const FRAC_FIR_12: [[i32; RESAMPLER_ORDER_FIR_12 / 2]; 12] = [
+    [189, -600, 617, 30567],
+    [117, -159, -1070, 29704],
+    [52, 221, -2392, 28276],
+    [-4, 529, -3350, 26341],
+    [-48, 758, -3956, 23973],
+    [-80, 905, -4235, 21254],
+    [-99, 972, -4222, 18278],
+    [-107, 967, -3957, 15143],
+    [-103, 896, -3487, 11950],
+    [-91, 773, -2865, 8798],
+    [-71, 611, -2143, 5784],
+    [-46, 425, -1375, 2996],
+];
+
+/// AR2 coefficients in Q14 followed by the FIR coefficients of every phase
+/// for a 3:4 decimation.
+const RESAMPLER_3_4_COEFS: [i32; 2 + 3 * RESAMPLER_DOWN_ORDER_FIR0 / 2] = [
+    -20694, -13867, -49, 64, 17, -157, 353, -496, 163, 11047, 22205, -39, 6, 91, -170, 186, 23,
+    -896, 6336, 19928, -19, -36, 102, -89, -24, 328, -951, 2568, 15909,
+];
+
+/// AR2 and FIR coefficients for a 2:3 decimation.
+const RESAMPLER_2_3_COEFS: [i32; 2 + 2 * RESAMPLER_DOWN_ORDER_FIR0 / 2] = [
+    -14457, -14019, 64, 128, -122, 36, 310, -768, 584, 9267, 17733, 12, 128, 18, -142, 288, -117,
+    -865, 4123, 14459,
+];
+
+/// AR2 and FIR coefficients for a 1:2 decimation, the filter has a single
+/// symmetric phase.
+const RESAMPLER_1_2_COEFS: [i32; 2 + RESAMPLER_DOWN_ORDER_FIR1 / 2] = [
+    616, -14323, -10, 39, 58, -46, -84, 120, 184, -315, -541, 1284, 5380, 9024,
+];
+
+/// Index of a sample rate in the delay matrix.
+fn rate_index(fs_khz: usize) -> usize {
+    match fs_khz {
+        8 => 0,
+        12 => 1,
+        16 => 2,
+        24 => 3,
+        _ => 4,
+    }
+}
+
+/// Conversion applied between the two sample rates
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    #[default]
+    Copy,
+    /// Exact 2x upsampling.
+    Up2,
+    /// 2x upsampling followed by a fractional interpolation.
+    IirFir,
+    /// AR2 low-pass followed by a fractional decimation.
+    DownFir {
+        coefs: &'static [i32],
+        order: usize,
+        fracs: usize,
+    },
+}
+
+/// Resampler from the internal sample rate to the output one
+#[derive(Debug, Default, Clone)]
+pub struct Resampler {
+    fs_in_khz: usize,
+    fs_out_khz: usize,
+    method: Method,
+    /// Delay of the input in samples, the first millisecond of every frame
+    /// is taken from the buffer.
+    input_delay: usize,
+    delay_buf: [i16; MAX_FS_KHZ],
+    /// Step between two output samples in the 2x upsampled input, in Q16.
+    inv_ratio_q16: i32,
+    /// Q10 states of the all-pass sections, Q8 states of the AR2 filter.
+    s_iir: [i32; 6],
+    /// Last upsampled samples, the history of the interpolation filter.
+    s_fir: [i16; RESAMPLER_ORDER_FIR_12],
+    /// Last low-passed samples, the history of the decimation filter.
+    s_fir_down: [i32; RESAMPLER_DOWN_ORDER_FIR1],
+}
+
+impl Resampler {
+    /// Creates a resampler from `fs_in_khz` to `fs_out_khz`.
+    pub fn new(fs_in_khz: usize, fs_out_khz: usize) -> Self {
+        let input_delay = DELAY_MATRIX[rate_index(fs_in_khz)][rate_index(fs_out_khz)];
+
+        let (method, up2x) = if fs_out_khz == fs_in_khz {
+            (Method::Copy, 0)
+        } else if fs_out_khz == 2 * fs_in_khz {
+            (Method::Up2, 1)
+        } else if fs_out_khz > fs_in_khz {
+            (Method::IirFir, 1)
+        } else {
+            let (coefs, order, fracs): (&'static [i32], _, _) = if 4 * fs_out_khz == 3 * fs_in_khz {
+                (&RESAMPLER_3_4_COEFS, RESAMPLER_DOWN_ORDER_FIR0, 3)
+            } else if 3 * fs_out_khz == 2 * fs_in_khz {
+                (&RESAMPLER_2_3_COEFS, RESAMPLER_DOWN_ORDER_FIR0, 2)
+            } else {
+                (&RESAMPLER_1_2_COEFS, RESAMPLER_DOWN_ORDER_FIR1, 1)
+            };
+
+            (
+                Method::DownFir {
+                    coefs,
+                    order,
+                    fracs,
+                },
+                0,
+            )
+        };
+
+        // The ratio of input to output samples, rounded up
+        let fs_in = fs_in_khz as i32 * 1000;
+        let fs_out = fs_out_khz as i32 * 1000;
+        let mut inv_ratio_q16 = ((fs_in << (14 + up2x)) / fs_out) << 2;
+        while smulww(inv_ratio_q16, fs_out) < fs_in << up2x {
+            inv_ratio_q16 += 1;
+        }
+
+        Self {
+            fs_in_khz,
+            fs_out_khz,
+            method,
+            input_delay,
+            inv_ratio_q16,
+            ..Default::default()
+        }
+    }
+
+    /// Resamples `input` into `output`, which holds the output rate worth of
+    /// samples for the duration of `input`, at least 1 ms long.
+    pub fn resample(&mut self, output: &mut [i16], input: &[i16]) {
+        let n = self.fs_in_khz - self.input_delay;
+        self.delay_buf[self.input_delay..self.fs_in_khz].copy_from_slice(&input[..n]);
+
+        let delay_buf = self.delay_buf;
+        let (first, rest) = output.split_at_mut(self.fs_out_khz);
+        self.process(first, &delay_buf[..self.fs_in_khz]);
+        self.process(rest, &input[n..input.len() - self.input_delay]);
+
+        self.delay_buf[..self.input_delay]
+            .copy_from_slice(&input[input.len() - self.input_delay..]);
+    }
+
+    /// Converts `input` with the method of the resampler.
+    fn process(&mut self, output: &mut [i16], input: &[i16]) {
+        match self.method {
+            Method::Copy => output.copy_from_slice(input),
+            Method::Up2 => self.up2_hq(output, input),
+            Method::IirFir => self.iir_fir(output, input),
+            Method::DownFir {
+                coefs,
+                order,
+                fracs,
+            } => self.down_fir(output, input, coefs, order, fracs),
+        }
+    }
+
+    /// Upsamples by 2 and interpolates the result, in batches.
+    fn iir_fir(&mut self, mut output: &mut [i16], input: &[i16]) {
+        let mut buf = [0; 2 * RESAMPLER_MAX_BATCH_SIZE_MS * MAX_FS_KHZ + RESAMPLER_ORDER_FIR_12];
+        buf[..RESAMPLER_ORDER_FIR_12].copy_from_slice(&self.s_fir);
+
+        let batch_size = RESAMPLER_MAX_BATCH_SIZE_MS * self.fs_in_khz;
+        for (i, input) in input.chunks(batch_size).enumerate() {
+            // The history of the filter is the end of the previous batch.
+            if i > 0 {
+                buf.copy_within(2 * batch_size..2 * batch_size + RESAMPLER_ORDER_FIR_12, 0);
+            }
+
+            self.up2_hq(&mut buf[RESAMPLER_ORDER_FIR_12..], input);
+
+            // Interpolate the upsampled signal, + 1 for the 2x upsampling
+            let max_index_q16 = (input.len() as i32) << (16 + 1);
+            let mut written = 0;
+            for index_q16 in (0..max_index_q16).step_by(self.inv_ratio_q16 as usize) {
+                let table_index = smulwb(index_q16 & 0xffff, 12) as usize;
+                let buf = &buf[(index_q16 >> 16) as usize..];
+
+                let res_q15 = (0..RESAMPLER_ORDER_FIR_12 / 2).fold(0, |res, j| {
+                    res + smulbb(buf[j] as i32, FRAC_FIR_12[table_index][j])
+                        + smulbb(
+                            buf[RESAMPLER_ORDER_FIR_12 - j - 1] as i32,
+                            FRAC_FIR_12[11 - table_index][j],
+                        )
+                });
+
+                output[written] =
+                    rshift_round(res_q15, 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+                written += 1;
+            }
+
+            output = &mut output[written..];
+            self.s_fir
+                .copy_from_slice(&buf[2 * input.len()..2 * input.len() + RESAMPLER_ORDER_FIR_12]);
+        }
+    }
+
+    /// Low-passes with an AR2 filter and decimates the result, in batches.
+    fn down_fir(
+        &mut self,
+        mut output: &mut [i16],
+        input: &[i16],
+        coefs: &[i32],
+        order: usize,
+        fracs: usize,
+    ) {
+        let mut buf = [0; RESAMPLER_MAX_BATCH_SIZE_MS * MAX_FS_KHZ + RESAMPLER_DOWN_ORDER_FIR1];
+        buf[..order].copy_from_slice(&self.s_fir_down[..order]);
+
+        let (ar2, fir) = coefs.split_at(2);
+        let batch_size = RESAMPLER_MAX_BATCH_SIZE_MS * self.fs_in_khz;
+        for (i, input) in input.chunks(batch_size).enumerate() {
+            // The history of the filter is the end of the previous batch.
+            if i > 0 {
+                buf.copy_within(batch_size..batch_size + order, 0);
+            }
+
+            self.ar2(&mut buf[order..order + input.len()], input, ar2);
+
+            let max_index_q16 = (input.len() as i32) << 16;
+            let mut written = 0;
+            for index_q16 in (0..max_index_q16).step_by(self.inv_ratio_q16 as usize) {
+                let buf = &buf[(index_q16 >> 16) as usize..][..order];
+
+                let res_q6 = if fracs > 1 {
+                    // The two halves of the filter come from mirrored phases.
+                    let phase = smulwb(index_q16 & 0xffff, fracs as i32) as usize;
+                    let h0 = &fir[order / 2 * phase..][..order / 2];
+                    let h1 = &fir[order / 2 * (fracs - 1 - phase)..][..order / 2];
+                    (0..order / 2).fold(0, |res, j| {
+                        smlawb(smlawb(res, buf[j], h0[j]), buf[order - 1 - j], h1[j])
+                    })
+                } else {
+                    (0..order / 2)
+                        .fold(0, |res, j| smlawb(res, buf[j] + buf[order - 1 - j], fir[j]))
+                };
+
+                output[written] =
+                    rshift_round(res_q6, 6).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+                written += 1;
+            }
+
+            output = &mut output[written..];
+            self.s_fir_down[..order].copy_from_slice(&buf[input.len()..input.len() + order]);
+        }
+    }
+
+    /// Second order AR filter with a Q8 output.
+    fn ar2(&mut self, output: &mut [i32], input: &[i16], a_q14: &[i32]) {
+        let s = &mut self.s_iir[..2];
+        for (out, &x) in output.iter_mut().zip(input) {
+            let out32 = s[0] + ((x as i32) << 8);
+            *out = out32;
+            let out32 = out32 << 2;
+            s[0] = smlawb(s[1], out32, a_q14[0]);
+            s[1] = smulwb(out32, a_q14[1]);
+        }
+    }
+
+    /// Upsamples by 2 with two branches of three all-pass sections each.
+    fn up2_hq(&mut self, output: &mut [i16], input: &[i16]) {
+        for (k, &x) in input.iter().enumerate() {
+            // The internal variables and the state are in Q10.
+            let in32 = (x as i32) << 10;
+
+            for (phase, coefs) in [UP2_HQ_0, UP2_HQ_1].iter().enumerate() {
+                let s = &mut self.s_iir[3 * phase..3 * phase + 3];
+
+                let y = in32 - s[0];
+                let x = smulwb(y, coefs[0]);
+                let out1 = s[0] + x;
+                s[0] = in32 + x;
+
+                let y = out1 - s[1];
+                let x = smulwb(y, coefs[1]);
+                let out2 = s[1] + x;
+                s[1] = out1 + x;
+
+                // The last coefficient does not fit in 16 bits, it is
+                // stored minus one.
+                let y = out2 - s[2];
+                let x = smlawb(y, y, coefs[2]);
+                let out1 = s[2] + x;
+                s[2] = out2 + x;
+
+                output[2 * k + phase] =
+                    rshift_round(out1, 10).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Resampler;
+
+    #[test]
+    fn resample() {
+        for fs_in_khz in [8, 12, 16] {
+            for fs_out_khz in [8, 12, 16, 24, 48] {
+                let mut resampler = Resampler::new(fs_in_khz, fs_out_khz);
+
+                // A constant input settles on the same constant, the
+                // decimation filters are slightly off unity gain.
+                let tolerance = if fs_out_khz < fs_in_khz { 10 } else { 2 };
+                let input = vec![1000; 10 * fs_in_khz];
+                let mut output = vec![0; 10 * fs_out_khz];
+                for _ in 0..4 {
+                    resampler.resample(&mut output, &input);
+                }
+
+                assert!(
+                    output.iter().all(|x| (x - 1000).abs() <= tolerance),
+                    "{fs_in_khz} -> {fs_out_khz}: {output:?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! SILK stereo decoding implementation
+//!
+//! A stereo stream is coded as a mid and a side channel. The side channel is
+//! coded as the residual of a prediction from the mid channel, with two
+//! weights: one applied to a low-passed mid and one to the mid itself. The
+//! weights are interpolated from the previous frame over the first 8 ms of
+//! the frame.
+//!
+//! A frame may also carry only the mid channel, the side being silent.
+
+use crate::opus::entropy::RangeCodingDecoder;
+
+use super::math::{rshift_round, smlawb, smulbb, smulwb};
+
+/// Length of the interpolation of the prediction weights.
+const STEREO_INTERP_LEN_MS: usize = 8;
+
+/// Half the size of the 5 sub-steps between two quantization levels, 0.1 in
+/// Q16.
+const STEREO_QUANT_HALF_SUB_STEP_Q16: i32 = 6554;
+
+/// Quantization levels of the prediction weights, in Q13.
+const STEREO_PRED_QUANT_Q13: [i32; 16] = [
+    -13732, -10050, -8266, -7526, -6500, -5000, -2950, -820, 820, 2950, 5000, 6500, 7526, 8266,
+    10050, 13732,
+];
+
+/// Levels of both weights, coded jointly.
+const STEREO_PRED_JOINT_MODEL: [usize; 26] = [
+    256, 7, 9, 10, 11, 12, 22, 46, 54, 55, 56, 59, 82, 174, 197, 200, 201, 202, 210, 234, 244, 245,
+    246, 247, 249, 256,
+];
+
+const STEREO_ONLY_CODE_MID_MODEL: [usize; 3] = [256, 192, 256];
+const UNIFORM3_MODEL: [usize; 4] = [256, 85, 171, 256];
+const UNIFORM5_MODEL: [usize; 6] = [256, 51, 102, 154, 205, 256];
+
+/// State of the stereo unmixing
+#[derive(Debug, Default)]
+pub struct Stereo {
+    /// Q13 prediction weights of the previous frame.
+    pred_prev_q13: [i32; 2],
+    /// Last two samples of the mid and side channels of the previous frame,
+    /// the output is delayed by one sample.
+    s_mid: [i16; 2],
+    s_side: [i16; 2],
+}
+
+impl Stereo {
+    /// Decodes the Q13 prediction weights of the side channel, the first
+    /// weight is the difference of both.
+    pub fn decode_pred(range_dec: &mut RangeCodingDecoder) -> [i32; 2] {
+        // The levels are coded jointly, the sub-steps separately.
+        let n = range_dec.icdf(&STEREO_PRED_JOINT_MODEL);
+
+        let mut ix = [[0; 3]; 2];
+        ix[0][2] = n / 5;
+        ix[1][2] = n - 5 * ix[0][2];
+        for ix in &mut ix {
+            ix[0] = range_dec.icdf(&UNIFORM3_MODEL);
+            ix[1] = range_dec.icdf(&UNIFORM5_MODEL);
+        }
+
+        let mut pred_q13 = [0; 2];
+        for (pred_q13, ix) in pred_q13.iter_mut().zip(ix) {
+            let level = ix[0] + 3 * ix[2];
+            let low_q13 = STEREO_PRED_QUANT_Q13[level];
+            let step_q13 = smulwb(
+                STEREO_PRED_QUANT_Q13[level + 1] - low_q13,
+                STEREO_QUANT_HALF_SUB_STEP_Q16,
+            );
+
+            *pred_q13 = low_q13 + smulbb(step_q13, 2 * ix[1] as i32 + 1);
+        }
+
+        pred_q13[0] -= pred_q13[1];
+        pred_q13
+    }
+
+    /// Decodes whether only the mid channel is coded in the frame.
+    pub fn decode_mid_only(range_dec: &mut RangeCodingDecoder) -> bool {
+        range_dec.icdf(&STEREO_ONLY_CODE_MID_MODEL) == 1
+    }
+
+    /// Q13 prediction weights of the previous frame, a lost frame keeps
+    /// them.
+    pub fn pred_prev(&self) -> [i32; 2] {
+        self.pred_prev_q13
+    }
+
+    /// Resets the state for a stream switching from mono to stereo.
+    pub fn reset_side(&mut self) {
+        self.pred_prev_q13 = [0; 2];
+        self.s_side = [0; 2];
+    }
+
+    /// Delays the mid channel of a stream output as mono, `x1` holds two
+    /// free samples followed by the frame.
+    pub fn buffer_mid(&mut self, x1: &mut [i16], frame_length: usize) {
+        x1[..2].copy_from_slice(&self.s_mid);
+        self.s_mid
+            .copy_from_slice(&x1[frame_length..frame_length + 2]);
+    }
+
+    /// Converts the mid `x1` and side `x2` channels into left and right, both
+    /// buffers hold two free samples followed by the frame.
+    pub fn ms_to_lr(
+        &mut self,
+        x1: &mut [i16],
+        x2: &mut [i16],
+        pred_q13: [i32; 2],
+        fs_khz: usize,
+        frame_length: usize,
+    ) {
+        x1[..2].copy_from_slice(&self.s_mid);
+        x2[..2].copy_from_slice(&self.s_side);
+        self.s_mid
+            .copy_from_slice(&x1[frame_length..frame_length + 2]);
+        self.s_side
+            .copy_from_slice(&x2[frame_length..frame_length + 2]);
+
+        // Interpolate the weights and add the prediction to the side
+        // channel.
+        let interp_len = STEREO_INTERP_LEN_MS * fs_khz;
+        let denom_q16 = (1 << 16) / interp_len as i32;
+        let delta0_q13 = rshift_round(smulbb(pred_q13[0] - self.pred_prev_q13[0], denom_q16), 16);
+        let delta1_q13 = rshift_round(smulbb(pred_q13[1] - self.pred_prev_q13[1], denom_q16), 16);
+
+        let mut pred0_q13 = self.pred_prev_q13[0];
+        let mut pred1_q13 = self.pred_prev_q13[1];
+        for n in 0..frame_length {
+            if n < interp_len {
+                pred0_q13 += delta0_q13;
+                pred1_q13 += delta1_q13;
+            } else {
+                pred0_q13 = pred_q13[0];
+                pred1_q13 = pred_q13[1];
+            }
+
+            // Low-passed mid in Q11
+            let mid = x1[n + 1] as i32;
+            let sum = (x1[n] as i32 + x1[n + 2] as i32 + (mid << 1)) << 9;
+
+            let sum = smlawb((x2[n + 1] as i32) << 8, sum, pred0_q13);
+            let sum = smlawb(sum, mid << 11, pred1_q13);
+            x2[n + 1] = rshift_round(sum, 8).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+
+        self.pred_prev_q13 = pred_q13;
+
+        for (x1, x2) in x1[1..=frame_length]
+            .iter_mut()
+            .zip(&mut x2[1..=frame_length])
+        {
+            let (mid, side) = (*x1 as i32, *x2 as i32);
+            *x1 = (mid + side).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            *x2 = (mid - side).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Stereo;
+
+    #[test]
+    fn ms_to_lr() {
+        let mut stereo = Stereo::default();
+
+        // Without prediction the side is added to and subtracted from the
+        // mid, one sample late.
+        let mut x1 = [0, 0, 100, 200, 300, i16::MAX];
+        let mut x2 = [0, 0, 10, 20, 30, 40];
+        stereo.ms_to_lr(&mut x1, &mut x2, [0, 0], 8, 4);
+        assert_eq!(x1[1..5], [0, 110, 220, 330]);
+        assert_eq!(x2[1..5], [0, 90, 180, 270]);
+
+        // The last sample of the previous frame comes first.
+        let mut x1 = [0; 6];
+        let mut x2 = [0; 6];
+        stereo.ms_to_lr(&mut x1, &mut x2, [0, 0], 8, 4);
+        assert_eq!(x1[1], i16::MAX);
+        assert_eq!(x2[1], i16::MAX - 40);
+    }
+}
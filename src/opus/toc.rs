@@ -137,8 +137,8 @@ impl From<u8> for Channels {
 ///
 /// | config  | mode        | bandwidth | duration          |
 /// |---------|-------------|-----------|-------------------|
-/// | 0...3	  | SILK-only   | NB        | 10, 20, 40, 60 ms |
-/// | 4...7	  | SILK-only   | MB        | 10, 20, 40, 60 ms |
+/// | 0...3   | SILK-only   | NB        | 10, 20, 40, 60 ms |
+/// | 4...7   | SILK-only   | MB        | 10, 20, 40, 60 ms |
 /// | 8...11  | SILK-only   | WB        | 10, 20, 40, 60 ms |
 /// | 12...13 | Hybrid      | SWB       | 10, 20 ms         |
 /// | 14...15 | Hybrid      | FB        | 10, 20 ms         |